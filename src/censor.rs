@@ -0,0 +1,247 @@
+//! This module is the last line of defense before text goes out to a
+//! public chatbox.  It masks (or blocks outright) words from a configurable
+//! list, and separately catches personal info like phone numbers or email
+//! addresses with a list of regular expressions.
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+use log::{debug, error};
+use crate::MelonError;
+
+/// What to do when a word or pattern matches
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MaskStyle {
+    /// Replace each character of the match with an asterisk
+    Asterisks,
+    /// Take the match out of the text entirely
+    Remove,
+    /// Refuse to send the message at all
+    Block,
+}
+
+/// Settings for the content filter, read from the [censor] table
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct CensorConfig {
+    /// Turn the content filter on or off
+    pub enabled: bool,
+    /// Words or phrases to filter (whole words, case-insensitive)
+    pub words: Vec<String>,
+    /// What to do with filtered words
+    pub mask: MaskStyle,
+    /// Regular expressions matching personal info that shouldn't go out
+    pub pii_patterns: Vec<String>,
+    /// What to do with personal info matches
+    pub pii_mask: MaskStyle,
+}
+
+impl Default for CensorConfig {
+    fn default() -> Self {
+        CensorConfig {
+            enabled: false,
+            words: Vec::new(),
+            mask: MaskStyle::Asterisks,
+            pii_patterns: vec![
+                r"(?:\+?\d{1,2}[-. ]?)?\(?\d{3}\)?[-. ]?\d{3}[-. ]?\d{4}\b".to_string(),
+                r"\b[\w.+-]+@[\w-]+\.[\w.-]+\b".to_string(),
+            ],
+            pii_mask: MaskStyle::Block,
+        }
+    }
+}
+
+/// The outcome of running text through the content filter
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verdict {
+    /// Nothing matched, the text is unchanged
+    Clean(String),
+    /// Some matches were masked or removed; holds the new text
+    Masked(String),
+    /// The message shouldn't be sent; holds the reason why
+    Blocked(String),
+}
+
+/// The content filter itself
+pub struct STTCensor {
+    /// Whether the filter does anything at all
+    pub enabled: bool,
+    /// Compiled word patterns
+    words: Vec<Regex>,
+    /// How to mask word matches
+    mask: MaskStyle,
+    /// Compiled personal info patterns
+    pii: Vec<Regex>,
+    /// How to mask personal info matches
+    pii_mask: MaskStyle,
+}
+
+impl STTCensor {
+    /// Compile the word list and patterns from the config
+    pub fn new(config: &CensorConfig) -> Result<STTCensor, MelonError> {
+        let mut words = Vec::new();
+        for word in config.words.iter() {
+            let pattern = format!(r"(?i){}{}{}", boundary(word.chars().next()), regex::escape(word), boundary(word.chars().last()));
+            match Regex::new(&pattern) {
+                Ok(x) => words.push(x),
+                Err(e) => {
+                    error!("Unable to build pattern for censored word '{}'", word);
+                    return Err(MelonError::InvalidPattern { pattern, source: e });
+                }
+            }
+        }
+        let mut pii = Vec::new();
+        for pattern in config.pii_patterns.iter() {
+            match Regex::new(pattern) {
+                Ok(x) => pii.push(x),
+                Err(e) => {
+                    error!("Invalid personal info pattern: {}", pattern);
+                    return Err(MelonError::InvalidPattern { pattern: pattern.to_string(), source: e });
+                }
+            }
+        }
+        Ok(STTCensor { enabled: config.enabled, words, mask: config.mask, pii, pii_mask: config.pii_mask })
+    }
+
+    /// Run some text through the filter
+    pub fn check(&self, text: &str) -> Verdict {
+        if !self.enabled { return Verdict::Clean(text.to_string()); }
+        // The reason ends up on screen, so it says which rule matched
+        // rather than repeating whatever it was
+        let (text, pii_hits) = match Self::apply(&self.pii, self.pii_mask, text) {
+            Ok(x) => x,
+            Err(i) => return Verdict::Blocked(format!("message contains personal info (pattern {})", i + 1)),
+        };
+        let (text, word_hits) = match Self::apply(&self.words, self.mask, &text) {
+            Ok(x) => x,
+            Err(i) => return Verdict::Blocked(format!("message contains filtered word {} from the list", i + 1)),
+        };
+        if pii_hits + word_hits == 0 {
+            Verdict::Clean(text)
+        } else {
+            debug!("Masked {} personal info and {} word matches", pii_hits, word_hits);
+            Verdict::Masked(text.split_whitespace().collect::<Vec<_>>().join(" "))
+        }
+    }
+
+    /// Apply a set of patterns with the given mask style.  Hands back the
+    /// new text and how many matches there were, or the index of the first
+    /// pattern that matched if the style is Block.
+    fn apply(patterns: &[Regex], style: MaskStyle, text: &str) -> Result<(String, usize), usize> {
+        let mut text = text.to_string();
+        let mut hits = 0;
+        for (i, re) in patterns.iter().enumerate() {
+            if !re.is_match(&text) { continue; }
+            if style == MaskStyle::Block { return Err(i); }
+            hits += re.find_iter(&text).count();
+            text = re.replace_all(&text, |caps: &regex::Captures| {
+                match style {
+                    MaskStyle::Asterisks => "*".repeat(caps[0].chars().count()),
+                    _ => String::new(),
+                }
+            }).to_string();
+        }
+        Ok((text, hits))
+    }
+}
+
+/// The boundary to put at one end of a filtered word.  \b only works next
+/// to a word character, so a word starting or ending with a symbol ("a$$",
+/// "@handle") gets \B there instead, which still stops it matching in the
+/// middle of something longer.
+fn boundary(end: Option<char>) -> &'static str {
+    match end {
+        Some(x) if x.is_alphanumeric() || x == '_' => r"\b",
+        _ => r"\B"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn censor(words: &[&str], mask: MaskStyle, pii_mask: MaskStyle) -> STTCensor {
+        STTCensor::new(&CensorConfig {
+            enabled: true,
+            words: words.iter().map(|x| x.to_string()).collect(),
+            mask,
+            pii_mask,
+            ..Default::default()
+        }).unwrap()
+    }
+
+    #[test]
+    fn disabled_passes_everything() {
+        let censor = STTCensor::new(&CensorConfig { words: vec!["heck".to_string()], ..Default::default() }).unwrap();
+        assert_eq!(censor.check("heck, call 555-123-4567"), Verdict::Clean("heck, call 555-123-4567".to_string()));
+    }
+
+    #[test]
+    fn clean_text_is_unchanged() {
+        let censor = censor(&["heck"], MaskStyle::Asterisks, MaskStyle::Block);
+        assert_eq!(censor.check("Hello  there."), Verdict::Clean("Hello  there.".to_string()));
+    }
+
+    #[test]
+    fn words_masked_with_asterisks() {
+        let censor = censor(&["heck"], MaskStyle::Asterisks, MaskStyle::Block);
+        assert_eq!(censor.check("Oh HECK, what the heck"), Verdict::Masked("Oh ****, what the ****".to_string()));
+        // Only whole words
+        assert_eq!(censor.check("Checking in"), Verdict::Clean("Checking in".to_string()));
+    }
+
+    #[test]
+    fn words_removed() {
+        let censor = censor(&["heck"], MaskStyle::Remove, MaskStyle::Block);
+        assert_eq!(censor.check("what the heck is that"), Verdict::Masked("what the is that".to_string()));
+    }
+
+    #[test]
+    fn words_blocked_without_repeating_them() {
+        let censor = censor(&["darn", "heck"], MaskStyle::Block, MaskStyle::Block);
+        match censor.check("what the heck") {
+            Verdict::Blocked(x) => {
+                assert!(!x.contains("heck"), "{}", x);
+                assert!(x.contains('2'), "{}", x);
+            },
+            x => panic!("expected Blocked, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn words_with_symbols_match() {
+        let censor = censor(&["a$$", "f*ck", "@handle"], MaskStyle::Asterisks, MaskStyle::Block);
+        assert_eq!(censor.check("you a$$"), Verdict::Masked("you ***".to_string()));
+        assert_eq!(censor.check("f*ck it"), Verdict::Masked("**** it".to_string()));
+        assert_eq!(censor.check("ask @handle."), Verdict::Masked("ask *******.".to_string()));
+        // But still not in the middle of something longer
+        assert_eq!(censor.check("a$$hole"), Verdict::Clean("a$$hole".to_string()));
+        assert_eq!(censor.check("me@handle"), Verdict::Clean("me@handle".to_string()));
+    }
+
+    #[test]
+    fn default_patterns_catch_phone_numbers_and_email() {
+        let censor = censor(&[], MaskStyle::Asterisks, MaskStyle::Asterisks);
+        for text in ["555-123-4567", "(555) 123-4567", "+1 555.123.4567", "5551234567"] {
+            assert_eq!(censor.check(text), Verdict::Masked("*".repeat(text.chars().count())), "{}", text);
+        }
+        assert_eq!(censor.check("mail me@example.com"), Verdict::Masked("mail **************".to_string()));
+        assert_eq!(censor.check("I have 3 cats"), Verdict::Clean("I have 3 cats".to_string()));
+    }
+
+    #[test]
+    fn personal_info_removed_or_blocked() {
+        let censor_remove = censor(&[], MaskStyle::Asterisks, MaskStyle::Remove);
+        assert_eq!(censor_remove.check("call 555-123-4567 now"), Verdict::Masked("call now".to_string()));
+        let censor_block = censor(&[], MaskStyle::Asterisks, MaskStyle::Block);
+        match censor_block.check("mail me@example.com") {
+            Verdict::Blocked(x) => assert!(!x.contains("example"), "{}", x),
+            x => panic!("expected Blocked, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn bad_pattern_is_an_error() {
+        let config = CensorConfig { pii_patterns: vec!["(".to_string()], ..Default::default() };
+        assert!(matches!(STTCensor::new(&config), Err(MelonError::InvalidPattern { .. })));
+    }
+}
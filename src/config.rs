@@ -0,0 +1,996 @@
+//! This module handles finding, reading and checking melon.toml.
+//! The config file is looked for in this order:
+//! 1. A path given on the command line (--config)
+//! 2. The MELONSTT_CONFIG environment variable
+//! 3. melonstt/melon.toml in the platform config directory
+//!    (%APPDATA% on Windows, ~/.config on Linux)
+//! 4. melon.toml in $CWD
+//!
+//! If none of those exist, a commented default config is written out
+//! so there's something to edit.
+//!
+//! The config can also have [profiles.<name>] tables.  Anything set in a
+//! profile overrides the same setting at the top level when that profile
+//! is active, so switching setups doesn't mean editing the file.
+//!
+//! Settings changed at runtime can be saved back to the same file.  Only
+//! the values that changed are rewritten, so comments and layout survive.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Write;
+use std::net::SocketAddrV4;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use serde_derive::{Deserialize, Serialize};
+use log::{debug, error, warn};
+use crate::filter::FilterConfig;
+use crate::pipeline::{self, RuleConfig};
+use crate::censor::CensorConfig;
+use crate::processor::DecodeOptions;
+use crate::recorder::{PrerollConfig, RecordMode, VadConfig, MAX_PREROLL_MS};
+use crate::history::HistoryConfig;
+use crate::hotkeys::HotkeyConfig;
+use crate::server::ServerConfig;
+use crate::ipc::IpcConfig;
+use crate::subtitles::SubtitleConfig;
+use crate::recordings::RecordingsConfig;
+use crate::obs::ObsConfig;
+use crate::outputs::{self, OutputConfig, OutputEntry};
+use crate::MelonError;
+
+/// Default config written out on first run.  It's the same melon.toml
+/// that ships with the source, comments and all.
+pub const DEFAULT_CONFIG: &str = include_str!("../melon.toml");
+
+/// Name of the config file
+pub const CONFIG_NAME: &str = "melon.toml";
+
+/// Environment variable that can point at a config file
+pub const CONFIG_ENV: &str = "MELONSTT_CONFIG";
+
+/// A struct representing a configuration file
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct STTConfig {
+    /// The language of the incoming speech audio
+    pub language: String,
+    /// Filename of the whisper language model to use for transcription
+    pub model: String,
+    /// Optional initial prompt to give Whisper some context
+    pub prompt: Option<String>,
+    /// Translate the speech into English instead of transcribing it
+    pub translate: bool,
+    /// Number of CPU threads Whisper should use
+    pub threads: i32,
+    /// Beam search width; 1 means plain greedy decoding
+    pub beam_size: i32,
+    /// Optional ip:port for the OSC endpoint (defaults to 127.0.0.1:9000)
+    pub osc_endpoint: Option<String>,
+    /// Optional directory to look in for other models (defaults to $CWD).
+    /// Relative paths here and in model start from the config file's directory.
+    pub models_dir: Option<String>,
+    /// Name of the input device to record from (defaults to the system default)
+    pub input_device: Option<String>,
+    /// Send messages too long for the chatbox in several parts instead of cutting them off
+    pub split_messages: bool,
+    /// How long each part of a split message stays up before the next one
+    pub split_delay_ms: u64,
+    /// How recordings are started and stopped: fixed, vad or push-to-talk
+    pub record_mode: RecordMode,
+    /// Send each transcription to the chatbox as soon as it's ready
+    pub auto_send: bool,
+    /// Settings for stopping recordings when the speaker goes quiet
+    pub vad: VadConfig,
+    /// Settings for keeping the microphone open so recordings don't clip
+    pub preroll: PrerollConfig,
+    /// Settings for the hallucination filter
+    pub filter: FilterConfig,
+    /// Ordered list of text post-processing rules
+    pub pipeline: Vec<RuleConfig>,
+    /// Settings for the profanity/personal info filter
+    pub censor: CensorConfig,
+    /// Settings for the transcription history
+    pub history: HistoryConfig,
+    /// Everywhere messages get sent
+    pub outputs: Vec<OutputEntry>,
+    /// Settings for sending captions to OBS
+    pub obs: ObsConfig,
+    /// Settings for writing subtitle files
+    pub subtitles: SubtitleConfig,
+    /// Settings for saving recordings
+    pub recordings: RecordingsConfig,
+    /// Settings for global hotkeys and trigger commands
+    pub hotkeys: HotkeyConfig,
+    /// Settings for the HTTP/WebSocket control server
+    pub server: ServerConfig,
+    /// Settings for the command socket
+    pub ipc: IpcConfig,
+    /// Name of the profile to use at startup, if any
+    pub profile: Option<String>,
+    /// Named sets of overrides for any of the settings above
+    pub profiles: BTreeMap<String, toml::Table>,
+    /// The raw top-level settings, kept so profiles can be layered on top
+    #[serde(skip)]
+    source: toml::Table,
+    /// The directory the config file is in
+    #[serde(skip)]
+    dir: PathBuf,
+}
+
+impl Default for STTConfig {
+    fn default() -> Self {
+        STTConfig {
+            language: "en".to_string(),
+            model: "ggml-tiny.en.bin".to_string(),
+            prompt: None,
+            translate: false,
+            threads: 8,
+            beam_size: 1,
+            osc_endpoint: None,
+            models_dir: None,
+            input_device: None,
+            split_messages: false,
+            split_delay_ms: 3000,
+            record_mode: RecordMode::Fixed,
+            auto_send: false,
+            vad: VadConfig::default(),
+            preroll: PrerollConfig::default(),
+            filter: FilterConfig::default(),
+            pipeline: pipeline::default_rules(),
+            censor: CensorConfig::default(),
+            history: HistoryConfig::default(),
+            outputs: outputs::default_outputs(),
+            obs: ObsConfig::default(),
+            subtitles: SubtitleConfig::default(),
+            recordings: RecordingsConfig::default(),
+            hotkeys: HotkeyConfig::default(),
+            server: ServerConfig::default(),
+            ipc: IpcConfig::default(),
+            profile: None,
+            profiles: BTreeMap::new(),
+            source: toml::Table::new(),
+            dir: PathBuf::new(),
+        }
+    }
+}
+
+/// A single problem found while validating the config
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigIssue {
+    /// Line in the config file the problem is on, if we could work it out
+    pub line: Option<usize>,
+    /// What's wrong
+    pub message: String,
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(x) => write!(f, "line {}: {}", x, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Shorthand for a config file that couldn't be written
+fn write_error(path: &Path, source: std::io::Error) -> MelonError {
+    MelonError::ConfigWrite { path: path.to_path_buf(), source }
+}
+
+impl STTConfig {
+    /// Pull out the settings STTProcessor needs for decoding
+    pub fn decode_options(&self) -> DecodeOptions {
+        DecodeOptions {
+            language: self.language.to_string(),
+            prompt: self.prompt.clone(),
+            translate: self.translate,
+            threads: self.threads,
+            beam_size: self.beam_size,
+        }
+    }
+
+    /// Make a relative path in the config relative to the config file
+    pub fn resolve(&self, path: &str) -> PathBuf {
+        self.dir.join(path)
+    }
+
+    /// Same as resolve, for a path that might not be set
+    fn resolve_opt(&self, path: &Option<String>) -> Option<String> {
+        path.as_ref().map(|x| self.resolve(x).to_string_lossy().to_string())
+    }
+
+    /// The [history] settings for STTHistory, with a relative file made
+    /// relative to the config file
+    pub fn history_config(&self) -> HistoryConfig {
+        HistoryConfig { file: self.resolve_opt(&self.history.file), ..self.history.clone() }
+    }
+
+    /// The [subtitles] settings for STTSubtitles, with a relative dir made
+    /// relative to the config file
+    pub fn subtitle_config(&self) -> SubtitleConfig {
+        SubtitleConfig { dir: self.resolve_opt(&self.subtitles.dir), ..self.subtitles.clone() }
+    }
+
+    /// The [recordings] settings for STTRecordings, with a relative dir
+    /// made relative to the config file
+    pub fn recordings_config(&self) -> RecordingsConfig {
+        RecordingsConfig { dir: self.resolve_opt(&self.recordings.dir), ..self.recordings.clone() }
+    }
+
+    /// The directory to look for models in
+    pub fn models_dir(&self) -> PathBuf {
+        match &self.models_dir {
+            Some(x) => self.resolve(x),
+            None => PathBuf::from(".")
+        }
+    }
+
+    /// Work out where a model lives.  A bare filename is looked for in
+    /// models_dir first, then anything relative next to the config file,
+    /// then in $CWD as before.
+    pub fn model_file(&self, model: &str) -> PathBuf {
+        let path = Path::new(model);
+        let mut candidates = Vec::new();
+        if self.models_dir.is_some() && path.components().count() == 1 {
+            candidates.push(self.models_dir().join(path));
+        }
+        candidates.push(self.resolve(model));
+        candidates.push(path.to_path_buf());
+        match candidates.iter().find(|x| x.is_file()) {
+            Some(x) => x.to_path_buf(),
+            None => candidates.swap_remove(0)
+        }
+    }
+
+    /// Work out which config file to use.  If nothing is found anywhere,
+    /// write the default config out and use that.
+    pub fn discover(cli_path: Option<&str>) -> Result<PathBuf, MelonError> {
+        if let Some(x) = cli_path {
+            debug!("Using config from command line: {}", x);
+            return Ok(PathBuf::from(x));
+        }
+        if let Ok(x) = std::env::var(CONFIG_ENV) {
+            debug!("Using config from {}: {}", CONFIG_ENV, x);
+            return Ok(PathBuf::from(x));
+        }
+        let platform = dirs::config_dir().map(|x| x.join("melonstt").join(CONFIG_NAME));
+        if let Some(x) = platform.as_ref().filter(|x| x.is_file()) {
+            debug!("Using config from platform config dir: {}", x.display());
+            return Ok(x.to_path_buf());
+        }
+        let cwd = PathBuf::from(CONFIG_NAME);
+        if cwd.is_file() {
+            debug!("Using config from $CWD");
+            return Ok(cwd);
+        }
+        // First run.  Prefer the platform config dir, fall back to $CWD.
+        let target = platform.unwrap_or(cwd);
+        Self::write_default(&target)?;
+        Ok(target)
+    }
+
+    /// Write the commented default config to the given path
+    pub fn write_default(path: &Path) -> Result<(), MelonError> {
+        if let Some(dir) = path.parent().filter(|x| !x.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        match std::fs::write(path, DEFAULT_CONFIG) {
+            Ok(_) => {
+                warn!("No config found, wrote default config to {}", path.display());
+                Ok(())
+            },
+            Err(e) => {
+                error!("Unable to write default config to {}", path.display());
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Read, parse and validate a config file
+    pub fn load(path: &Path) -> Result<STTConfig, MelonError> {
+        let conf_data = match std::fs::read_to_string(path) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Unable to read config file");
+                return Err(MelonError::ConfigRead { path: path.to_path_buf(), source: e });
+            }
+        };
+        let mut config = match toml::from_str::<toml::Table>(&conf_data).and_then(Self::from_table) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Error parsing config toml");
+                return Err(e.into());
+            }
+        };
+        config.dir = path.parent().map(|x| x.to_path_buf()).unwrap_or_default();
+        // Check the top-level settings first, then what each profile ends up as
+        let mut issues = config.validate(&conf_data, None);
+        if issues.is_empty() {
+            for name in config.profile_names() {
+                match config.with_profile(Some(&name)) {
+                    Ok(x) => issues.extend(x.validate(&conf_data, Some(&name))),
+                    Err(e) => issues.push(ConfigIssue {
+                        line: None,
+                        message: format!("profile '{}': {}", name, e)
+                    }),
+                }
+            }
+        }
+        if let Some(x) = config.profile.as_ref().filter(|x| !config.profiles.contains_key(*x)) {
+            issues.push(ConfigIssue { line: None, message: format!("profile '{}' doesn't exist", x) });
+        }
+        if !issues.is_empty() {
+            for issue in issues.iter() {
+                error!("{}: {}", path.display(), issue);
+            }
+            return Err(MelonError::ConfigInvalid { path: path.to_path_buf(), issues });
+        }
+        Ok(config)
+    }
+
+    /// Build a config from an already-parsed TOML table
+    fn from_table(table: toml::Table) -> Result<STTConfig, toml::de::Error> {
+        let mut config: STTConfig = toml::Value::Table(table.clone()).try_into()?;
+        config.source = table;
+        Ok(config)
+    }
+
+    /// Names of all the profiles in the config, in alphabetical order
+    pub fn profile_names(&self) -> Vec<String> {
+        self.profiles.keys().cloned().collect()
+    }
+
+    /// Work out the settings with the named profile layered over the
+    /// top-level ones.  None gives the top-level settings on their own.
+    /// Tables are merged key by key; anything else (including lists like
+    /// pipeline) is replaced outright by the profile's version.
+    pub fn with_profile(&self, name: Option<&str>) -> Result<STTConfig, MelonError> {
+        let mut table = self.source.clone();
+        match name {
+            Some(name) => {
+                let overrides = match self.profiles.get(name) {
+                    Some(x) => x,
+                    None => return Err(MelonError::UnknownProfile(name.to_string()))
+                };
+                Self::merge(&mut table, overrides);
+                table.insert("profile".to_string(), toml::Value::String(name.to_string()));
+            },
+            None => { table.remove("profile"); }
+        }
+        let mut config = Self::from_table(table)?;
+        // Keep the untouched top-level settings around for the next switch
+        config.source = self.source.clone();
+        config.dir = self.dir.clone();
+        Ok(config)
+    }
+
+    /// Recursively copy everything in `over` on top of `base`
+    fn merge(base: &mut toml::Table, over: &toml::Table) {
+        for (key, value) in over {
+            match (base.get_mut(key), value) {
+                (Some(toml::Value::Table(b)), toml::Value::Table(o)) => Self::merge(b, o),
+                _ => { base.insert(key.to_string(), value.clone()); }
+            }
+        }
+    }
+
+    /// Check every setting makes sense.  `source` is the raw text of the
+    /// config file, used to work out which line each problem is on.  When
+    /// checking a profile, problems are pinned to the profile's own line
+    /// for a setting if it overrides it.
+    pub fn validate(&self, source: &str, profile: Option<&str>) -> Vec<ConfigIssue> {
+        let doc = toml_edit::ImDocument::parse(source.to_string()).ok();
+        let line = |path: &[&str]| {
+            let doc = doc.as_ref()?;
+            let in_profile = profile.and_then(|p| {
+                let full: Vec<&str> = ["profiles", p].iter().chain(path.iter()).copied().collect();
+                Self::line_of(doc, source, &full)
+            });
+            in_profile.or_else(|| Self::line_of(doc, source, path))
+        };
+        let mut issues = Vec::new();
+        let mut issue = |path: &[&str], message: String| {
+            let message = match profile {
+                Some(p) => format!("profile '{}': {}", p, message),
+                None => message
+            };
+            issues.push(ConfigIssue { line: line(path), message });
+        };
+
+        if self.language.trim().is_empty() || !self.language.chars().all(|x| x.is_ascii_alphabetic()) {
+            issue(&["language"], format!("language '{}' should be a language code like \"en\"", self.language));
+        }
+        if self.model.trim().is_empty() {
+            issue(&["model"], "model can't be empty".to_string());
+        } else if !self.model_file(&self.model).is_file() {
+            issue(&["model"], self.missing_model(source));
+        }
+        if self.threads < 1 {
+            issue(&["threads"], "threads should be at least 1".to_string());
+        }
+        if self.beam_size < 0 {
+            issue(&["beam_size"], "beam_size can't be negative".to_string());
+        }
+        if let Some(x) = &self.osc_endpoint {
+            if SocketAddrV4::from_str(x).is_err() {
+                issue(&["osc_endpoint"], format!("osc_endpoint '{}' should look like 127.0.0.1:9000", x));
+            }
+        }
+        if let Some(x) = &self.models_dir {
+            if !self.resolve(x).is_dir() {
+                issue(&["models_dir"], format!("models_dir '{}' is not a directory", x));
+            }
+        }
+        if !(0.0..=1.0).contains(&self.vad.threshold) {
+            issue(&["vad", "threshold"], "vad threshold should be between 0.0 and 1.0".to_string());
+        }
+        if self.vad.max_seconds < 1 {
+            issue(&["vad", "max_seconds"], "vad max_seconds should be at least 1".to_string());
+        }
+        if self.preroll.length_ms > MAX_PREROLL_MS {
+            issue(&["preroll", "length_ms"], format!("preroll length_ms should be at most {}", MAX_PREROLL_MS));
+        }
+        if !(0.0..=1.0).contains(&self.filter.no_speech_threshold) {
+            issue(&["filter", "no_speech_threshold"], "no_speech_threshold should be between 0.0 and 1.0".to_string());
+        }
+        for (i, rule) in self.pipeline.iter().enumerate() {
+            if let RuleConfig::Regex { pattern, .. } = rule {
+                if let Err(e) = regex::Regex::new(pattern) {
+                    let index = i.to_string();
+                    issue(&["pipeline", &index, "pattern"], format!("invalid regex: {}", e));
+                }
+            }
+        }
+        for (i, pattern) in self.censor.pii_patterns.iter().enumerate() {
+            if let Err(e) = regex::Regex::new(pattern) {
+                let index = i.to_string();
+                issue(&["censor", "pii_patterns", &index], format!("invalid regex: {}", e));
+            }
+        }
+        for (i, entry) in self.outputs.iter().enumerate() {
+            let index = i.to_string();
+            match &entry.output {
+                OutputConfig::File { path, .. } if path.trim().is_empty() => {
+                    issue(&["outputs", &index, "path"], "file output needs a path".to_string());
+                },
+                OutputConfig::Webhook { url, .. } if !url.starts_with("http://") && !url.starts_with("https://") => {
+                    issue(&["outputs", &index, "url"], format!("webhook url '{}' should start with http:// or https://", url));
+                },
+                OutputConfig::Irc(irc) => {
+                    if irc.server.trim().is_empty() {
+                        issue(&["outputs", &index, "server"], "irc output needs a server".to_string());
+                    }
+                    if irc.nick.trim().is_empty() {
+                        issue(&["outputs", &index, "nick"], "irc output needs a nick".to_string());
+                    }
+                    if irc.channel.trim_start_matches('#').trim().is_empty() {
+                        issue(&["outputs", &index, "channel"], "irc output needs a channel".to_string());
+                    }
+                },
+                _ => {}
+            }
+        }
+        if self.obs.enabled && !self.obs.url.starts_with("ws://") {
+            issue(&["obs", "url"], format!("obs url '{}' should look like ws://127.0.0.1:4455", self.obs.url));
+        }
+        if self.obs.enabled && self.obs.mode == crate::obs::CaptionMode::TextSource && self.obs.source.trim().is_empty() {
+            issue(&["obs", "source"], "obs source needs the name of a text source in text-source mode".to_string());
+        }
+        if self.recordings.enabled && !self.recordings.raw && !self.recordings.converted {
+            issue(&["recordings", "converted"], "recordings are on but neither raw nor converted is, so nothing would be saved".to_string());
+        }
+        if self.server.enabled && std::net::SocketAddr::from_str(&self.server.bind).is_err() {
+            issue(&["server", "bind"], format!("server bind '{}' should look like 127.0.0.1:8745", self.server.bind));
+        }
+        for (action, hotkey) in self.hotkeys.hotkeys() {
+            if let Err(e) = hotkey {
+                let key = action.name().replace('-', "_");
+                issue(&["hotkeys", &key], format!("invalid hotkey: {}", e));
+            }
+        }
+        issues
+    }
+
+    /// What to say about a model file that isn't there.  An untouched
+    /// default config is almost certainly a first run, so say where the
+    /// config went and where the model should go instead of just failing.
+    fn missing_model(&self, source: &str) -> String {
+        if source != DEFAULT_CONFIG {
+            return format!("model file '{}' not found", self.model);
+        }
+        let dir = match self.dir.as_os_str().is_empty() {
+            true => "the current directory".to_string(),
+            false => self.dir.display().to_string()
+        };
+        format!("model file '{}' not found.  Looks like a first run: the default config was written to {}. \
+            Download a Whisper model (see \"Language Model\" in the README) and put it in that directory, \
+            or point `model` in the config at one you already have", self.model, dir)
+    }
+
+    /// Find the line a (possibly nested) key is on.  Numeric path parts
+    /// index into arrays, so ["pipeline", "2", "pattern"] works.
+    fn line_of(doc: &toml_edit::ImDocument<String>, source: &str, path: &[&str]) -> Option<usize> {
+        let mut item = doc.as_item();
+        for part in path {
+            item = match part.parse::<usize>() {
+                Ok(i) => item.get(i)?,
+                Err(_) => item.get(*part)?,
+            };
+        }
+        let span = item.span()?;
+        Some(source[..span.start].matches('\n').count() + 1)
+    }
+
+    /// Work out what the config file should hold so that loading it and
+    /// applying `effective`'s profile gives back `effective`.  With no
+    /// profile active the top-level settings are replaced; otherwise
+    /// anything that differs from the top level goes into the profile.
+    pub fn fold_in(&self, effective: &STTConfig) -> Result<STTConfig, MelonError> {
+        let mut retval = Self::to_table(self)?;
+        let mut settings = Self::to_table(effective)?;
+        settings.remove("profile");
+        settings.remove("profiles");
+        match effective.profile.as_deref() {
+            Some(name) => {
+                let old = self.profiles.get(name).cloned().unwrap_or_default();
+                let overrides = Self::overrides(&retval, &settings, &old);
+                if let Some(toml::Value::Table(x)) = retval.get_mut("profiles") {
+                    x.insert(name.to_string(), toml::Value::Table(overrides));
+                }
+            },
+            None => {
+                retval.retain(|k, _| k == "profile" || k == "profiles");
+                retval.extend(settings);
+            }
+        }
+        let mut retval = Self::from_table(retval)?;
+        retval.dir = self.dir.clone();
+        Ok(retval)
+    }
+
+    /// Find the settings in `effective` that differ from `base`.  Anything
+    /// already in the profile (`old`) is kept even if it matches `base`.
+    fn overrides(base: &toml::Table, effective: &toml::Table, old: &toml::Table) -> toml::Table {
+        let mut retval = toml::Table::new();
+        for (key, value) in effective {
+            let old = old.get(key);
+            match (base.get(key), value) {
+                (Some(toml::Value::Table(b)), toml::Value::Table(e)) => {
+                    let old = old.and_then(|x| x.as_table()).cloned().unwrap_or_default();
+                    let sub = Self::overrides(b, e, &old);
+                    if !sub.is_empty() { retval.insert(key.to_string(), toml::Value::Table(sub)); }
+                },
+                (Some(b), v) if b == v && old.is_none() => {},
+                _ => { retval.insert(key.to_string(), value.clone()); }
+            }
+        }
+        retval
+    }
+
+    /// Save these settings to a config file, keeping the comments and
+    /// layout of whatever's already there.  Only values that changed are
+    /// rewritten, and settings missing from the file are only added if
+    /// they differ from the defaults.  The old file is kept as a .bak and
+    /// the new one is moved into place in one go, so it's never half-written.
+    pub fn save(&self, path: &Path) -> Result<(), MelonError> {
+        let existing = match std::fs::read_to_string(path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                error!("Unable to read config file before saving");
+                return Err(MelonError::ConfigRead { path: path.to_path_buf(), source: e });
+            }
+        };
+        // Don't clobber a file the user has broken while we were running
+        let mut doc = match existing.parse::<toml_edit::DocumentMut>() {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Config file doesn't parse, not saving over it");
+                return Err(write_error(path, std::io::Error::new(std::io::ErrorKind::InvalidData, e)));
+            }
+        };
+        let new = Self::to_table(self)?;
+        let defaults = Self::to_table(&STTConfig::default())?;
+        Self::update_table(doc.as_table_mut(), &new, Some(&defaults));
+        match Self::write_atomic(path, &doc.to_string()) {
+            Ok(_) => {
+                debug!("Saved config to {}", path.display());
+                Ok(())
+            },
+            Err(e) => {
+                error!("Unable to save config to {}", path.display());
+                Err(write_error(path, e))
+            }
+        }
+    }
+
+    /// Turn a config into a plain TOML table
+    fn to_table(config: &STTConfig) -> Result<toml::Table, MelonError> {
+        match toml::Value::try_from(config)? {
+            toml::Value::Table(x) => Ok(x),
+            _ => Ok(toml::Table::new())
+        }
+    }
+
+    /// Bring a table in the file up to date with `new`, touching as little
+    /// as possible.  `defaults` holds the built-in values for this table.
+    fn update_table(table: &mut dyn toml_edit::TableLike, new: &toml::Table, defaults: Option<&toml::Table>) {
+        // Settings that are no longer set at all (like a cleared prompt).
+        // Any comments above them move down onto the next setting.
+        let keys: Vec<String> = table.iter().map(|(k, _)| k.to_string()).collect();
+        let mut orphaned = String::new();
+        for key in keys {
+            let prefix = table.key(&key)
+                .and_then(|x| x.leaf_decor().prefix())
+                .and_then(|x| x.as_str())
+                .unwrap_or_default()
+                .to_string();
+            if !new.contains_key(&key) {
+                orphaned.push_str(&prefix);
+                table.remove(&key);
+            } else if !orphaned.is_empty() && table.get(&key).is_some_and(|x| x.is_value()) {
+                if let Some(mut x) = table.key_mut(&key) {
+                    x.leaf_decor_mut().set_prefix(std::mem::take(&mut orphaned) + &prefix);
+                }
+            }
+        }
+        for (key, value) in new {
+            let default = defaults.and_then(|x| x.get(key));
+            match table.get_mut(key) {
+                Some(item) => match value {
+                    toml::Value::Table(x) if item.is_table_like() => {
+                        if let Some(sub) = item.as_table_like_mut() {
+                            Self::update_table(sub, x, default.and_then(|x| x.as_table()));
+                        }
+                    },
+                    _ if Self::item_value(item).as_ref() == Some(value) => {},
+                    // Lists of tables leave out their defaults, so fill them in before comparing
+                    toml::Value::Array(_) if Self::item_value(item).and_then(|x| Self::with_defaults(key, x)).as_ref() == Some(value) => {},
+                    _ => {
+                        let mut new = Self::to_item(value);
+                        Self::keep_layout(item, &mut new);
+                        *item = new;
+                    }
+                },
+                None if default == Some(value) => {},
+                None => { table.insert(key, Self::to_item(value)); }
+            }
+        }
+    }
+
+    /// Read back what a piece of the file actually says
+    fn item_value(item: &toml_edit::Item) -> Option<toml::Value> {
+        let mut doc = toml_edit::DocumentMut::new();
+        doc.insert("v", item.clone());
+        toml::from_str::<toml::Table>(&doc.to_string()).ok()?.remove("v")
+    }
+
+    /// Fill in the defaults for a setting as written in the file, so that
+    /// e.g. an [[outputs]] table without enabled = true still counts as
+    /// unchanged.  None if it isn't a top-level setting.
+    fn with_defaults(key: &str, value: toml::Value) -> Option<toml::Value> {
+        let mut table = toml::Table::new();
+        table.insert(key.to_string(), value);
+        let config = Self::from_table(table).ok()?;
+        Self::to_table(&config).ok()?.remove(key)
+    }
+
+    /// Carry comments and position over from an old value to its replacement
+    fn keep_layout(old: &toml_edit::Item, new: &mut toml_edit::Item) {
+        match (old, new) {
+            (toml_edit::Item::Value(o), toml_edit::Item::Value(n)) => {
+                *n.decor_mut() = o.decor().clone();
+            },
+            (toml_edit::Item::Table(o), toml_edit::Item::Table(n)) => {
+                *n.decor_mut() = o.decor().clone();
+                n.set_position(o.position().unwrap_or_default());
+            },
+            (toml_edit::Item::ArrayOfTables(o), toml_edit::Item::ArrayOfTables(n)) => {
+                let mut position = 0;
+                for (i, n) in n.iter_mut().enumerate() {
+                    if let Some(o) = o.get(i) {
+                        *n.decor_mut() = o.decor().clone();
+                        position = o.position().unwrap_or(position);
+                    }
+                    n.set_position(position);
+                }
+            },
+            _ => {}
+        }
+    }
+
+    /// Build a fresh piece of the file for a value.  Tables become [table]
+    /// sections and lists of tables become [[table]] sections; anything
+    /// nested inside those is written inline.
+    fn to_item(value: &toml::Value) -> toml_edit::Item {
+        match value {
+            toml::Value::Table(x) => {
+                let mut table = toml_edit::Table::new();
+                table.set_implicit(true);
+                for (k, v) in x {
+                    table.insert(k, Self::to_item(v));
+                }
+                toml_edit::Item::Table(table)
+            },
+            toml::Value::Array(x) if !x.is_empty() && x.iter().all(|x| x.is_table()) => {
+                let mut array = toml_edit::ArrayOfTables::new();
+                for entry in x.iter().filter_map(|x| x.as_table()) {
+                    let mut table = toml_edit::Table::new();
+                    for (k, v) in entry {
+                        table.insert(k, toml_edit::Item::Value(Self::to_value(v)));
+                    }
+                    array.push(table);
+                }
+                toml_edit::Item::ArrayOfTables(array)
+            },
+            _ => toml_edit::Item::Value(Self::to_value(value))
+        }
+    }
+
+    /// Convert a single value to its inline form
+    fn to_value(value: &toml::Value) -> toml_edit::Value {
+        match value {
+            toml::Value::String(x) => x.as_str().into(),
+            toml::Value::Integer(x) => (*x).into(),
+            toml::Value::Float(x) => (*x).into(),
+            toml::Value::Boolean(x) => (*x).into(),
+            toml::Value::Datetime(x) => (*x).into(),
+            toml::Value::Array(x) => toml_edit::Value::Array(x.iter().map(Self::to_value).collect()),
+            toml::Value::Table(x) => {
+                let mut table = toml_edit::InlineTable::new();
+                for (k, v) in x {
+                    table.insert(k, Self::to_value(v));
+                }
+                toml_edit::Value::InlineTable(table)
+            }
+        }
+    }
+
+    /// Write a file by writing a temporary file next to it and renaming it
+    /// over the top, keeping the previous version as <name>.bak
+    fn write_atomic(path: &Path, contents: &str) -> std::io::Result<()> {
+        let name = path.file_name().map(|x| x.to_string_lossy().to_string()).unwrap_or_default();
+        let temp = path.with_file_name(format!("{}.tmp", name));
+        let backup = path.with_file_name(format!("{}.bak", name));
+        let mut file = std::fs::File::create(&temp)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        drop(file);
+        if path.is_file() {
+            std::fs::copy(path, &backup)?;
+        }
+        std::fs::rename(&temp, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch;
+
+    #[test]
+    fn model_is_found_next_to_config() {
+        let dir = scratch("model-next-to-config");
+        std::fs::write(dir.join("ggml-test.bin"), b"ggml").unwrap();
+        std::fs::create_dir(dir.join("models")).unwrap();
+        std::fs::write(dir.join("models").join("ggml-other.bin"), b"ggml").unwrap();
+        let path = dir.join(CONFIG_NAME);
+        std::fs::write(&path, "model = \"ggml-test.bin\"\nmodels_dir = \"models\"\n").unwrap();
+        let config = STTConfig::load(&path).unwrap();
+        assert_eq!(config.model_file(&config.model), dir.join("ggml-test.bin"));
+        assert_eq!(config.model_file("ggml-other.bin"), dir.join("models").join("ggml-other.bin"));
+        assert_eq!(config.models_dir(), dir.join("models"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn data_paths_are_relative_to_config() {
+        let dir = scratch("data-paths");
+        std::fs::write(dir.join("ggml-test.bin"), b"ggml").unwrap();
+        let path = dir.join(CONFIG_NAME);
+        std::fs::write(&path, "\
+model = \"ggml-test.bin\"
+
+[history]
+file = \"history.json\"
+
+[subtitles]
+dir = \"captions\"
+").unwrap();
+        let config = STTConfig::load(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(config.history_config().file.map(PathBuf::from), Some(dir.join("history.json")));
+        assert_eq!(config.subtitle_config().dir.map(PathBuf::from), Some(dir.join("captions")));
+        assert_eq!(config.recordings_config().dir, None);
+        // What gets saved back is still what was written
+        assert_eq!(config.history.file.as_deref(), Some("history.json"));
+    }
+
+    const COMMENTED: &str = "\
+# My settings, don't touch
+language = \"en\"   # spoken language
+model = \"ggml-base.bin\"
+threads = 8
+
+# Stop talking after this
+[vad]
+# How loud counts as speech
+threshold = 0.02
+silence_ms = 800
+";
+
+    #[test]
+    fn save_keeps_comments_and_order() {
+        let dir = scratch("save");
+        let path = dir.join(CONFIG_NAME);
+        std::fs::write(&path, COMMENTED).unwrap();
+        let mut config = STTConfig::from_table(toml::from_str(COMMENTED).unwrap()).unwrap();
+        config.language = "ja".to_string();
+        config.vad.silence_ms = 500;
+        config.save(&path).unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        let backup = std::fs::read_to_string(path.with_extension("toml.bak")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(backup, COMMENTED);
+        for comment in ["# My settings, don't touch", "# spoken language", "# Stop talking after this", "# How loud counts as speech"] {
+            assert!(saved.contains(comment), "lost comment '{}' in:\n{}", comment, saved);
+        }
+        assert!(saved.contains("language = \"ja\""));
+        assert!(saved.contains("silence_ms = 500"));
+        assert!(saved.contains("threshold = 0.02"));
+        assert!(saved.contains("threads = 8"));
+        let at = |x: &str| saved.find(x).unwrap();
+        assert!(at("language") < at("model") && at("model") < at("threads") && at("threads") < at("[vad]"));
+        assert!(at("threshold") < at("silence_ms"));
+        // Nothing that was left at its default got written out
+        assert!(!saved.contains("beam_size"));
+
+        let reloaded = STTConfig::from_table(toml::from_str(&saved).unwrap()).unwrap();
+        assert!(reloaded.language == "ja" && reloaded.vad.silence_ms == 500 && reloaded.threads == 8);
+    }
+
+    #[test]
+    fn save_over_default_config_changes_one_line() {
+        let dir = scratch("save-default");
+        let path = dir.join(CONFIG_NAME);
+        std::fs::write(&path, DEFAULT_CONFIG).unwrap();
+        let mut config = STTConfig::from_table(toml::from_str(DEFAULT_CONFIG).unwrap()).unwrap();
+        config.beam_size = 5;
+        config.save(&path).unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let changed: Vec<(&str, &str)> = DEFAULT_CONFIG.lines().zip(saved.lines()).filter(|(a, b)| a != b).collect();
+        assert_eq!(changed, vec![("beam_size = 1", "beam_size = 5")]);
+        assert_eq!(DEFAULT_CONFIG.lines().count(), saved.lines().count());
+    }
+
+    /// Load a config that should fail, and get back what's wrong with it
+    fn issues_of(path: &Path) -> Vec<ConfigIssue> {
+        match STTConfig::load(path) {
+            Err(MelonError::ConfigInvalid { issues, .. }) => issues,
+            Err(e) => panic!("expected config issues, got {}", e),
+            Ok(_) => panic!("expected config issues, but {} loaded", path.display()),
+        }
+    }
+
+    #[test]
+    fn issues_point_at_their_lines() {
+        let dir = scratch("issue-lines");
+        std::fs::write(dir.join("ggml-test.bin"), b"ggml").unwrap();
+        let path = dir.join(CONFIG_NAME);
+        std::fs::write(&path, "\
+model = \"ggml-test.bin\"
+language = \"en-US\"
+threads = 4
+osc_endpoint = \"localhost\"
+").unwrap();
+        let issues = issues_of(&path);
+        let lines: Vec<Option<usize>> = issues.iter().map(|x| x.line).collect();
+        assert_eq!(lines, vec![Some(2), Some(4)], "{:?}", issues);
+        assert!(issues[0].message.contains("en-US"));
+        assert!(issues[1].message.contains("localhost"));
+        assert_eq!(issues[1].to_string(), "line 4: osc_endpoint 'localhost' should look like 127.0.0.1:9000");
+
+        // A bad value in a profile is pinned to the profile's line, not
+        // the top-level one it overrides
+        std::fs::write(&path, "\
+model = \"ggml-test.bin\"
+osc_endpoint = \"127.0.0.1:9000\"
+
+[profiles.stream]
+language = \"ja\"
+osc_endpoint = \"127.0.0.1\"
+").unwrap();
+        let issues = issues_of(&path);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(issues.len(), 1, "{:?}", issues);
+        assert_eq!(issues[0].line, Some(6));
+        assert!(issues[0].message.starts_with("profile 'stream': osc_endpoint '127.0.0.1'"));
+    }
+
+    #[test]
+    fn first_run_says_where_the_model_goes() {
+        let dir = scratch("first-run");
+        let path = dir.join(CONFIG_NAME);
+        STTConfig::write_default(&path).unwrap();
+        let issues = issues_of(&path);
+        let model = issues.iter().find(|x| x.message.starts_with("model file")).unwrap();
+        assert!(model.message.contains("first run"), "{}", model.message);
+        assert!(model.message.contains(&dir.display().to_string()), "{}", model.message);
+
+        // Once the config's been edited it's just a missing file
+        std::fs::write(&path, format!("{}\n# edited\n", DEFAULT_CONFIG)).unwrap();
+        let issues = issues_of(&path);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let model = issues.iter().find(|x| x.message.starts_with("model file")).unwrap();
+        assert!(!model.message.contains("first run"), "{}", model.message);
+    }
+
+    const PROFILES: &str = "\
+model = \"ggml-tiny.en.bin\"
+language = \"en\"
+
+[vad]
+threshold = 0.02
+silence_ms = 800
+
+[[pipeline]]
+type = \"trim\"
+
+[[pipeline]]
+type = \"emoji\"
+
+[profiles.quiet.vad]
+threshold = 0.005
+
+[profiles.japanese]
+model = \"ggml-base.bin\"
+language = \"ja\"
+pipeline = [{ type = \"punctuation\" }]
+";
+
+    #[test]
+    fn profile_overrides_nested_tables_key_by_key() {
+        let config = STTConfig::from_table(toml::from_str(PROFILES).unwrap()).unwrap();
+        let quiet = config.with_profile(Some("quiet")).unwrap();
+        assert_eq!(quiet.profile.as_deref(), Some("quiet"));
+        assert_eq!(quiet.vad.threshold, 0.005);
+        // Everything else in [vad] comes from the top level
+        assert_eq!(quiet.vad.silence_ms, 800);
+        assert_eq!(quiet.pipeline, config.pipeline);
+        assert_eq!(quiet.model, config.model);
+
+        // Switching back gives the top level again
+        let back = quiet.with_profile(None).unwrap();
+        assert_eq!(back.profile, None);
+        assert_eq!(back.vad.threshold, 0.02);
+    }
+
+    #[test]
+    fn profile_can_change_model_and_replace_lists() {
+        let config = STTConfig::from_table(toml::from_str(PROFILES).unwrap()).unwrap();
+        let japanese = config.with_profile(Some("japanese")).unwrap();
+        assert_eq!(japanese.model, "ggml-base.bin");
+        assert_eq!(japanese.language, "ja");
+        assert_eq!(japanese.model_file(&japanese.model), PathBuf::from("ggml-base.bin"));
+        // Lists aren't merged, the profile's replaces the top-level one
+        assert_eq!(japanese.pipeline, vec![RuleConfig::Punctuation]);
+        assert_eq!(japanese.vad.threshold, 0.02);
+        // Straight from one profile to another, nothing of the first is left
+        let quiet = japanese.with_profile(Some("quiet")).unwrap();
+        assert!(quiet.model == "ggml-tiny.en.bin" && quiet.language == "en" && quiet.pipeline.len() == 2);
+    }
+
+    #[test]
+    fn unknown_profile_is_an_error() {
+        let config = STTConfig::from_table(toml::from_str(PROFILES).unwrap()).unwrap();
+        assert_eq!(config.profile_names(), vec!["japanese".to_string(), "quiet".to_string()]);
+        match config.with_profile(Some("loud")) {
+            Err(MelonError::UnknownProfile(x)) => assert_eq!(x, "loud"),
+            Err(e) => panic!("expected UnknownProfile, got {}", e),
+            Ok(_) => panic!("profile 'loud' shouldn't exist"),
+        }
+    }
+}
@@ -0,0 +1,195 @@
+//! This module defines the error type returned by everything in the
+//! library.  Each subsystem gets its own variants so callers can tell
+//! "no microphone" apart from "model missing" apart from "OSC send failed"
+//! and decide what to tell the user or whether to try again.
+use std::error::Error;
+use std::path::PathBuf;
+use thiserror::Error;
+use crate::config::ConfigIssue;
+
+/// Everything that can go wrong inside MelonSTT
+#[derive(Debug, Error)]
+pub enum MelonError {
+    /// The config file couldn't be read off disk
+    #[error("Unable to read config file {path}: {source}")]
+    ConfigRead {
+        /// The config file that was being read
+        path: PathBuf,
+        /// What went wrong reading it
+        source: std::io::Error,
+    },
+    /// The config file isn't valid TOML or has the wrong types in it
+    #[error("Error in config file: {0}")]
+    ConfigParse(#[from] toml::de::Error),
+    /// The config parsed fine but some settings don't make sense
+    #[error("Problems in config file {}:\n{}", path.display(), issues.iter().map(|x| x.to_string()).collect::<Vec<_>>().join("\n"))]
+    ConfigInvalid {
+        /// The config file that was being checked
+        path: PathBuf,
+        /// Everything that's wrong with it, with line numbers where possible
+        issues: Vec<ConfigIssue>,
+    },
+    /// The config file couldn't be saved
+    #[error("Unable to save config file {path}: {source}")]
+    ConfigWrite {
+        /// The config file that was being written
+        path: PathBuf,
+        /// What went wrong writing it
+        source: std::io::Error,
+    },
+    /// The settings couldn't be turned back into TOML
+    #[error("Unable to convert settings to TOML: {0}")]
+    ConfigSerialize(#[from] toml::ser::Error),
+    /// A profile was asked for that isn't in the config
+    #[error("There's no profile called '{0}' in the config")]
+    UnknownProfile(String),
+    /// A regular expression in the config doesn't compile
+    #[error("Invalid pattern '{pattern}' in config: {source}")]
+    InvalidPattern {
+        /// The offending pattern
+        pattern: String,
+        /// What the regex crate had to say about it
+        source: regex::Error,
+    },
+    /// There's no microphone or other input device to record from
+    #[error("No recording devices found.  Plug in a microphone and restart.")]
+    NoInputDevice,
+    /// The input device named in the config isn't plugged in
+    #[error("Input device '{0}' not found")]
+    UnknownDevice(String),
+    /// Asked to stop recording when no recording was running
+    #[error("Not currently recording")]
+    NotRecording,
+    /// Something went wrong talking to the audio device
+    #[error("Audio device error ({context}): {source}")]
+    AudioDevice {
+        /// What we were trying to do at the time
+        context: &'static str,
+        /// The underlying cpal error
+        source: Box<dyn Error + Send + Sync>,
+    },
+    /// The recorded samples couldn't be pulled out and converted
+    #[error("Error converting recorded audio: {0}")]
+    AudioConversion(String),
+    /// The model file doesn't exist
+    #[error("Model file {0} not found.  Check 'model' in melon.toml.")]
+    ModelMissing(PathBuf),
+    /// The model file exists but isn't a usable ggml model
+    #[error("Model file {path} isn't a usable Whisper model: {reason}")]
+    ModelInvalid {
+        /// The model file that was being read
+        path: PathBuf,
+        /// Why it isn't usable
+        reason: String,
+    },
+    /// Whisper itself failed to load a model or run a transcription
+    #[error("Whisper error: {0}")]
+    Whisper(#[from] whisper_rs::WhisperError),
+    /// The OSC endpoint in the config isn't a valid ip:port
+    #[error("Invalid OSC endpoint '{addr}': {source}")]
+    InvalidEndpoint {
+        /// The address string from the config
+        addr: String,
+        /// Why it didn't parse
+        source: std::net::AddrParseError,
+    },
+    /// The local UDP socket couldn't be bound
+    #[error("Unable to open local UDP socket: {0}")]
+    SocketBind(#[source] std::io::Error),
+    /// An OSC packet couldn't be encoded
+    #[error("Error encoding OSC packet: {0}")]
+    OscEncode(#[from] rosc::OscError),
+    /// An OSC packet couldn't be sent
+    #[error("Error sending OSC packet: {0}")]
+    OscSend(#[source] std::io::Error),
+    /// The history couldn't be converted to or from JSON
+    #[error("Error reading or writing history: {0}")]
+    HistoryFormat(#[from] serde_json::Error),
+    /// There's no history entry with the given id
+    #[error("No history entry with id {0}")]
+    UnknownEntry(u64),
+    /// A subtitle file couldn't be written
+    #[error("Unable to write subtitles to {path}: {source}")]
+    SubtitleWrite {
+        /// The file (or directory) being written
+        path: PathBuf,
+        /// What went wrong writing it
+        source: std::io::Error,
+    },
+    /// A recording couldn't be saved as a WAV file
+    #[error("Unable to save recording to {path}: {source}")]
+    RecordingWrite {
+        /// The file being written
+        path: PathBuf,
+        /// What went wrong writing it
+        source: hound::Error,
+    },
+    /// A saved recording couldn't be read back
+    #[error("Unable to read recording {path}: {source}")]
+    RecordingRead {
+        /// The file being read
+        path: PathBuf,
+        /// What went wrong reading it
+        source: hound::Error,
+    },
+    /// A history entry has no saved recording to work with
+    #[error("History entry {0} has no saved recording")]
+    NoRecording(u64),
+    /// A message couldn't be sent to one of the outputs
+    #[error("Unable to send to {output}: {reason}")]
+    Output {
+        /// Which output it was
+        output: String,
+        /// Why it failed
+        reason: String,
+    },
+    /// Couldn't connect or talk to OBS
+    #[error("OBS error: {0}")]
+    Obs(String),
+    /// OBS got a request but refused it; holds its reason
+    #[error("OBS refused the caption: {0}")]
+    ObsRequest(String),
+    /// Couldn't connect, log in or talk to an IRC server
+    #[error("IRC error: {0}")]
+    Irc(String),
+    /// A key in the [hotkeys] table couldn't be understood
+    #[error("Invalid hotkey for {action}: {reason}")]
+    InvalidHotkey {
+        /// The action the key was for
+        action: &'static str,
+        /// What's wrong with it
+        reason: String,
+    },
+    /// Global hotkeys couldn't be set up
+    #[error("Unable to set up hotkeys: {0}")]
+    Hotkey(String),
+    /// Some other filesystem error
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    /// A lock was poisoned by a thread panicking while holding it
+    #[error("Internal lock poisoned: {0}")]
+    LockPoisoned(&'static str),
+    /// A background thread panicked before it could finish
+    #[error("Background thread panicked: {0}")]
+    ThreadPanicked(&'static str),
+}
+
+impl MelonError {
+    /// Whether the same operation might work if it's simply tried again.
+    /// Config and model problems need the user to fix something first;
+    /// device hiccups and dropped packets often sort themselves out.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self,
+            MelonError::AudioDevice { .. }
+            | MelonError::AudioConversion(_)
+            | MelonError::OscSend(_)
+            | MelonError::Obs(_)
+            | MelonError::Irc(_)
+            | MelonError::SocketBind(_))
+    }
+
+    /// Wrap a cpal (or any other device) error with what we were doing at the time
+    pub fn audio_device(context: &'static str, source: impl Error + Send + Sync + 'static) -> MelonError {
+        MelonError::AudioDevice { context, source: Box::new(source) }
+    }
+}
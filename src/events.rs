@@ -0,0 +1,133 @@
+//! This module defines the events MelonSTT announces to anyone listening,
+//! so a UI (or anything else) can find out about things that happen in
+//! the background like a config reload or a model finishing loading.
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use serde_derive::Serialize;
+use log::{debug, warn};
+
+/// How many events a subscriber can fall behind by before it gets dropped
+pub const EVENT_BACKLOG: usize = 64;
+
+/// Something that happened inside MelonSTT.  Serializes as
+/// {"event": "model_loaded", "data": ...} for the control server.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum MelonEvent {
+    /// The config file was reloaded; holds a description of each change applied
+    ConfigReloaded(Vec<String>),
+    /// The config file changed but couldn't be applied; holds the reason
+    ConfigError(String),
+    /// A new model finished loading and is now in use
+    ModelLoaded(String),
+    /// A different profile was switched to; None means no profile
+    ProfileChanged(Option<String>),
+    /// Something was added to, changed in or removed from the history
+    HistoryChanged,
+    /// A model failed to load; the previous model is still in use
+    ModelLoadFailed {
+        /// The model that was being loaded
+        model: String,
+        /// Why it failed
+        error: String,
+    },
+    /// A recording started
+    RecordingStarted,
+    /// A recording was stopped and thrown away
+    RecordingCancelled,
+    /// A recording finished transcribing; empty if nothing was said
+    Transcribed(String),
+    /// A message went out to the chatbox; holds the text that was sent
+    Sent(String),
+    /// A message couldn't be sent to one of the other outputs (OBS, etc)
+    OutputFailed {
+        /// Which output it was
+        output: String,
+        /// Why it failed
+        error: String,
+    },
+}
+
+/// Hands out receivers and sends every event to all of them
+#[derive(Clone, Default)]
+pub struct EventBus {
+    /// Everyone currently listening
+    subscribers: Arc<Mutex<Vec<SyncSender<MelonEvent>>>>,
+}
+
+impl EventBus {
+    /// Create an event bus with nobody listening yet
+    pub fn new() -> EventBus {
+        EventBus::default()
+    }
+
+    /// Start listening for events.  Dropping the receiver unsubscribes.
+    /// Events are never held up for anyone, so a receiver that falls
+    /// more than EVENT_BACKLOG events behind gets cut off instead, and
+    /// sees the channel close once it catches up.
+    pub fn subscribe(&self) -> Receiver<MelonEvent> {
+        let (tx, rx) = sync_channel(EVENT_BACKLOG);
+        if let Ok(mut subs) = self.subscribers.lock() {
+            subs.push(tx);
+        }
+        rx
+    }
+
+    /// Send an event to every subscriber, forgetting any that have gone
+    /// away or fallen too far behind
+    pub fn emit(&self, event: MelonEvent) {
+        debug!("Event: {:?}", event);
+        if let Ok(mut subs) = self.subscribers.lock() {
+            subs.retain(|x| match x.try_send(event.clone()) {
+                Ok(_) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("Event subscriber fell too far behind, dropping it");
+                    false
+                },
+                Err(TrySendError::Disconnected(_)) => false
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn everyone_gets_every_event() {
+        let bus = EventBus::new();
+        let (a, b) = (bus.subscribe(), bus.subscribe());
+        bus.emit(MelonEvent::RecordingStarted);
+        bus.emit(MelonEvent::Transcribed("hi".to_string()));
+        for rx in [a, b] {
+            assert_eq!(rx.try_recv(), Ok(MelonEvent::RecordingStarted));
+            assert_eq!(rx.try_recv(), Ok(MelonEvent::Transcribed("hi".to_string())));
+        }
+    }
+
+    #[test]
+    fn subscriber_that_falls_behind_is_dropped() {
+        let bus = EventBus::new();
+        let slow = bus.subscribe();
+        let fast = bus.subscribe();
+        for i in 0..=EVENT_BACKLOG {
+            bus.emit(MelonEvent::Sent(i.to_string()));
+            assert_eq!(fast.try_recv(), Ok(MelonEvent::Sent(i.to_string())));
+        }
+        // The slow one gets what fit, then finds the channel closed
+        assert_eq!(slow.try_iter().count(), EVENT_BACKLOG);
+        assert_eq!(slow.try_recv(), Err(std::sync::mpsc::TryRecvError::Disconnected));
+        bus.emit(MelonEvent::HistoryChanged);
+        assert_eq!(fast.try_recv(), Ok(MelonEvent::HistoryChanged));
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn dropped_receivers_are_forgotten() {
+        let bus = EventBus::new();
+        drop(bus.subscribe());
+        bus.emit(MelonEvent::HistoryChanged);
+        assert!(bus.subscribers.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,238 @@
+//! This module cleans up Whisper's output before it goes anywhere near
+//! the chatbox.  Whisper (tiny in particular) loves to hallucinate things
+//! like "Thank you." or "[BLANK_AUDIO]" when it's fed silence, and will
+//! sometimes get stuck repeating the same phrase over and over.
+use crate::processor::STTSegment;
+use serde_derive::{Deserialize, Serialize};
+use log::debug;
+
+/// Settings for the hallucination filter, read from the [filter] table
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct FilterConfig {
+    /// Turn the whole filter stage on or off
+    pub enabled: bool,
+    /// Drop anything inside [brackets], (parentheses) or *asterisks*
+    pub drop_bracketed: bool,
+    /// Segments with an estimated no-speech probability above this are dropped
+    #[serde(serialize_with = "tidy_f32")]
+    pub no_speech_threshold: f32,
+    /// A phrase repeated more than this many times in a row gets collapsed
+    /// down to a single copy.  Single words always get at least
+    /// MIN_WORD_RUN repeats, since people really do say "no no no".
+    pub max_repeats: usize,
+    /// Transcriptions that are known hallucinations.  Compared against the
+    /// whole result, ignoring case and punctuation.
+    pub blocklist: Vec<String>,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        FilterConfig {
+            enabled: true,
+            drop_bracketed: true,
+            no_speech_threshold: 0.6,
+            max_repeats: 2,
+            blocklist: vec![
+                "Thank you.".to_string(),
+                "Thanks for watching!".to_string(),
+                "Thank you for watching.".to_string(),
+                "Please subscribe.".to_string(),
+                "Subtitles by the Amara.org community".to_string(),
+            ],
+        }
+    }
+}
+
+/// Shortest run of one repeated word that counts as Whisper getting stuck
+pub const MIN_WORD_RUN: usize = 4;
+
+/// Save an f32 the way it would be typed (0.6, not 0.6000000238418579)
+pub(crate) fn tidy_f32<S: serde::Serializer>(value: &f32, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(value.to_string().parse().unwrap_or(*value as f64))
+}
+
+/// The filter stage itself
+pub struct STTFilter {
+    /// The settings this filter was built with
+    pub config: FilterConfig,
+}
+
+impl STTFilter {
+    /// Build a new filter from the given settings
+    pub fn new(config: &FilterConfig) -> STTFilter {
+        STTFilter { config: config.clone() }
+    }
+
+    /// Run a set of transcribed segments through the filter and hand back
+    /// whatever is left.  An empty string means there was nothing worth sending.
+    pub fn filter(&self, segments: &[STTSegment]) -> String {
+        if !self.config.enabled {
+            return segments.iter().map(|x| x.text.as_str()).collect::<String>().trim().to_string();
+        }
+        let kept: Vec<&str> = self.kept_segments(segments).iter().map(|x| x.text.as_str()).collect();
+        self.filter_text(&kept.join(" "))
+    }
+
+    /// The segments that are likely enough to be speech to keep
+    pub fn kept_segments<'a>(&self, segments: &'a [STTSegment]) -> Vec<&'a STTSegment> {
+        if !self.config.enabled { return segments.iter().collect(); }
+        let mut kept = Vec::new();
+        for segment in segments {
+            if segment.no_speech_prob > self.config.no_speech_threshold {
+                debug!("Dropping segment '{}' (no-speech prob {:.2})", segment.text, segment.no_speech_prob);
+                continue;
+            }
+            kept.push(segment);
+        }
+        kept
+    }
+
+    /// Apply the text-only parts of the filter (tags, repeats, blocklist)
+    pub fn filter_text(&self, text: &str) -> String {
+        if !self.config.enabled { return text.trim().to_string(); }
+        let mut text = text.to_string();
+        if self.config.drop_bracketed {
+            text = Self::strip_tags(&text);
+        }
+        text = Self::collapse_repeats(&text, self.config.max_repeats);
+        if self.is_blocklisted(&text) {
+            debug!("Dropping blocklisted transcription '{}'", text);
+            return String::new();
+        }
+        text
+    }
+
+    /// Remove non-speech tags like [BLANK_AUDIO], (music) or *laughs*.
+    /// A bracket that never gets closed isn't a tag, so it and everything
+    /// after it are kept.
+    pub fn strip_tags(text: &str) -> String {
+        let mut retval = String::new();
+        let mut closer: Option<char> = None;
+        // The tag so far, in case it turns out not to be one
+        let mut pending = String::new();
+        for c in text.chars() {
+            match closer {
+                Some(x) if c == x => {
+                    closer = None;
+                    pending.clear();
+                },
+                Some(_) => pending.push(c),
+                None => {
+                    closer = match c {
+                        '[' => Some(']'),
+                        '(' => Some(')'),
+                        '*' => Some('*'),
+                        _ => None
+                    };
+                    match closer {
+                        Some(_) => pending.push(c),
+                        None => retval.push(c)
+                    }
+                }
+            }
+        }
+        retval.push_str(&pending);
+        retval.split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+
+    /// Find runs of the same phrase repeated more than `max_repeats` times
+    /// in a row and collapse each run down to a single copy
+    pub fn collapse_repeats(text: &str, max_repeats: usize) -> String {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let normal: Vec<String> = words.iter().map(|x| Self::normalize(x)).collect();
+        let mut retval: Vec<&str> = Vec::new();
+        let mut i = 0;
+        while i < words.len() {
+            let mut skipped = false;
+            for n in 1..=(words.len() - i) / 2 {
+                let mut count = 1;
+                while i + (count + 1) * n <= words.len()
+                    && normal[i..i + n] == normal[i + count * n..i + (count + 1) * n] {
+                    count += 1;
+                }
+                let limit = match n {
+                    1 => max_repeats.max(MIN_WORD_RUN - 1),
+                    _ => max_repeats
+                };
+                if count > limit {
+                    debug!("Collapsing {} repeats of '{}'", count, words[i..i + n].join(" "));
+                    retval.extend_from_slice(&words[i..i + n]);
+                    i += count * n;
+                    skipped = true;
+                    break;
+                }
+            }
+            if !skipped {
+                retval.push(words[i]);
+                i += 1;
+            }
+        }
+        retval.join(" ")
+    }
+
+    /// Check whether the text (ignoring case and punctuation) is on the blocklist
+    pub fn is_blocklisted(&self, text: &str) -> bool {
+        let text = Self::normalize(text);
+        if text.is_empty() { return true; }
+        self.config.blocklist.iter().any(|x| Self::normalize(x) == text)
+    }
+
+    /// Lowercase and throw away everything but letters, numbers and single spaces
+    fn normalize(text: &str) -> String {
+        text.chars()
+            .filter(|x| x.is_alphanumeric() || x.is_whitespace())
+            .collect::<String>()
+            .to_lowercase()
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(text: &str, no_speech_prob: f32) -> STTSegment {
+        STTSegment { text: text.to_string(), t0: 0, t1: 0, no_speech_prob }
+    }
+
+    #[test]
+    fn strips_tags() {
+        assert_eq!(STTFilter::strip_tags("[BLANK_AUDIO] hello (music) there *laughs*"), "hello there");
+    }
+
+    #[test]
+    fn keeps_text_after_unmatched_bracket() {
+        assert_eq!(STTFilter::strip_tags("it costs 5 (or so"), "it costs 5 (or so");
+        assert_eq!(STTFilter::strip_tags("[music] 2 * 3 is six"), "2 * 3 is six");
+    }
+
+    #[test]
+    fn keeps_short_word_runs() {
+        assert_eq!(STTFilter::collapse_repeats("no no no", 2), "no no no");
+        assert_eq!(STTFilter::collapse_repeats("no no no no no", 2), "no");
+    }
+
+    #[test]
+    fn collapses_repeated_phrases() {
+        let text = "I'm going I'm going I'm going I'm going home";
+        assert_eq!(STTFilter::collapse_repeats(text, 2), "I'm going home");
+        assert_eq!(STTFilter::collapse_repeats("see you, see you", 2), "see you, see you");
+    }
+
+    #[test]
+    fn keeps_one_word_answers() {
+        let filter = STTFilter::new(&FilterConfig::default());
+        assert_eq!(filter.filter_text("You."), "You.");
+        assert_eq!(filter.filter_text("Thank you."), "");
+    }
+
+    #[test]
+    fn drops_unlikely_segments() {
+        let filter = STTFilter::new(&FilterConfig::default());
+        let segments = [segment("hello", 0.1), segment("Thanks for watching!", 0.9)];
+        assert_eq!(filter.filter(&segments), "hello");
+    }
+}
@@ -0,0 +1,444 @@
+//! This module keeps a history of everything transcribed so it can be
+//! looked back over, resent or exported.  The history can optionally be
+//! saved to disk so it's still there after a restart.
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Local};
+use serde_derive::{Deserialize, Serialize};
+use log::{debug, error};
+use crate::MelonError;
+
+/// Settings for the transcription history, read from the [history] table
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct HistoryConfig {
+    /// Keep a history at all
+    pub enabled: bool,
+    /// Once there are more entries than this the oldest get dropped
+    pub max_entries: usize,
+    /// Save the history to disk so it survives a restart
+    pub persist: bool,
+    /// Where to save it (defaults to melonstt/history.json in the platform data dir)
+    pub file: Option<String>,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            enabled: true,
+            max_entries: 200,
+            persist: false,
+            file: None,
+        }
+    }
+}
+
+/// Formats the history can be exported in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    /// Every field of every entry as a JSON array
+    Json,
+    /// Every field of every entry, one row per entry
+    Csv,
+    /// Just the timestamp and text, one line per entry
+    Text,
+}
+
+impl ExportFormat {
+    /// The usual file extension for this format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Csv => "csv",
+            ExportFormat::Text => "txt",
+        }
+    }
+}
+
+/// A single transcription and what happened to it
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct HistoryEntry {
+    /// Unique id for this entry, used to resend or delete it
+    pub id: u64,
+    /// When the transcription finished
+    pub timestamp: DateTime<Local>,
+    /// Length of the recorded audio in seconds
+    pub duration: f32,
+    /// The model that did the transcribing
+    pub model: String,
+    /// The language it was transcribed as
+    pub language: String,
+    /// Exactly what Whisper came up with
+    pub raw_text: String,
+    /// The text after the filter and pipeline were done with it
+    pub text: String,
+    /// Whether it's been sent to the chatbox
+    pub sent: bool,
+    /// The saved 16kHz recording, if recordings are being saved
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio: Option<PathBuf>,
+    /// The saved recording straight from the device, if that's being saved too
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_audio: Option<PathBuf>,
+}
+
+/// The history itself
+pub struct STTHistory {
+    /// The settings this history was built with
+    pub config: HistoryConfig,
+    /// Every entry, oldest first
+    pub entries: Vec<HistoryEntry>,
+    /// The id the next entry will get
+    next_id: u64,
+    /// Entries dropped to stay within max_entries, by clearing the
+    /// history or by turning it off, kept until take_trimmed so whatever
+    /// they point at can be cleaned up
+    trimmed: Vec<HistoryEntry>,
+}
+
+impl STTHistory {
+    /// Build a new history.  If it's set to persist, whatever was saved
+    /// last time is loaded back in; a missing or unreadable file just
+    /// means starting from scratch.
+    pub fn new(config: &HistoryConfig) -> STTHistory {
+        let mut history = STTHistory { config: config.clone(), entries: Vec::new(), next_id: 1, trimmed: Vec::new() };
+        history.load();
+        history
+    }
+
+    /// Switch to new settings, loading or trimming the history to match
+    pub fn set_config(&mut self, config: &HistoryConfig) {
+        let reload = config.persist && (!self.config.persist || config.file != self.config.file);
+        self.config = config.clone();
+        if reload && self.entries.is_empty() {
+            self.load();
+        }
+        self.changed();
+    }
+
+    /// Where the history gets saved, if it's being saved at all
+    pub fn path(&self) -> Option<PathBuf> {
+        if !self.config.persist { return None; }
+        match &self.config.file {
+            Some(x) => Some(PathBuf::from(x)),
+            None => dirs::data_dir().map(|x| x.join("melonstt").join("history.json"))
+        }
+    }
+
+    /// Add a new transcription to the history and hand back its id.  The
+    /// id and sent flag on the entry passed in are ignored.  Nothing is
+    /// kept (and None comes back) if the history is turned off.
+    pub fn add(&mut self, mut entry: HistoryEntry) -> Option<u64> {
+        if !self.config.enabled { return None; }
+        entry.id = self.next_id;
+        entry.sent = false;
+        self.next_id += 1;
+        debug!("Adding history entry {}: '{}'", entry.id, entry.text);
+        self.entries.push(entry);
+        self.changed();
+        self.entries.last().map(|x| x.id)
+    }
+
+    /// Look up an entry by id
+    pub fn get(&self, id: u64) -> Option<&HistoryEntry> {
+        self.entries.iter().find(|x| x.id == id)
+    }
+
+    /// The saved recording to run through Whisper again for an entry.
+    /// The 16kHz file is what Whisper heard first time round; the raw one
+    /// gets converted the same way it was then.
+    pub fn recording(&self, id: u64) -> Result<PathBuf, MelonError> {
+        let entry = match self.get(id) {
+            Some(x) => x,
+            None => return Err(MelonError::UnknownEntry(id))
+        };
+        match (&entry.audio, &entry.raw_audio) {
+            (Some(x), _) | (None, Some(x)) => Ok(x.to_path_buf()),
+            (None, None) => Err(MelonError::NoRecording(id))
+        }
+    }
+
+    /// The most recent entry, if there is one
+    pub fn latest(&self) -> Option<&HistoryEntry> {
+        self.entries.last()
+    }
+
+    /// Note that an entry has been sent to the chatbox
+    pub fn mark_sent(&mut self, id: u64) {
+        if let Some(x) = self.entries.iter_mut().find(|x| x.id == id) {
+            x.sent = true;
+            self.changed();
+        }
+    }
+
+    /// Note where an entry's recording was saved
+    pub fn set_audio(&mut self, id: u64, audio: Option<PathBuf>, raw_audio: Option<PathBuf>) {
+        if let Some(x) = self.entries.iter_mut().find(|x| x.id == id) {
+            x.audio = audio;
+            x.raw_audio = raw_audio;
+            self.changed();
+        }
+    }
+
+    /// Forget about saved recordings that have been deleted, so nothing
+    /// tries to open them later
+    pub fn forget_audio(&mut self, paths: &[PathBuf]) {
+        let mut forgot = false;
+        for entry in self.entries.iter_mut() {
+            for audio in [&mut entry.audio, &mut entry.raw_audio] {
+                if audio.as_ref().is_some_and(|x| paths.contains(x)) {
+                    *audio = None;
+                    forgot = true;
+                }
+            }
+        }
+        if forgot {
+            self.changed();
+        }
+    }
+
+    /// Hand over the entries dropped (rather than removed by id) since
+    /// this was last called, to delete their recordings
+    pub fn take_trimmed(&mut self) -> Vec<HistoryEntry> {
+        std::mem::take(&mut self.trimmed)
+    }
+
+    /// Delete an entry.  Hands back false if there was no such entry.
+    pub fn remove(&mut self, id: u64) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|x| x.id != id);
+        if self.entries.len() == before { return false; }
+        self.changed();
+        true
+    }
+
+    /// Delete every entry
+    pub fn clear(&mut self) {
+        self.trimmed.append(&mut self.entries);
+        self.changed();
+    }
+
+    /// Write out the whole history in the given format
+    pub fn export(&self, format: ExportFormat) -> Result<String, MelonError> {
+        match format {
+            ExportFormat::Json => Ok(serde_json::to_string_pretty(&self.entries)?),
+            ExportFormat::Csv => {
+                let mut retval = "id,timestamp,duration,model,language,raw_text,text,sent\n".to_string();
+                for x in self.entries.iter() {
+                    let row = [
+                        x.id.to_string(),
+                        x.timestamp.to_rfc3339(),
+                        format!("{:.2}", x.duration),
+                        x.model.to_string(),
+                        x.language.to_string(),
+                        x.raw_text.to_string(),
+                        x.text.to_string(),
+                        x.sent.to_string(),
+                    ];
+                    retval.push_str(&row.iter().map(|x| Self::csv_field(x)).collect::<Vec<_>>().join(","));
+                    retval.push('\n');
+                }
+                Ok(retval)
+            },
+            ExportFormat::Text => {
+                Ok(self.entries.iter()
+                    .map(|x| format!("[{}] {}\n", x.timestamp.format("%Y-%m-%d %H:%M:%S"), x.text))
+                    .collect())
+            }
+        }
+    }
+
+    /// Export the whole history to a file
+    pub fn export_to(&self, path: &Path, format: ExportFormat) -> Result<(), MelonError> {
+        let data = self.export(format)?;
+        match std::fs::write(path, data) {
+            Ok(_) => {
+                debug!("Exported history to {}", path.display());
+                Ok(())
+            },
+            Err(e) => {
+                error!("Unable to export history to {}", path.display());
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Save the history to disk, if it's set to persist
+    pub fn save(&self) -> Result<(), MelonError> {
+        let path = match self.path() {
+            Some(x) => x,
+            None => return Ok(())
+        };
+        if let Some(dir) = path.parent().filter(|x| !x.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&path, serde_json::to_string(&self.entries)?)?;
+        Ok(())
+    }
+
+    /// Load whatever was saved last time, if the history persists
+    fn load(&mut self) {
+        let path = match self.path() {
+            Some(x) if x.is_file() => x,
+            _ => return
+        };
+        let loaded = std::fs::read_to_string(&path)
+            .map_err(MelonError::from)
+            .and_then(|x| Ok(serde_json::from_str::<Vec<HistoryEntry>>(&x)?));
+        match loaded {
+            Ok(x) => {
+                debug!("Loaded {} history entries from {}", x.len(), path.display());
+                self.next_id = x.iter().map(|x| x.id + 1).max().unwrap_or(1);
+                self.entries = x;
+            },
+            Err(e) => { error!("Unable to load history from {}: {}", path.display(), e); }
+        }
+    }
+
+    /// Trim the history down to size and save it after any change
+    fn changed(&mut self) {
+        if !self.config.enabled {
+            self.trimmed.append(&mut self.entries);
+        }
+        if self.entries.len() > self.config.max_entries {
+            let extra = self.entries.len() - self.config.max_entries;
+            self.trimmed.extend(self.entries.drain(..extra));
+        }
+        if let Err(e) = self.save() {
+            error!("Unable to save history: {}", e);
+        }
+    }
+
+    /// Quote a CSV field if it needs it
+    fn csv_field(field: &str) -> String {
+        match field.contains([',', '"', '\n', '\r']) {
+            true => format!("\"{}\"", field.replace('"', "\"\"")),
+            false => field.to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use crate::testing::scratch;
+
+    fn entry(text: &str) -> HistoryEntry {
+        HistoryEntry {
+            id: 0,
+            timestamp: Local::now(),
+            duration: 1.0,
+            model: "ggml-tiny.en.bin".to_string(),
+            language: "en".to_string(),
+            raw_text: text.to_string(),
+            text: text.to_string(),
+            sent: false,
+            audio: Some(PathBuf::from(format!("{}.wav", text))),
+            raw_audio: None,
+        }
+    }
+
+    #[test]
+    fn trimmed_entries_are_handed_over_once() {
+        let mut history = STTHistory::new(&HistoryConfig { max_entries: 2, ..Default::default() });
+        for text in ["one", "two", "three", "four"] {
+            history.add(entry(text));
+        }
+        let texts: Vec<&str> = history.entries.iter().map(|x| x.text.as_str()).collect();
+        assert_eq!(texts, ["three", "four"]);
+        let trimmed: Vec<String> = history.take_trimmed().into_iter().map(|x| x.text).collect();
+        assert_eq!(trimmed, ["one", "two"]);
+        assert!(history.take_trimmed().is_empty());
+        history.set_config(&HistoryConfig { max_entries: 1, ..Default::default() });
+        assert_eq!(history.take_trimmed()[0].text, "three");
+    }
+
+    #[test]
+    fn forgets_deleted_audio() {
+        let mut history = STTHistory::new(&HistoryConfig::default());
+        let one = history.add(entry("one")).unwrap();
+        let two = history.add(entry("two")).unwrap();
+        history.set_audio(two, Some(PathBuf::from("two.wav")), Some(PathBuf::from("two-raw.wav")));
+        history.forget_audio(&[PathBuf::from("one.wav"), PathBuf::from("two-raw.wav")]);
+        assert_eq!(history.get(one).unwrap().audio, None);
+        assert_eq!(history.get(two).unwrap().audio, Some(PathBuf::from("two.wav")));
+        assert_eq!(history.get(two).unwrap().raw_audio, None);
+    }
+
+    #[test]
+    fn recording_prefers_what_whisper_heard() {
+        let mut history = STTHistory::new(&HistoryConfig::default());
+        let both = history.add(entry("both")).unwrap();
+        history.set_audio(both, Some(PathBuf::from("both.wav")), Some(PathBuf::from("both-raw.wav")));
+        let raw = history.add(entry("raw")).unwrap();
+        history.set_audio(raw, None, Some(PathBuf::from("raw-raw.wav")));
+        let none = history.add(entry("none")).unwrap();
+        history.set_audio(none, None, None);
+
+        assert_eq!(history.recording(both).unwrap(), PathBuf::from("both.wav"));
+        assert_eq!(history.recording(raw).unwrap(), PathBuf::from("raw-raw.wav"));
+        assert!(matches!(history.recording(none), Err(MelonError::NoRecording(x)) if x == none));
+        assert!(matches!(history.recording(99), Err(MelonError::UnknownEntry(99))));
+    }
+
+    #[test]
+    fn clearing_or_turning_off_hands_entries_over() {
+        let mut history = STTHistory::new(&HistoryConfig::default());
+        history.add(entry("one"));
+        history.clear();
+        assert!(history.entries.is_empty());
+        assert_eq!(history.take_trimmed()[0].text, "one");
+
+        history.add(entry("two"));
+        history.set_config(&HistoryConfig { enabled: false, ..Default::default() });
+        assert!(history.entries.is_empty());
+        assert_eq!(history.take_trimmed()[0].text, "two");
+        assert_eq!(history.add(entry("three")), None);
+        assert!(history.take_trimmed().is_empty());
+    }
+
+    #[test]
+    fn exports_every_format() {
+        let mut history = STTHistory::new(&HistoryConfig::default());
+        let mut first = entry("plain");
+        first.timestamp = Local.with_ymd_and_hms(2024, 5, 6, 7, 8, 9).unwrap();
+        first.raw_text = "hello, \"you\"".to_string();
+        first.text = "two\nlines".to_string();
+        let mut second = entry("simple");
+        second.timestamp = Local.with_ymd_and_hms(2024, 5, 6, 7, 9, 0).unwrap();
+        let id = history.add(first).unwrap();
+        history.add(second);
+        history.mark_sent(id);
+
+        let text = history.export(ExportFormat::Text).unwrap();
+        assert_eq!(text, "[2024-05-06 07:08:09] two\nlines\n[2024-05-06 07:09:00] simple\n");
+
+        let csv = history.export(ExportFormat::Csv).unwrap();
+        let stamp = history.entries[0].timestamp.to_rfc3339();
+        assert!(csv.starts_with("id,timestamp,duration,model,language,raw_text,text,sent\n"));
+        assert!(csv.contains(&format!("1,{},1.00,ggml-tiny.en.bin,en,\"hello, \"\"you\"\"\",\"two\nlines\",true\n", stamp)), "{}", csv);
+        assert!(csv.ends_with(",simple,simple,false\n"), "{}", csv);
+
+        let json = history.export(ExportFormat::Json).unwrap();
+        assert_eq!(serde_json::from_str::<Vec<HistoryEntry>>(&json).unwrap(), history.entries);
+    }
+
+    #[test]
+    fn persisted_history_loads_back() {
+        let dir = scratch("history");
+        let file = dir.join("history.json");
+        let config = HistoryConfig { persist: true, file: Some(file.to_string_lossy().to_string()), ..Default::default() };
+        let mut history = STTHistory::new(&config);
+        for text in ["one", "two", "three"] {
+            history.add(entry(text));
+        }
+        history.remove(1);
+        assert!(file.is_file());
+
+        let mut reloaded = STTHistory::new(&config);
+        assert_eq!(reloaded.entries, history.entries);
+        // Ids carry on from the highest one saved, not the number of entries
+        assert_eq!(reloaded.add(entry("four")), Some(4));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,561 @@
+//! This module handles global hotkeys, so recording can be started and
+//! stopped without having to find the MelonSTT window (handy in VR).
+//! There are a few ways a hotkey can arrive:
+//! 1. The native global hotkey API (X11, Windows, macOS) via global-hotkey
+//! 2. Reading keyboards directly through evdev on Linux, which also works
+//!    under Wayland as long as you're in the 'input' group
+//! 3. A plain text command sent to a local UDP port, for anything else.
+//!    'melonstt trigger <action>' sends one, or bind your own tool to it.
+//!
+//! Whichever way they come in, everything ends up as a HotkeyEvent on a
+//! single channel.
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{SocketAddrV4, UdpSocket};
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use global_hotkey::{GlobalHotKeyEvent, GlobalHotKeyManager, HotKeyState};
+use global_hotkey::hotkey::HotKey;
+use serde_derive::{Deserialize, Serialize};
+use log::{debug, error, warn};
+use crate::MelonError;
+
+/// Something a hotkey can do
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HotkeyAction {
+    /// Start recording, or stop and transcribe if already recording
+    ToggleRecord,
+    /// Record for as long as the key is held down
+    PushToTalk,
+    /// Send the latest transcription to the chatbox
+    SendLast,
+    /// Throw away the recording in progress
+    Cancel,
+}
+
+impl HotkeyAction {
+    /// Every action, in the order they appear in the config
+    pub const ALL: [HotkeyAction; 4] = [
+        HotkeyAction::ToggleRecord,
+        HotkeyAction::PushToTalk,
+        HotkeyAction::SendLast,
+        HotkeyAction::Cancel,
+    ];
+
+    /// The name used for this action in the config and trigger commands
+    pub fn name(&self) -> &'static str {
+        match self {
+            HotkeyAction::ToggleRecord => "toggle-record",
+            HotkeyAction::PushToTalk => "push-to-talk",
+            HotkeyAction::SendLast => "send-last",
+            HotkeyAction::Cancel => "cancel",
+        }
+    }
+}
+
+impl fmt::Display for HotkeyAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for HotkeyAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        HotkeyAction::ALL.into_iter()
+            .find(|x| x.name() == s.trim().to_lowercase().replace('_', "-"))
+            .ok_or_else(|| format!("unknown hotkey action '{}'", s))
+    }
+}
+
+/// A hotkey being pressed or let go
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HotkeyEvent {
+    /// What the hotkey is for
+    pub action: HotkeyAction,
+    /// True when the key went down, false when it came back up.  Only
+    /// push-to-talk cares about the key coming back up.
+    pub pressed: bool,
+}
+
+impl HotkeyEvent {
+    /// Parse a trigger command: an action name, optionally followed by
+    /// "down" or "up" (push-to-talk needs both).  Plain action names count
+    /// as the key going down.
+    pub fn parse(command: &str) -> Result<HotkeyEvent, String> {
+        let mut words = command.split_whitespace();
+        let action = HotkeyAction::from_str(words.next().unwrap_or(""))?;
+        let pressed = match words.next() {
+            None | Some("down") | Some("press") => true,
+            Some("up") | Some("release") => false,
+            Some(x) => return Err(format!("expected 'down' or 'up' after '{}', not '{}'", action, x))
+        };
+        Ok(HotkeyEvent { action, pressed })
+    }
+}
+
+/// Where hotkeys get picked up from
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum HotkeyBackend {
+    /// evdev on Wayland, the native API everywhere else
+    #[default]
+    Auto,
+    /// The native global hotkey API (X11, Windows, macOS)
+    Native,
+    /// Read keyboards directly (Linux only)
+    Evdev,
+    /// No hotkeys, just the trigger port
+    None,
+}
+
+/// Settings for global hotkeys, read from the [hotkeys] table.  Keys are
+/// written like "Ctrl+Alt+R"; an empty string leaves that action unbound.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct HotkeyConfig {
+    /// Turn hotkeys on at all
+    pub enabled: bool,
+    /// Where to pick hotkeys up from
+    pub backend: HotkeyBackend,
+    /// Key to start/stop recording
+    pub toggle_record: String,
+    /// Key to hold down while talking
+    pub push_to_talk: String,
+    /// Key to send the latest transcription
+    pub send_last: String,
+    /// Key to throw away the recording in progress
+    pub cancel: String,
+    /// Local UDP port to listen on for trigger commands, if any
+    pub trigger_port: Option<u16>,
+    /// Only listen to this evdev device (a /dev/input path or device name)
+    pub evdev_device: Option<String>,
+}
+
+impl Default for HotkeyConfig {
+    fn default() -> Self {
+        HotkeyConfig {
+            enabled: false,
+            backend: HotkeyBackend::Auto,
+            toggle_record: "Ctrl+Alt+R".to_string(),
+            push_to_talk: "Ctrl+Alt+T".to_string(),
+            send_last: "Ctrl+Alt+S".to_string(),
+            cancel: "Ctrl+Alt+X".to_string(),
+            trigger_port: Some(49002),
+            evdev_device: None,
+        }
+    }
+}
+
+impl HotkeyConfig {
+    /// The key configured for an action, if it has one
+    pub fn key_for(&self, action: HotkeyAction) -> Option<&str> {
+        let key = match action {
+            HotkeyAction::ToggleRecord => &self.toggle_record,
+            HotkeyAction::PushToTalk => &self.push_to_talk,
+            HotkeyAction::SendLast => &self.send_last,
+            HotkeyAction::Cancel => &self.cancel,
+        };
+        Some(key.as_str()).filter(|x| !x.trim().is_empty())
+    }
+
+    /// Parse every configured key, handing back the action it's for and
+    /// either the hotkey or why it's no good
+    pub fn hotkeys(&self) -> Vec<(HotkeyAction, Result<HotKey, String>)> {
+        HotkeyAction::ALL.into_iter()
+            .filter_map(|action| {
+                let key = self.key_for(action)?;
+                Some((action, HotKey::from_str(key).map_err(|e| e.to_string())))
+            })
+            .collect()
+    }
+}
+
+/// Keeps whichever hotkey backends are running alive.  Drop it to stop
+/// the native hotkeys; the evdev and trigger threads just stop sending.
+pub struct STTHotkeys {
+    /// Everything that comes in gets forwarded here
+    sender: Sender<HotkeyEvent>,
+    /// The native hotkey manager, if that's what's in use
+    manager: Option<GlobalHotKeyManager>,
+    /// Which backend ended up being used
+    pub backend: HotkeyBackend,
+    /// The port trigger commands are listened for on, if any
+    pub trigger_port: Option<u16>,
+}
+
+impl STTHotkeys {
+    /// Start listening for hotkeys and trigger commands.  Needs to be
+    /// called from the thread running the UI event loop, since that's
+    /// where the native API delivers key presses on Windows and macOS.
+    /// Every hotkey event turns up on the receiver handed back.
+    pub fn new(config: &HotkeyConfig) -> Result<(STTHotkeys, Receiver<HotkeyEvent>), MelonError> {
+        let (sender, receiver) = channel();
+        let mut hotkeys = STTHotkeys { sender, manager: None, backend: HotkeyBackend::None, trigger_port: None };
+        if !config.enabled {
+            debug!("Hotkeys are turned off");
+            return Ok((hotkeys, receiver));
+        }
+        if let Some(port) = config.trigger_port {
+            match hotkeys.listen_trigger(port) {
+                Ok(_) => { hotkeys.trigger_port = Some(port); },
+                Err(e) => { error!("Unable to listen for trigger commands: {}", e); }
+            }
+        }
+        // Try the backend asked for, falling back to the other if it's auto
+        let order = match config.backend {
+            HotkeyBackend::Auto if Self::prefer_evdev() => vec![HotkeyBackend::Evdev, HotkeyBackend::Native],
+            HotkeyBackend::Auto => vec![HotkeyBackend::Native, HotkeyBackend::Evdev],
+            x => vec![x],
+        };
+        let mut last_error = None;
+        for backend in order {
+            let result = match backend {
+                HotkeyBackend::Native => hotkeys.start_native(config),
+                HotkeyBackend::Evdev => hotkeys.start_evdev(config),
+                _ => Ok(()),
+            };
+            match result {
+                Ok(_) => {
+                    debug!("Using {:?} hotkey backend", backend);
+                    hotkeys.backend = backend;
+                    return Ok((hotkeys, receiver));
+                },
+                Err(e) => {
+                    warn!("{:?} hotkey backend unavailable: {}", backend, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        match (last_error, hotkeys.trigger_port) {
+            // Still usable through the trigger port, so just say so in the log
+            (Some(e), Some(_)) => {
+                warn!("No global hotkeys ({}), only trigger commands will work", e);
+                Ok((hotkeys, receiver))
+            },
+            (Some(e), None) => Err(e),
+            (None, _) => Ok((hotkeys, receiver)),
+        }
+    }
+
+    /// Whether reading keyboards directly is likely to work better than
+    /// the native API.  That's the case under Wayland, where X11 grabs
+    /// only see keys pressed in X11 windows.
+    fn prefer_evdev() -> bool {
+        cfg!(target_os = "linux")
+            && (std::env::var_os("WAYLAND_DISPLAY").is_some() || std::env::var_os("DISPLAY").is_none())
+    }
+
+    /// Register every hotkey with the native API, and forward its events
+    fn start_native(&mut self, config: &HotkeyConfig) -> Result<(), MelonError> {
+        // The X11 side can't report a missing display, so check first
+        if cfg!(target_os = "linux") && std::env::var_os("DISPLAY").is_none() {
+            return Err(MelonError::Hotkey("no X11 display to grab keys on".to_string()));
+        }
+        let manager = match GlobalHotKeyManager::new() {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Error creating global hotkey manager");
+                return Err(MelonError::Hotkey(e.to_string()));
+            }
+        };
+        let mut actions = HashMap::new();
+        for (action, hotkey) in config.hotkeys() {
+            let hotkey = hotkey.map_err(|e| MelonError::InvalidHotkey { action: action.name(), reason: e })?;
+            match manager.register(hotkey) {
+                Ok(_) => { debug!("Registered {} for {}", hotkey, action); },
+                Err(e) => {
+                    error!("Error registering hotkey {} for {}", hotkey, action);
+                    return Err(MelonError::Hotkey(format!("{} ({}): {}", hotkey, action, e)));
+                }
+            }
+            actions.insert(hotkey.id(), action);
+        }
+        let sender = self.sender.clone();
+        std::thread::spawn(move || {
+            while let Ok(event) = GlobalHotKeyEvent::receiver().recv() {
+                let action = match actions.get(&event.id()) {
+                    Some(x) => *x,
+                    None => continue
+                };
+                let pressed = event.state() == HotKeyState::Pressed;
+                if sender.send(HotkeyEvent { action, pressed }).is_err() { break; }
+            }
+        });
+        self.manager = Some(manager);
+        Ok(())
+    }
+
+    /// Read keyboards directly, one thread per keyboard
+    #[cfg(target_os = "linux")]
+    fn start_evdev(&mut self, config: &HotkeyConfig) -> Result<(), MelonError> {
+        let mut combos = Vec::new();
+        for (action, hotkey) in config.hotkeys() {
+            let hotkey = hotkey.map_err(|e| MelonError::InvalidHotkey { action: action.name(), reason: e })?;
+            match evdev_keys::combo(&hotkey) {
+                Some(x) => combos.push((action, x)),
+                None => return Err(MelonError::InvalidHotkey {
+                    action: action.name(),
+                    reason: format!("{} can't be read through evdev", hotkey)
+                })
+            }
+        }
+        let keyboards = evdev_keys::keyboards(config.evdev_device.as_deref());
+        if keyboards.is_empty() {
+            return Err(MelonError::Hotkey(
+                "no readable keyboards in /dev/input (are you in the 'input' group?)".to_string()));
+        }
+        for (path, device) in keyboards {
+            debug!("Watching {} ({}) for hotkeys", path.display(), device.name().unwrap_or("unnamed"));
+            let sender = self.sender.clone();
+            let combos = combos.clone();
+            std::thread::spawn(move || evdev_keys::watch(device, combos, sender));
+        }
+        Ok(())
+    }
+
+    /// evdev is only a thing on Linux
+    #[cfg(not(target_os = "linux"))]
+    fn start_evdev(&mut self, _config: &HotkeyConfig) -> Result<(), MelonError> {
+        Err(MelonError::Hotkey("evdev is only available on Linux".to_string()))
+    }
+
+    /// Listen for trigger commands on a local UDP port.  Only loopback is
+    /// listened on, so nothing outside this machine can set off a recording.
+    fn listen_trigger(&self, port: u16) -> Result<(), MelonError> {
+        let socket = match UdpSocket::bind(SocketAddrV4::new([127, 0, 0, 1].into(), port)) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Error binding trigger port {}", port);
+                return Err(MelonError::SocketBind(e));
+            }
+        };
+        debug!("Listening for trigger commands on 127.0.0.1:{}", port);
+        let sender = self.sender.clone();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 256];
+            loop {
+                let len = match socket.recv_from(&mut buf) {
+                    Ok((len, _)) => len,
+                    Err(e) => {
+                        error!("Error reading trigger command: {}", e);
+                        continue;
+                    }
+                };
+                let command = String::from_utf8_lossy(&buf[..len]);
+                match HotkeyEvent::parse(&command) {
+                    Ok(x) => {
+                        debug!("Trigger command: {:?}", x);
+                        if sender.send(x).is_err() { break; }
+                    },
+                    Err(e) => { warn!("Ignoring trigger command: {}", e); }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Whether the native hotkey API is in use
+    pub fn is_native(&self) -> bool {
+        self.manager.is_some()
+    }
+}
+
+/// Send a trigger command to a running MelonSTT on this machine
+pub fn send_trigger(port: u16, command: &str) -> Result<(), MelonError> {
+    // Check it here so typos get reported rather than silently ignored
+    HotkeyEvent::parse(command).map_err(MelonError::Hotkey)?;
+    let socket = match UdpSocket::bind("127.0.0.1:0") {
+        Ok(x) => x,
+        Err(e) => return Err(MelonError::SocketBind(e))
+    };
+    match socket.send_to(command.as_bytes(), SocketAddrV4::new([127, 0, 0, 1].into(), port)) {
+        Ok(_) => {
+            debug!("Sent trigger command '{}' to port {}", command, port);
+            Ok(())
+        },
+        Err(e) => {
+            error!("Error sending trigger command");
+            Err(MelonError::Io(e))
+        }
+    }
+}
+
+/// Reading keys straight from /dev/input
+#[cfg(target_os = "linux")]
+mod evdev_keys {
+    use std::path::PathBuf;
+    use std::str::FromStr;
+    use std::sync::mpsc::Sender;
+    use evdev::{Device, InputEventKind, Key};
+    use global_hotkey::hotkey::{Code, HotKey, Modifiers};
+    use log::{debug, error};
+    use super::{HotkeyAction, HotkeyEvent};
+
+    /// A hotkey in evdev terms: the modifiers that need holding and the key
+    #[derive(Clone, Copy)]
+    pub struct Combo {
+        mods: Modifiers,
+        key: Key,
+    }
+
+    /// Translate a parsed hotkey into evdev key codes
+    pub fn combo(hotkey: &HotKey) -> Option<Combo> {
+        Some(Combo { mods: hotkey.mods, key: key(hotkey.key)? })
+    }
+
+    /// Map a key from the hotkey parser onto its evdev key code.  Most
+    /// names line up once the prefix is changed; the rest are listed here.
+    fn key(code: Code) -> Option<Key> {
+        let name = code.to_string();
+        let name = name.strip_prefix("Key")
+            .or_else(|| name.strip_prefix("Digit"))
+            .unwrap_or(&name);
+        let name = match name {
+            "Escape" => "ESC",
+            "Backquote" => "GRAVE",
+            "BracketLeft" => "LEFTBRACE",
+            "BracketRight" => "RIGHTBRACE",
+            "Quote" => "APOSTROPHE",
+            "Period" => "DOT",
+            "ArrowUp" => "UP",
+            "ArrowDown" => "DOWN",
+            "ArrowLeft" => "LEFT",
+            "ArrowRight" => "RIGHT",
+            "PrintScreen" => "SYSRQ",
+            "NumpadAdd" => "KPPLUS",
+            "NumpadSubtract" => "KPMINUS",
+            "NumpadMultiply" => "KPASTERISK",
+            "NumpadDivide" => "KPSLASH",
+            "NumpadDecimal" => "KPDOT",
+            x if x.starts_with("Numpad") => return Key::from_str(&format!("KEY_KP{}", &x[6..].to_uppercase())).ok(),
+            x => x,
+        };
+        Key::from_str(&format!("KEY_{}", name.to_uppercase())).ok()
+    }
+
+    /// Which modifier, if any, a key is
+    fn modifier(key: Key) -> Option<Modifiers> {
+        match key {
+            Key::KEY_LEFTCTRL | Key::KEY_RIGHTCTRL => Some(Modifiers::CONTROL),
+            Key::KEY_LEFTALT | Key::KEY_RIGHTALT => Some(Modifiers::ALT),
+            Key::KEY_LEFTSHIFT | Key::KEY_RIGHTSHIFT => Some(Modifiers::SHIFT),
+            Key::KEY_LEFTMETA | Key::KEY_RIGHTMETA => Some(Modifiers::SUPER),
+            _ => None
+        }
+    }
+
+    /// Open every keyboard we're allowed to read.  If a device is given
+    /// (by path or name) only that one is opened.
+    pub fn keyboards(only: Option<&str>) -> Vec<(PathBuf, Device)> {
+        evdev::enumerate()
+            .filter(|(path, device)| match only {
+                Some(x) => path.to_str() == Some(x) || device.name() == Some(x),
+                // Anything with letter keys counts as a keyboard
+                None => device.supported_keys().is_some_and(|x| x.contains(Key::KEY_A) && x.contains(Key::KEY_ENTER)),
+            })
+            .collect()
+    }
+
+    /// Watch a keyboard for hotkeys until it goes away or nobody's listening
+    pub fn watch(mut device: Device, combos: Vec<(HotkeyAction, Combo)>, sender: Sender<HotkeyEvent>) {
+        let mut held = Modifiers::empty();
+        // Hotkeys that went down and haven't come back up yet
+        let mut active: Vec<(HotkeyAction, Key)> = Vec::new();
+        loop {
+            let events = match device.fetch_events() {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("Stopped reading keyboard for hotkeys: {}", e);
+                    return;
+                }
+            };
+            for event in events {
+                let key = match event.kind() {
+                    InputEventKind::Key(x) => x,
+                    _ => continue
+                };
+                // 1 is down, 0 is up and 2 is auto-repeat, which we ignore
+                let pressed = match event.value() {
+                    1 => true,
+                    0 => false,
+                    _ => continue
+                };
+                if let Some(x) = modifier(key) {
+                    held.set(x, pressed);
+                    continue;
+                }
+                let mut fired = Vec::new();
+                if pressed {
+                    for (action, _) in combos.iter().filter(|(_, x)| x.key == key && x.mods == held) {
+                        active.push((*action, key));
+                        fired.push(HotkeyEvent { action: *action, pressed: true });
+                    }
+                } else {
+                    active.retain(|(action, k)| {
+                        if *k != key { return true; }
+                        fired.push(HotkeyEvent { action: *action, pressed: false });
+                        false
+                    });
+                }
+                for x in fired {
+                    debug!("evdev hotkey: {:?}", x);
+                    if sender.send(x).is_err() { return; }
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn maps_keys() {
+            assert_eq!(key(Code::KeyR), Some(Key::KEY_R));
+            assert_eq!(key(Code::Digit5), Some(Key::KEY_5));
+            assert_eq!(key(Code::F12), Some(Key::KEY_F12));
+            assert_eq!(key(Code::Escape), Some(Key::KEY_ESC));
+            assert_eq!(key(Code::BracketLeft), Some(Key::KEY_LEFTBRACE));
+            assert_eq!(key(Code::Period), Some(Key::KEY_DOT));
+            assert_eq!(key(Code::ArrowUp), Some(Key::KEY_UP));
+            assert_eq!(key(Code::Numpad7), Some(Key::KEY_KP7));
+            assert_eq!(key(Code::NumpadAdd), Some(Key::KEY_KPPLUS));
+            assert_eq!(key(Code::NumpadDecimal), Some(Key::KEY_KPDOT));
+            assert_eq!(key(Code::NumpadEnter), Some(Key::KEY_KPENTER));
+            assert_eq!(key(Code::Space), Some(Key::KEY_SPACE));
+        }
+
+        #[test]
+        fn finds_modifiers() {
+            assert_eq!(modifier(Key::KEY_RIGHTCTRL), Some(Modifiers::CONTROL));
+            assert_eq!(modifier(Key::KEY_LEFTALT), Some(Modifiers::ALT));
+            assert_eq!(modifier(Key::KEY_R), None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_actions() {
+        assert_eq!(HotkeyAction::from_str("toggle-record"), Ok(HotkeyAction::ToggleRecord));
+        assert_eq!(HotkeyAction::from_str(" Push_To_Talk "), Ok(HotkeyAction::PushToTalk));
+        assert!(HotkeyAction::from_str("explode").is_err());
+        for action in HotkeyAction::ALL {
+            assert_eq!(HotkeyAction::from_str(action.name()), Ok(action));
+        }
+    }
+
+    #[test]
+    fn parses_trigger_commands() {
+        assert_eq!(HotkeyEvent::parse("send-last"), Ok(HotkeyEvent { action: HotkeyAction::SendLast, pressed: true }));
+        assert_eq!(HotkeyEvent::parse("push-to-talk down"), Ok(HotkeyEvent { action: HotkeyAction::PushToTalk, pressed: true }));
+        assert_eq!(HotkeyEvent::parse("push-to-talk release\n"), Ok(HotkeyEvent { action: HotkeyAction::PushToTalk, pressed: false }));
+        assert!(HotkeyEvent::parse("push-to-talk sideways").is_err());
+        assert!(HotkeyEvent::parse("").is_err());
+    }
+}
@@ -0,0 +1,304 @@
+//! This module lets shell scripts drive MelonSTT over a Unix domain
+//! socket (a named pipe on Windows), one command per line:
+//!
+//! ```text
+//! status
+//! record 5            (or just 'record' to stop when you go quiet)
+//! start / stop / cancel
+//! send "some text"    (or just 'send' for the latest transcription)
+//! latest
+//! profile stream      (or just 'profile' for no profile)
+//! ```
+//!
+//! Commands can also be sent as JSON, like {"id": 1, "command": "record",
+//! "seconds": 5}; the id comes back on the response.  Every response is
+//! one line of JSON with "ok" set, and events (recording started,
+//! transcription ready and so on) are sent as they happen as lines with
+//! "event" set.  For example: `socat - UNIX-CONNECT:$XDG_RUNTIME_DIR/melonstt.sock`
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc::Sender;
+use log::{debug, error};
+use crate::{ControlCommand, MelonError, MelonSTT};
+
+/// Settings for the command socket, read from the [ipc] table
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct IpcConfig {
+    /// Listen for commands at all
+    pub enabled: bool,
+    /// Socket (or pipe) to listen on.  Defaults to melonstt.sock in the
+    /// runtime dir on Unix and \\.\pipe\melonstt on Windows.
+    pub path: Option<String>,
+}
+
+impl IpcConfig {
+    /// Where the socket or pipe ends up
+    pub fn socket_path(&self) -> PathBuf {
+        if let Some(x) = &self.path {
+            return PathBuf::from(x);
+        }
+        match cfg!(windows) {
+            true => PathBuf::from(r"\\.\pipe\melonstt"),
+            false => dirs::runtime_dir().unwrap_or_else(std::env::temp_dir).join("melonstt.sock")
+        }
+    }
+}
+
+/// A command plus the id to answer it with
+#[derive(Deserialize)]
+struct Request {
+    id: Option<Value>,
+    #[serde(flatten)]
+    command: ControlCommand,
+}
+
+impl Request {
+    /// Parse a line, either as JSON or the plain 'record 5' form
+    fn parse(line: &str) -> Result<Request, String> {
+        let line = line.trim();
+        if line.starts_with('{') {
+            return serde_json::from_str(line).map_err(|e| format!("bad JSON command: {}", e));
+        }
+        let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        // Quoted arguments are read as JSON strings so escapes work
+        let arg = match rest.starts_with('"') {
+            true => Some(serde_json::from_str::<String>(rest).map_err(|e| format!("bad quoted argument: {}", e))?),
+            false => Some(rest.to_string()).filter(|x| !x.is_empty())
+        };
+        let command = match name {
+            "status" => ControlCommand::Status,
+            "record" => {
+                let seconds = match arg {
+                    Some(x) => Some(x.parse::<u64>().map_err(|_| format!("'{}' isn't a number of seconds", x))?),
+                    None => None
+                };
+                ControlCommand::Record { seconds }
+            },
+            "start" => ControlCommand::Start,
+            "stop" => ControlCommand::Stop,
+            "cancel" => ControlCommand::Cancel,
+            "send" => ControlCommand::Send { text: arg },
+            "latest" => ControlCommand::Latest,
+            "profile" => ControlCommand::Profile { name: arg },
+            x => return Err(format!("unknown command '{}'", x))
+        };
+        Ok(Request { id: None, command })
+    }
+}
+
+/// Start listening for commands on their own thread.  On Unix the socket
+/// is created straight away, so problems with the path show up here.
+pub fn spawn(melon: &Arc<Mutex<MelonSTT>>, config: &IpcConfig) -> Result<JoinHandle<()>, MelonError> {
+    let path = config.socket_path();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    #[cfg(unix)]
+    let listener = {
+        clear_stale_socket(&path)?;
+        let listener = match std::os::unix::net::UnixListener::bind(&path) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Error creating command socket {}", path.display());
+                return Err(MelonError::SocketBind(e));
+            }
+        };
+        // Only this user gets to start recordings
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        listener.set_nonblocking(true)?;
+        listener
+    };
+    debug!("Listening for commands on {}", path.display());
+    let melon = melon.clone();
+    Ok(std::thread::spawn(move || {
+        runtime.block_on(async move {
+            #[cfg(unix)]
+            let result = listen(listener, melon).await;
+            #[cfg(windows)]
+            let result = listen(path, melon).await;
+            if let Err(e) = result {
+                error!("Command socket stopped: {}", e);
+            }
+        });
+    }))
+}
+
+/// A socket left over from last time would stop us binding, but only
+/// clear it out if it really is a socket nobody's listening on.  Anything
+/// else at the path is left alone and reported as in use.
+#[cfg(unix)]
+fn clear_stale_socket(path: &std::path::Path) -> Result<(), MelonError> {
+    use std::os::unix::fs::FileTypeExt;
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(x) => x,
+        Err(_) => return Ok(())
+    };
+    let stale = metadata.file_type().is_socket() && matches!(
+        std::os::unix::net::UnixStream::connect(path),
+        Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused
+    );
+    match stale {
+        true => {
+            debug!("Removing stale command socket {}", path.display());
+            std::fs::remove_file(path)?;
+            Ok(())
+        },
+        false => {
+            error!("{} is already in use", path.display());
+            Err(MelonError::SocketBind(std::io::ErrorKind::AddrInUse.into()))
+        }
+    }
+}
+
+/// Accept connections on the Unix socket
+#[cfg(unix)]
+async fn listen(listener: std::os::unix::net::UnixListener, melon: Arc<Mutex<MelonSTT>>) -> std::io::Result<()> {
+    let listener = tokio::net::UnixListener::from_std(listener)?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(connection(stream, melon.clone()));
+    }
+}
+
+/// Accept connections on the named pipe.  Each client gets its own pipe
+/// instance, so a new one is made ready every time one connects.
+#[cfg(windows)]
+async fn listen(path: PathBuf, melon: Arc<Mutex<MelonSTT>>) -> std::io::Result<()> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+    let mut server = ServerOptions::new().first_pipe_instance(true).create(&path)?;
+    loop {
+        server.connect().await?;
+        let client = std::mem::replace(&mut server, ServerOptions::new().create(&path)?);
+        tokio::spawn(connection(client, melon.clone()));
+    }
+}
+
+/// Talk to one client until it hangs up
+async fn connection<S>(stream: S, melon: Arc<Mutex<MelonSTT>>)
+where S: AsyncRead + AsyncWrite + Send + 'static {
+    debug!("Command client connected");
+    let (reader, mut writer) = tokio::io::split(stream);
+    // Responses and events both go out through here, a line at a time.
+    // It's bounded so a client that stops reading backs up into the event
+    // bus and gets dropped there.
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<String>(crate::events::EVENT_BACKLOG);
+    tokio::spawn(async move {
+        while let Some(mut line) = rx.recv().await {
+            line.push('\n');
+            if writer.write_all(line.as_bytes()).await.is_err() { break; }
+        }
+    });
+    // Subscribing takes the lock, and the event bus blocks, so both
+    // happen off the async thread
+    let subscriber = melon.clone();
+    let events = match tokio::task::spawn_blocking(move || subscriber.lock().map(|x| x.subscribe()).ok()).await {
+        Ok(Some(x)) => x,
+        _ => {
+            error!("Error subscribing command client to events");
+            return;
+        }
+    };
+    let event_tx = tx.clone();
+    tokio::task::spawn_blocking(move || {
+        for event in events {
+            let line = match serde_json::to_string(&event) {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("Error serializing event: {}", e);
+                    continue;
+                }
+            };
+            if event_tx.blocking_send(line).is_err() { break; }
+        }
+    });
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() { continue; }
+        match Request::parse(&line) {
+            // Commands run on their own so events keep flowing while a
+            // recording is going
+            Ok(x) => { tokio::spawn(run(x, melon.clone(), tx.clone())); },
+            Err(e) => { let _ = tx.send(json!({ "ok": false, "error": e }).to_string()).await; }
+        }
+    }
+    debug!("Command client disconnected");
+}
+
+/// Carry out a command and send back the response
+async fn run(request: Request, melon: Arc<Mutex<MelonSTT>>, tx: Sender<String>) {
+    let result = tokio::task::spawn_blocking(move || MelonSTT::control_shared(&melon, request.command)).await;
+    let response = match result {
+        Ok(Ok(x)) => json!({ "id": request.id, "ok": true, "result": x }),
+        Ok(Err(e)) => json!({ "id": request.id, "ok": false, "error": e.to_string() }),
+        Err(_) => json!({ "id": request.id, "ok": false, "error": "command panicked" }),
+    };
+    let _ = tx.send(response.to_string()).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch;
+
+    #[cfg(unix)]
+    #[test]
+    fn stale_socket_is_removed() {
+        let dir = scratch("stale-socket");
+        let path = dir.join("melonstt.sock");
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        clear_stale_socket(&path).unwrap();
+        assert!(!path.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn live_socket_is_left_alone() {
+        let dir = scratch("live-socket");
+        let path = dir.join("melonstt.sock");
+        let _listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        assert!(matches!(clear_stale_socket(&path), Err(MelonError::SocketBind(_))));
+        assert!(path.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn other_files_are_left_alone() {
+        let dir = scratch("not-a-socket");
+        let path = dir.join("melonstt.sock");
+        std::fs::write(&path, "important").unwrap();
+        assert!(matches!(clear_stale_socket(&path), Err(MelonError::SocketBind(_))));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "important");
+        std::fs::remove_file(&path).unwrap();
+        clear_stale_socket(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_plain_commands() {
+        assert_eq!(Request::parse("status").unwrap().command, ControlCommand::Status);
+        assert_eq!(Request::parse("record 5").unwrap().command, ControlCommand::Record { seconds: Some(5) });
+        assert_eq!(Request::parse("record").unwrap().command, ControlCommand::Record { seconds: None });
+        assert_eq!(Request::parse("send \"say \\\"hi\\\"\"").unwrap().command, ControlCommand::Send { text: Some("say \"hi\"".to_string()) });
+        assert_eq!(Request::parse("profile stream").unwrap().command, ControlCommand::Profile { name: Some("stream".to_string()) });
+        assert!(Request::parse("record soon").is_err());
+        assert!(Request::parse("dance").is_err());
+    }
+
+    #[test]
+    fn parse_json_commands() {
+        let request = Request::parse(r#"{"id": 7, "command": "record", "seconds": 3}"#).unwrap();
+        assert_eq!(request.id, Some(json!(7)));
+        assert_eq!(request.command, ControlCommand::Record { seconds: Some(3) });
+        assert!(Request::parse(r#"{"command": "dance"}"#).is_err());
+    }
+}
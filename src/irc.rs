@@ -0,0 +1,495 @@
+//! This module echoes messages into an IRC channel, mostly so streamers
+//! can put what they say into Twitch chat for viewers who can't hear it.
+//! Twitch chat is plain IRC (irc.chat.twitch.tv, port 6697 with TLS) with
+//! an OAuth token from the account doing the talking as the password.
+//!
+//! Messages longer than an IRC line allows are split up between words,
+//! and sending is held back to rate_limit messages every 30 seconds so
+//! Twitch doesn't lock the account out of chat for a while.  The
+//! connection is made the first time something's sent, and made again
+//! if the server dropped us in the meantime.
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use serde_derive::{Deserialize, Serialize};
+use log::{debug, error, warn};
+use crate::MelonError;
+use crate::outputs::OutputSink;
+
+/// How long to wait on the server before giving up
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The window rate_limit counts messages over
+const RATE_WINDOW: Duration = Duration::from_secs(30);
+
+/// Longest line IRC allows, not counting the CRLF on the end
+const LINE_LIMIT: usize = 510;
+
+/// Settings for an IRC output
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct IrcConfig {
+    /// The server to connect to
+    pub server: String,
+    /// Its port; usually 6697 with TLS and 6667 without
+    pub port: u16,
+    /// Connect over TLS
+    pub tls: bool,
+    /// Nickname to chat as; on Twitch this is the account name
+    pub nick: String,
+    /// Server password; on Twitch this is the account's OAuth token
+    pub token: Option<String>,
+    /// The channel to send to; on Twitch this is the streamer's account name
+    pub channel: String,
+    /// Most messages to send in any 30 seconds (0 doesn't hold back at all)
+    pub rate_limit: usize,
+    /// Longest message, in bytes, before it's split up
+    pub max_length: usize,
+}
+
+impl Default for IrcConfig {
+    fn default() -> Self {
+        IrcConfig {
+            server: "irc.chat.twitch.tv".to_string(),
+            port: 6697,
+            tls: true,
+            nick: String::new(),
+            token: None,
+            channel: String::new(),
+            rate_limit: 20,
+            max_length: 450,
+        }
+    }
+}
+
+impl IrcConfig {
+    /// Whether this is Twitch, which has a few quirks of its own
+    fn twitch(&self) -> bool {
+        self.server.ends_with("twitch.tv")
+    }
+
+    /// The channel with its # on the front.  Twitch channels are
+    /// always lowercase and won't match otherwise.
+    fn channel_name(&self) -> String {
+        let name = self.channel.trim_start_matches('#');
+        match self.twitch() {
+            true => format!("#{}", name.to_lowercase()),
+            false => format!("#{}", name)
+        }
+    }
+
+    /// The password to log in with.  Twitch wants the token written as
+    /// oauth:xxxx, which is easy to forget, so that's added if need be.
+    fn password(&self) -> Option<String> {
+        let token = self.token.as_deref().filter(|x| !x.is_empty())?;
+        match self.twitch() && !token.starts_with("oauth:") {
+            true => Some(format!("oauth:{}", token)),
+            false => Some(token.to_string())
+        }
+    }
+}
+
+/// Something IRC can be spoken over: a plain socket or TLS wrapped around one
+trait Stream: Read + Write + Send {}
+impl<T: Read + Write + Send> Stream for T {}
+
+/// A connection to the server
+struct Connection {
+    /// What gets read from and written to
+    stream: Box<dyn Stream>,
+    /// The socket underneath, for changing its timeouts
+    socket: TcpStream,
+    /// Anything read that doesn't make up a whole line yet
+    buffer: Vec<u8>,
+}
+
+impl Connection {
+    /// Send a line, adding the CRLF
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        self.stream.write_all(format!("{}\r\n", line).as_bytes())?;
+        self.stream.flush()
+    }
+
+    /// Wait for the next whole line from the server
+    fn read_line(&mut self) -> std::io::Result<String> {
+        loop {
+            if let Some(i) = self.buffer.iter().position(|x| *x == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=i).collect();
+                return Ok(String::from_utf8_lossy(&line).trim_end().to_string());
+            }
+            let mut chunk = [0u8; 4096];
+            match self.stream.read(&mut chunk)? {
+                0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                n => self.buffer.extend_from_slice(&chunk[..n])
+            }
+        }
+    }
+
+    /// Deal with whatever the server has sent since we last looked,
+    /// without waiting for more.  Errors mean the connection's gone.
+    fn poll(&mut self) -> std::io::Result<()> {
+        self.socket.set_nonblocking(true)?;
+        let result = loop {
+            match self.read_line() {
+                Ok(line) => self.handle(&line)?,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break Ok(()),
+                Err(e) => break Err(e)
+            }
+        };
+        self.socket.set_nonblocking(false)?;
+        result
+    }
+
+    /// Answer pings and note anything worth knowing about
+    fn handle(&mut self, line: &str) -> std::io::Result<()> {
+        let (_, command, rest) = parse(line);
+        match command {
+            "PING" => self.write_line(&format!("PONG {}", rest)),
+            "ERROR" => Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, rest.trim_start_matches(':').to_string())),
+            // Twitch says why a message didn't show up (slow mode, banned...) this way
+            "NOTICE" => {
+                warn!("IRC server says: {}", rest.split_once(':').map(|x| x.1).unwrap_or(rest));
+                Ok(())
+            },
+            _ => Ok(())
+        }
+    }
+}
+
+/// Split a line from the server into its prefix, command and the rest
+fn parse(line: &str) -> (&str, &str, &str) {
+    // Twitch can put IRCv3 tags in front, which we've no use for
+    let line = match line.starts_with('@') {
+        true => line.split_once(' ').map(|x| x.1).unwrap_or(""),
+        false => line
+    };
+    let (prefix, line) = match line.strip_prefix(':') {
+        Some(x) => x.split_once(' ').unwrap_or((x, "")),
+        None => ("", line)
+    };
+    let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+    (prefix, command, rest)
+}
+
+/// Sends messages to an IRC channel
+pub struct IrcSink {
+    /// The settings in use
+    config: IrcConfig,
+    /// The connection, once there is one
+    connection: Option<Connection>,
+    /// When recent messages went out, for rate limiting
+    sent: VecDeque<Instant>,
+}
+
+impl IrcSink {
+    /// Set up the IRC output.  Nothing connects until the first message.
+    pub fn new(config: &IrcConfig) -> IrcSink {
+        IrcSink { config: config.clone(), connection: None, sent: VecDeque::new() }
+    }
+
+    /// Connect, log in and join the channel
+    fn connect(&self) -> Result<Connection, MelonError> {
+        let address = format!("{}:{}", self.config.server, self.config.port);
+        debug!("Connecting to IRC at {}", address);
+        let socket = address.to_socket_addrs()
+            .map_err(|e| MelonError::Irc(format!("can't find {}: {}", self.config.server, e)))?
+            .find_map(|x| TcpStream::connect_timeout(&x, TIMEOUT).ok())
+            .ok_or_else(|| MelonError::Irc(format!("can't connect to {}", address)))?;
+        socket.set_read_timeout(Some(TIMEOUT))?;
+        socket.set_write_timeout(Some(TIMEOUT))?;
+        let stream: Box<dyn Stream> = match self.config.tls {
+            true => Box::new(self.tls(socket.try_clone()?)?),
+            false => Box::new(socket.try_clone()?)
+        };
+        let mut connection = Connection { stream, socket, buffer: Vec::new() };
+        let nick = &self.config.nick;
+        let mut login: Vec<String> = self.config.password().map(|x| format!("PASS {}", x)).into_iter().collect();
+        login.push(format!("NICK {}", nick));
+        login.push(format!("USER {} 0 * :MelonSTT", nick));
+        for line in login.iter() {
+            connection.write_line(line).map_err(|e| MelonError::Irc(e.to_string()))?;
+        }
+        // Welcome (001) means we're in; anything else is the server saying no
+        loop {
+            let line = connection.read_line().map_err(|e| MelonError::Irc(format!("no welcome from the server: {}", e)))?;
+            let (_, command, rest) = parse(&line);
+            match command {
+                "001" => break,
+                // Nick taken or not allowed
+                "432" | "433" => return Err(MelonError::Irc(format!("can't use the nick '{}'", nick))),
+                // Twitch turns down a bad token with a NOTICE and hangs up
+                "NOTICE" if rest.contains("authentication failed") || rest.contains("Improperly formatted auth") => {
+                    error!("Twitch didn't accept the token, is it right and does it have chat:edit?");
+                    return Err(MelonError::Irc("login failed, check the token".to_string()));
+                },
+                _ => connection.handle(&line).map_err(|e| MelonError::Irc(e.to_string()))?
+            }
+        }
+        connection.write_line(&format!("JOIN {}", self.config.channel_name()))
+            .map_err(|e| MelonError::Irc(e.to_string()))?;
+        debug!("Connected to IRC, joined {}", self.config.channel_name());
+        Ok(connection)
+    }
+
+    /// Wrap a socket in TLS, checking the server against the usual roots
+    fn tls(&self, socket: TcpStream) -> Result<rustls::StreamOwned<rustls::ClientConnection, TcpStream>, MelonError> {
+        let roots = rustls::RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+        let config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let name = rustls::pki_types::ServerName::try_from(self.config.server.clone())
+            .map_err(|e| MelonError::Irc(format!("bad server name '{}': {}", self.config.server, e)))?;
+        let connection = rustls::ClientConnection::new(Arc::new(config), name)
+            .map_err(|e| MelonError::Irc(e.to_string()))?;
+        Ok(rustls::StreamOwned::new(connection, socket))
+    }
+
+    /// Wait until another message can go out without going over rate_limit
+    fn wait_for_rate_limit(&mut self) {
+        if self.config.rate_limit == 0 { return; }
+        while self.sent.front().is_some_and(|x| x.elapsed() >= RATE_WINDOW) {
+            self.sent.pop_front();
+        }
+        if self.sent.len() >= self.config.rate_limit {
+            if let Some(oldest) = self.sent.front() {
+                let wait = RATE_WINDOW.saturating_sub(oldest.elapsed());
+                debug!("IRC rate limit reached, waiting {:.1}s", wait.as_secs_f32());
+                std::thread::sleep(wait);
+            }
+            self.sent.pop_front();
+        }
+    }
+
+    /// Send one line to the channel, connecting (or connecting again if
+    /// the old connection has gone stale) as need be
+    fn send_line(&mut self, text: &str) -> Result<(), MelonError> {
+        let line = format!("PRIVMSG {} :{}", self.config.channel_name(), text);
+        let attempts = if self.connection.is_some() { 2 } else { 1 };
+        let mut last_error = None;
+        for _ in 0..attempts {
+            if self.connection.is_none() {
+                self.connection = Some(self.connect()?);
+            }
+            let Some(connection) = self.connection.as_mut() else { continue };
+            match connection.poll().and_then(|_| connection.write_line(&line)) {
+                Ok(_) => return Ok(()),
+                Err(e) => {
+                    warn!("Lost connection to IRC ({})", e);
+                    self.connection = None;
+                    last_error = Some(MelonError::Irc(e.to_string()));
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| MelonError::Irc("couldn't get through to the server".to_string())))
+    }
+
+    /// How long a message can be once everything else on the line (and
+    /// what the server adds when passing it on) is taken into account
+    fn message_limit(&self) -> usize {
+        // :nick!user@host PRIVMSG #channel :, allowing the longest hostname
+        let overhead = format!(":{0}!{0}@ PRIVMSG {1} :", self.config.nick, self.config.channel_name()).len() + 63;
+        self.config.max_length.min(LINE_LIMIT.saturating_sub(overhead)).max(1)
+    }
+}
+
+impl OutputSink for IrcSink {
+    fn name(&self) -> String {
+        format!("IRC {}", self.config.channel_name())
+    }
+
+    fn send(&mut self, text: &str) -> Result<(), MelonError> {
+        let lines = split(text, self.message_limit());
+        for line in lines.iter() {
+            self.wait_for_rate_limit();
+            self.send_line(line)?;
+            self.sent.push_back(Instant::now());
+        }
+        debug!("Sent message to IRC in {} lines", lines.len());
+        Ok(())
+    }
+}
+
+/// Split a message into lines of at most limit bytes, between words
+/// where possible.  Line breaks can't be sent over IRC so they become
+/// spaces.
+fn split(text: &str, limit: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        if !line.is_empty() && line.len() + 1 + word.len() > limit {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        // A word too long for a line of its own is cut wherever it has to be
+        for c in word.chars() {
+            if line.len() + c.len_utf8() > limit {
+                lines.push(std::mem::take(&mut line));
+            }
+            line.push(c);
+        }
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    /// An IRC output pointed at a stand-in server on localhost
+    fn stand_in() -> (TcpListener, IrcConfig) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = IrcConfig {
+            server: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+            tls: false,
+            nick: "melon".to_string(),
+            token: Some("oauth:abc123".to_string()),
+            channel: "chan".to_string(),
+            rate_limit: 0,
+            ..Default::default()
+        };
+        (listener, config)
+    }
+
+    /// Take a client, send the lines given once it's logged in and hand
+    /// back the reader to carry on with and everything it sent so far
+    fn accept(listener: &TcpListener, replies: &str) -> (BufReader<TcpStream>, Vec<String>) {
+        let (mut socket, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(socket.try_clone().unwrap());
+        let login: Vec<String> = (0..3).map(|_| read(&mut reader)).collect();
+        socket.write_all(replies.as_bytes()).unwrap();
+        (reader, login)
+    }
+
+    fn read(reader: &mut BufReader<TcpStream>) -> String {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        line.trim_end().to_string()
+    }
+
+    #[test]
+    fn logs_in_and_sends() {
+        let (listener, config) = stand_in();
+        let server = std::thread::spawn(move || {
+            let (mut reader, mut lines) = accept(&listener, "PING :irc.example.com\r\n:irc.example.com 001 melon :Welcome\r\n");
+            lines.extend((0..3).map(|_| read(&mut reader)));
+            lines
+        });
+        IrcSink::new(&config).send("hello there").unwrap();
+        assert_eq!(server.join().unwrap(), [
+            "PASS oauth:abc123",
+            "NICK melon",
+            "USER melon 0 * :MelonSTT",
+            "PONG :irc.example.com",
+            "JOIN #chan",
+            "PRIVMSG #chan :hello there",
+        ]);
+    }
+
+    #[test]
+    fn taken_nick_is_an_error() {
+        let (listener, config) = stand_in();
+        let server = std::thread::spawn(move || {
+            let _ = accept(&listener, ":irc.example.com 433 * melon :Nickname is already in use\r\n");
+        });
+        match IrcSink::new(&config).send("hello") {
+            Err(MelonError::Irc(x)) => assert!(x.contains("nick"), "{}", x),
+            x => panic!("expected an Irc error, got {:?}", x),
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn reconnects_after_being_dropped() {
+        let (listener, config) = stand_in();
+        let (dropped, wait) = std::sync::mpsc::channel();
+        let server = std::thread::spawn(move || {
+            let welcome = ":irc.example.com 001 melon :Welcome\r\n";
+            let (mut reader, _) = accept(&listener, welcome);
+            let first = (0..2).map(|_| read(&mut reader)).last().unwrap();
+            drop(reader);
+            dropped.send(()).unwrap();
+            let (mut reader, login) = accept(&listener, welcome);
+            let second = (0..2).map(|_| read(&mut reader)).last().unwrap();
+            (first, login, second)
+        });
+        let mut sink = IrcSink::new(&config);
+        sink.send("one").unwrap();
+        wait.recv().unwrap();
+        // Give the hang up a moment to arrive
+        std::thread::sleep(Duration::from_millis(50));
+        sink.send("two").unwrap();
+        let (first, login, second) = server.join().unwrap();
+        assert_eq!(first, "PRIVMSG #chan :one");
+        assert_eq!(login[1], "NICK melon");
+        assert_eq!(second, "PRIVMSG #chan :two");
+    }
+
+    #[test]
+    fn twitch_token_gets_oauth_prefix() {
+        let config = IrcConfig { token: Some("abc123".to_string()), ..Default::default() };
+        assert_eq!(config.password().as_deref(), Some("oauth:abc123"));
+        let config = IrcConfig { token: Some("oauth:abc123".to_string()), ..Default::default() };
+        assert_eq!(config.password().as_deref(), Some("oauth:abc123"));
+        let config = IrcConfig { server: "irc.libera.chat".to_string(), token: Some("abc123".to_string()), ..Default::default() };
+        assert_eq!(config.password().as_deref(), Some("abc123"));
+        let config = IrcConfig { token: Some(String::new()), ..Default::default() };
+        assert_eq!(config.password(), None);
+    }
+
+    #[test]
+    fn twitch_channel_is_lowercased() {
+        let config = IrcConfig { channel: "#MelonDog".to_string(), ..Default::default() };
+        assert_eq!(config.channel_name(), "#melondog");
+    }
+
+    #[test]
+    fn message_limit_leaves_room_for_the_prefix() {
+        let (_, config) = stand_in();
+        // 510 - len(":melon!melon@ PRIVMSG #chan :") - 63
+        assert_eq!(IrcSink::new(&config).message_limit(), 418);
+        let config = IrcConfig { max_length: 100, ..config };
+        assert_eq!(IrcSink::new(&config).message_limit(), 100);
+    }
+
+    #[test]
+    fn splits_at_message_limit() {
+        let (_, config) = stand_in();
+        let limit = IrcSink::new(&config).message_limit();
+        let text = ["word"; 200].join(" ");
+        let lines = split(&text, limit);
+        // 83 words fit in 418 bytes
+        assert_eq!(lines.iter().map(|x| x.split(' ').count()).collect::<Vec<_>>(), [83, 83, 34]);
+        assert!(lines.iter().all(|x| x.len() <= limit));
+        assert_eq!(lines.join(" "), text);
+        assert_eq!(split(&"a".repeat(limit), limit).len(), 1);
+        assert_eq!(split(&"a".repeat(limit + 1), limit).len(), 2);
+    }
+
+    #[test]
+    fn splits_between_words() {
+        assert_eq!(split("one two three", 7), ["one two", "three"]);
+        assert_eq!(split("one\ntwo", 100), ["one two"]);
+        assert_eq!(split("abcdefghij", 4), ["abcd", "efgh", "ij"]);
+        assert!(split("   ", 10).is_empty());
+    }
+
+    #[test]
+    fn splits_multibyte_characters_whole() {
+        assert_eq!(split("ééé", 5), ["éé", "é"]);
+        assert_eq!(split("日本語です", 7), ["日本", "語で", "す"]);
+        assert_eq!(split("🍈🍈🍈", 9), ["🍈🍈", "🍈"]);
+        for line in split(&"éa日🍈".repeat(50), 11) {
+            assert!(line.len() <= 11);
+        }
+    }
+}
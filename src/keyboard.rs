@@ -0,0 +1,326 @@
+//! This module types messages into whatever window has focus, for apps
+//! that don't speak OSC (games, Discord, text editors and so on).  It
+//! fakes key presses one character at a time, with a pause in between
+//! since plenty of games drop keys that arrive too quickly, and can
+//! press Enter at the end to send the message.
+//!
+//! There are two ways of faking keys.  The native one (XTest on X11,
+//! SendInput on Windows, CGEvent on macOS) can type anything the OS can.
+//! On Linux there's also uinput, which makes a virtual keyboard in the
+//! kernel, so it works under Wayland too.  It needs write access to
+//! /dev/uinput (the input group, usually) and can only type what's on a
+//! US keyboard, since it has to go through whatever layout is active.
+use std::time::Duration;
+use serde_derive::{Deserialize, Serialize};
+use log::{debug, error, warn};
+use crate::MelonError;
+use crate::outputs::OutputSink;
+
+/// How key presses get faked
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyboardBackend {
+    /// uinput on Wayland, the native API everywhere else
+    #[default]
+    Auto,
+    /// The native API (XTest on X11, Windows, macOS)
+    Native,
+    /// A virtual keyboard through /dev/uinput (Linux only)
+    Uinput,
+}
+
+impl KeyboardBackend {
+    /// Work out which backend Auto means here
+    fn resolve(self) -> KeyboardBackend {
+        match self {
+            KeyboardBackend::Auto if cfg!(target_os = "linux")
+                && (std::env::var_os("WAYLAND_DISPLAY").is_some() || std::env::var_os("DISPLAY").is_none()) => KeyboardBackend::Uinput,
+            KeyboardBackend::Auto => KeyboardBackend::Native,
+            x => x
+        }
+    }
+}
+
+/// The thing actually pressing keys, once it's been set up
+enum Typist {
+    /// enigo, for the native API
+    Native(Box<enigo::Enigo>),
+    /// A uinput virtual keyboard
+    #[cfg(target_os = "linux")]
+    Uinput(uinput::Keyboard),
+}
+
+/// Types messages into the focused window
+pub struct KeyboardSink {
+    /// Pause between characters
+    char_delay: Duration,
+    /// Press Enter once the message is typed
+    press_enter: bool,
+    /// How to fake the key presses
+    backend: KeyboardBackend,
+    /// The keyboard, once it's been set up
+    typist: Option<Typist>,
+}
+
+impl KeyboardSink {
+    /// Set up typing.  Nothing touches the keyboard until the first
+    /// message, so a missing display or /dev/uinput shows up as an
+    /// output error rather than stopping MelonSTT from starting.
+    pub fn new(char_delay_ms: u64, press_enter: bool, backend: KeyboardBackend) -> KeyboardSink {
+        KeyboardSink { char_delay: Duration::from_millis(char_delay_ms), press_enter, backend: backend.resolve(), typist: None }
+    }
+
+    /// Set up whichever backend is in use
+    fn open(&self) -> Result<Typist, MelonError> {
+        match self.backend {
+            #[cfg(target_os = "linux")]
+            KeyboardBackend::Uinput => Ok(Typist::Uinput(uinput::Keyboard::new().map_err(|e| self.error(e))?)),
+            #[cfg(not(target_os = "linux"))]
+            KeyboardBackend::Uinput => Err(self.error("uinput is only available on Linux")),
+            _ => match enigo::Enigo::new(&enigo::Settings::default()) {
+                Ok(x) => Ok(Typist::Native(Box::new(x))),
+                Err(e) => Err(self.error(e))
+            }
+        }
+    }
+
+    /// Wrap something that went wrong as an output error
+    fn error(&self, reason: impl ToString) -> MelonError {
+        MelonError::Output { output: self.name(), reason: reason.to_string() }
+    }
+}
+
+impl OutputSink for KeyboardSink {
+    fn name(&self) -> String {
+        match self.backend {
+            KeyboardBackend::Uinput => "keyboard (uinput)".to_string(),
+            _ => "keyboard".to_string()
+        }
+    }
+
+    fn send(&mut self, text: &str) -> Result<(), MelonError> {
+        if self.typist.is_none() {
+            self.typist = Some(self.open()?);
+        }
+        let (delay, press_enter) = (self.char_delay, self.press_enter);
+        let result = match self.typist.as_mut() {
+            Some(x) => type_text(x, text, delay, press_enter),
+            None => return Ok(())
+        };
+        match result {
+            Ok(skipped) => {
+                if skipped > 0 {
+                    warn!("Skipped {} characters that can't be typed through uinput", skipped);
+                }
+                debug!("Typed {} characters", text.chars().count() - skipped);
+                Ok(())
+            },
+            Err(e) => {
+                error!("Error typing message: {}", e);
+                // Start over next time in case the display or device went away
+                self.typist = None;
+                Err(self.error(e))
+            }
+        }
+    }
+}
+
+/// Type a message a character at a time, handing back how many
+/// characters had to be skipped
+fn type_text(typist: &mut Typist, text: &str, delay: Duration, press_enter: bool) -> Result<usize, String> {
+    use enigo::Keyboard;
+    let mut skipped = 0;
+    for c in one_line(text).chars() {
+        let typed = match typist {
+            Typist::Native(x) => x.text(&c.to_string()).map(|_| true).map_err(|e| e.to_string())?,
+            #[cfg(target_os = "linux")]
+            Typist::Uinput(x) => x.type_char(c).map_err(|e| e.to_string())?,
+        };
+        if !typed { skipped += 1; }
+        std::thread::sleep(delay);
+    }
+    if press_enter {
+        match typist {
+            Typist::Native(x) => x.key(enigo::Key::Return, enigo::Direction::Click).map_err(|e| e.to_string())?,
+            #[cfg(target_os = "linux")]
+            Typist::Uinput(x) => x.press_enter().map_err(|e| e.to_string())?,
+        }
+    }
+    Ok(skipped)
+}
+
+/// Newlines would send half a message in most chat boxes, so they're
+/// typed as spaces and only press_enter sends it
+fn one_line(text: &str) -> String {
+    text.chars().map(|x| if x == '\n' || x == '\r' { ' ' } else { x }).collect()
+}
+
+/// A virtual keyboard made through /dev/uinput
+#[cfg(target_os = "linux")]
+mod uinput {
+    use std::io;
+    use std::time::Duration;
+    use evdev::{AttributeSet, EventType, InputEvent, Key};
+    use evdev::uinput::{VirtualDevice, VirtualDeviceBuilder};
+    use log::debug;
+
+    /// Every key a US keyboard needs to type ASCII
+    const KEYS: [Key; 50] = [
+        Key::KEY_A, Key::KEY_B, Key::KEY_C, Key::KEY_D, Key::KEY_E, Key::KEY_F, Key::KEY_G,
+        Key::KEY_H, Key::KEY_I, Key::KEY_J, Key::KEY_K, Key::KEY_L, Key::KEY_M, Key::KEY_N,
+        Key::KEY_O, Key::KEY_P, Key::KEY_Q, Key::KEY_R, Key::KEY_S, Key::KEY_T, Key::KEY_U,
+        Key::KEY_V, Key::KEY_W, Key::KEY_X, Key::KEY_Y, Key::KEY_Z,
+        Key::KEY_1, Key::KEY_2, Key::KEY_3, Key::KEY_4, Key::KEY_5, Key::KEY_6, Key::KEY_7,
+        Key::KEY_8, Key::KEY_9, Key::KEY_0,
+        Key::KEY_SPACE, Key::KEY_MINUS, Key::KEY_EQUAL, Key::KEY_LEFTBRACE, Key::KEY_RIGHTBRACE,
+        Key::KEY_BACKSLASH, Key::KEY_SEMICOLON, Key::KEY_APOSTROPHE, Key::KEY_GRAVE, Key::KEY_COMMA,
+        Key::KEY_DOT, Key::KEY_SLASH, Key::KEY_ENTER, Key::KEY_LEFTSHIFT,
+    ];
+
+    /// The virtual keyboard
+    pub struct Keyboard {
+        device: VirtualDevice,
+    }
+
+    impl Keyboard {
+        /// Make the virtual keyboard
+        pub fn new() -> io::Result<Keyboard> {
+            let mut keys = AttributeSet::<Key>::new();
+            for key in KEYS {
+                keys.insert(key);
+            }
+            let device = VirtualDeviceBuilder::new()?
+                .name("MelonSTT virtual keyboard")
+                .with_keys(&keys)?
+                .build()?;
+            // The desktop takes a moment to notice a new keyboard, and
+            // anything typed before then is lost
+            std::thread::sleep(Duration::from_millis(300));
+            debug!("Created uinput virtual keyboard");
+            Ok(Keyboard { device })
+        }
+
+        /// Type one character, handing back false if it isn't on a US keyboard
+        pub fn type_char(&mut self, c: char) -> io::Result<bool> {
+            match key_for(c) {
+                Some((key, shift)) => {
+                    self.tap(key, shift)?;
+                    Ok(true)
+                },
+                None => Ok(false)
+            }
+        }
+
+        /// Press and let go of Enter
+        pub fn press_enter(&mut self) -> io::Result<()> {
+            self.tap(Key::KEY_ENTER, false)
+        }
+
+        /// Press and let go of a key, holding shift around it if need be
+        fn tap(&mut self, key: Key, shift: bool) -> io::Result<()> {
+            let event = |key: Key, value: i32| InputEvent::new(EventType::KEY, key.code(), value);
+            if shift { self.device.emit(&[event(Key::KEY_LEFTSHIFT, 1)])?; }
+            self.device.emit(&[event(key, 1)])?;
+            self.device.emit(&[event(key, 0)])?;
+            if shift { self.device.emit(&[event(Key::KEY_LEFTSHIFT, 0)])?; }
+            Ok(())
+        }
+    }
+
+    /// Which key (and whether shift is needed) types a character on a US layout
+    fn key_for(c: char) -> Option<(Key, bool)> {
+        const LETTERS: &str = "abcdefghijklmnopqrstuvwxyz";
+        const DIGITS: &str = "1234567890";
+        const SHIFTED_DIGITS: &str = "!@#$%^&*()";
+        if let Some(i) = LETTERS.find(c.to_ascii_lowercase()).filter(|_| c.is_ascii_alphabetic()) {
+            return Some((KEYS[i], c.is_ascii_uppercase()));
+        }
+        if let Some(i) = DIGITS.find(c) {
+            return Some((KEYS[26 + i], false));
+        }
+        if let Some(i) = SHIFTED_DIGITS.find(c) {
+            return Some((KEYS[26 + i], true));
+        }
+        let key = match c {
+            ' ' => (Key::KEY_SPACE, false),
+            '\t' => (Key::KEY_SPACE, false),
+            '-' => (Key::KEY_MINUS, false),
+            '_' => (Key::KEY_MINUS, true),
+            '=' => (Key::KEY_EQUAL, false),
+            '+' => (Key::KEY_EQUAL, true),
+            '[' => (Key::KEY_LEFTBRACE, false),
+            '{' => (Key::KEY_LEFTBRACE, true),
+            ']' => (Key::KEY_RIGHTBRACE, false),
+            '}' => (Key::KEY_RIGHTBRACE, true),
+            '\\' => (Key::KEY_BACKSLASH, false),
+            '|' => (Key::KEY_BACKSLASH, true),
+            ';' => (Key::KEY_SEMICOLON, false),
+            ':' => (Key::KEY_SEMICOLON, true),
+            '\'' | '’' | '‘' => (Key::KEY_APOSTROPHE, false),
+            '"' | '“' | '”' => (Key::KEY_APOSTROPHE, true),
+            '`' => (Key::KEY_GRAVE, false),
+            '~' => (Key::KEY_GRAVE, true),
+            ',' => (Key::KEY_COMMA, false),
+            '<' => (Key::KEY_COMMA, true),
+            '.' => (Key::KEY_DOT, false),
+            '>' => (Key::KEY_DOT, true),
+            '/' => (Key::KEY_SLASH, false),
+            '?' => (Key::KEY_SLASH, true),
+            _ => return None
+        };
+        Some(key)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn maps_plain_characters() {
+            assert_eq!(key_for('a'), Some((Key::KEY_A, false)));
+            assert_eq!(key_for('z'), Some((Key::KEY_Z, false)));
+            assert_eq!(key_for('1'), Some((Key::KEY_1, false)));
+            assert_eq!(key_for('0'), Some((Key::KEY_0, false)));
+            assert_eq!(key_for(' '), Some((Key::KEY_SPACE, false)));
+            assert_eq!(key_for('/'), Some((Key::KEY_SLASH, false)));
+        }
+
+        #[test]
+        fn maps_shifted_characters() {
+            assert_eq!(key_for('A'), Some((Key::KEY_A, true)));
+            assert_eq!(key_for('!'), Some((Key::KEY_1, true)));
+            assert_eq!(key_for(')'), Some((Key::KEY_0, true)));
+            assert_eq!(key_for('?'), Some((Key::KEY_SLASH, true)));
+            assert_eq!(key_for('_'), Some((Key::KEY_MINUS, true)));
+            assert_eq!(key_for('"'), Some((Key::KEY_APOSTROPHE, true)));
+        }
+
+        #[test]
+        fn maps_smart_quotes() {
+            assert_eq!(key_for('’'), Some((Key::KEY_APOSTROPHE, false)));
+            assert_eq!(key_for('‘'), Some((Key::KEY_APOSTROPHE, false)));
+            assert_eq!(key_for('“'), Some((Key::KEY_APOSTROPHE, true)));
+            assert_eq!(key_for('”'), Some((Key::KEY_APOSTROPHE, true)));
+        }
+
+        #[test]
+        fn skips_what_a_us_keyboard_cant_type() {
+            assert_eq!(key_for('é'), None);
+            assert_eq!(key_for('ß'), None);
+            assert_eq!(key_for('日'), None);
+            assert_eq!(key_for('🍈'), None);
+            assert_eq!(key_for('\u{7f}'), None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn newlines_become_spaces() {
+        assert_eq!(one_line("one\ntwo\r\nthree"), "one two  three");
+        assert_eq!(one_line("no breaks"), "no breaks");
+    }
+}
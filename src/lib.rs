@@ -3,11 +3,28 @@
 //! This file defines a 'melonstt' struct and associated functions
 //! to record, process, transcribe, and send spoken speech
 //! which can be wrapped in a UI of your choosing
-use recorder::STTRecorder;
-use processor::STTProcessor;
-use log::{debug, error};
+use recorder::{InputLevel, RawAudio, STTRecorder};
+use processor::{DecodeOptions, STTProcessor};
+use log::{debug, error, warn};
 use network::STTNetwork;
-use serde_derive::Deserialize;
+use filter::{FilterConfig, STTFilter};
+use pipeline::{RuleConfig, STTPipeline};
+use censor::{STTCensor, Verdict};
+use models::ModelInfo;
+use history::{HistoryEntry, STTHistory};
+use hotkeys::{HotkeyAction, HotkeyEvent};
+use subtitles::{Cue, STTSubtitles};
+use recordings::STTRecordings;
+use outputs::STTOutputs;
+pub use error::MelonError;
+pub use config::{ConfigIssue, STTConfig};
+use events::{EventBus, MelonEvent};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::mpsc::Receiver;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
 
 /// This module defines a struct and associated functions for
 /// recording and converting incoming speech audio
@@ -20,15 +37,286 @@ pub mod processor;
 /// This module handles sending OSC packets to VRChat via UDP
 pub mod network;
 
-/// A struct representing a configuration file
-#[derive(Deserialize)]
-pub struct STTConfig {
-    /// The language of the incoming speech audio
+/// This module filters hallucinations and non-speech junk out of
+/// Whisper's transcriptions
+pub mod filter;
+
+/// This module defines the configurable text post-processing pipeline
+pub mod pipeline;
+
+/// This module masks or blocks profanity and personal info before sending
+pub mod censor;
+
+/// This module finds, describes and verifies ggml model files
+pub mod models;
+
+/// This module defines the error type used throughout the library
+pub mod error;
+
+/// This module finds, reads and validates the config file
+pub mod config;
+
+/// This module defines events announced by MelonSTT and how to listen for them
+pub mod events;
+
+/// This module keeps, saves and exports the history of transcriptions
+pub mod history;
+
+/// This module picks up global hotkeys and local trigger commands
+pub mod hotkeys;
+
+/// This module runs the optional HTTP/WebSocket control server
+pub mod server;
+
+/// This module takes line-based commands over a Unix socket or named pipe
+pub mod ipc;
+
+/// This module writes transcriptions out as SRT/WebVTT subtitle files
+pub mod subtitles;
+
+/// This module sends captions to OBS through obs-websocket
+pub mod obs;
+
+/// This module defines the outputs messages get sent to
+pub mod outputs;
+
+/// This module types messages into the focused window
+pub mod keyboard;
+
+/// This module sends messages to an IRC channel such as Twitch chat
+pub mod irc;
+
+/// This module saves recordings as WAV files alongside the history
+pub mod recordings;
+
+/// This module holds helpers shared between the tests
+#[cfg(test)]
+mod testing;
+
+/// What happened to a message handed to send_to_osc
+#[derive(Clone, Debug, PartialEq, serde_derive::Serialize)]
+#[serde(tag = "status", content = "text", rename_all = "snake_case")]
+pub enum SendStatus {
+    /// The message was sent; holds the text that actually went out
+    Sent(String),
+    /// There was nothing to send so nothing was sent
+    Empty,
+    /// The content filter refused to send it; holds the reason why
+    Blocked(String),
+}
+
+/// What handle_hotkey ended up doing
+#[derive(Clone, Debug, PartialEq)]
+pub enum HotkeyOutcome {
+    /// A recording was started
+    Started,
+    /// A recording was stopped and transcribed; holds the transcription
+    Transcribed(String),
+    /// The latest transcription was sent (or not); holds how that went
+    Sent(SendStatus),
+    /// The recording in progress was thrown away
+    Cancelled,
+    /// The hotkey didn't apply right now, like a cancel with nothing recording
+    Nothing,
+}
+
+/// A snapshot of what MelonSTT is up to, for anything controlling it
+/// from outside the window
+#[derive(Clone, Debug, serde_derive::Serialize)]
+pub struct MelonStatus {
+    /// Whether a recording is running
+    pub recording: bool,
+    /// The input device being recorded from
+    pub device: String,
+    /// The model currently loaded
+    pub model: String,
+    /// The language being transcribed
     pub language: String,
-    /// Filename of the whisper language model to use for transcription
+    /// The active profile, if any
+    pub profile: Option<String>,
+    /// Every profile that could be switched to
+    pub profiles: Vec<String>,
+    /// The latest transcription in the history
+    pub latest: Option<HistoryEntry>,
+}
+
+/// Something to do from outside the window, sent through the control
+/// server or the command socket
+#[derive(Clone, Debug, PartialEq, serde_derive::Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlCommand {
+    /// What's going on right now
+    Status,
+    /// Record for some seconds, or until silence
+    Record {
+        /// How long to record for; None stops once the speaker goes quiet
+        seconds: Option<u64>
+    },
+    /// Start recording until stop
+    Start,
+    /// Stop recording and transcribe
+    Stop,
+    /// Stop recording and throw it away
+    Cancel,
+    /// Send some text, or the latest transcription
+    Send {
+        /// What to send; None sends the latest transcription
+        text: Option<String>
+    },
+    /// The latest history entry
+    Latest,
+    /// Switch profile
+    Profile {
+        /// The profile to switch to; None for no profile
+        name: Option<String>
+    },
+}
+
+/// Settings to change when running a saved recording through Whisper
+/// again.  Anything left as None stays as it is in the current settings.
+#[derive(Clone, Debug, Default, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(default)]
+pub struct RetranscribeOptions {
+    /// A different model; a bare filename is looked for in models_dir
+    pub model: Option<String>,
+    /// A different language code
+    pub language: Option<String>,
+    /// A different initial prompt, or an empty one for no prompt
+    pub prompt: Option<String>,
+    /// A different beam size
+    pub beam_size: Option<i32>,
+}
+
+impl RetranscribeOptions {
+    /// The decode settings for the run: `base` with anything set here
+    /// swapped in.  An empty prompt means no prompt at all.
+    pub fn decode_options(&self, base: DecodeOptions) -> DecodeOptions {
+        let mut decode = base;
+        if let Some(x) = &self.language { decode.language = x.to_string(); }
+        if let Some(x) = &self.prompt { decode.prompt = Some(x.to_string()).filter(|x| !x.is_empty()); }
+        if let Some(x) = self.beam_size { decode.beam_size = x; }
+        decode
+    }
+
+    /// The model file to load for the run, or None if it's the `current`
+    /// one and that can be used as it is
+    pub fn model_file(&self, config: &STTConfig, current: &str) -> Option<String> {
+        self.model.as_deref()
+            .map(|x| config.model_file(x).to_string_lossy().to_string())
+            .filter(|x| x != current)
+    }
+}
+
+/// What came of running a saved recording through Whisper again
+#[derive(Clone, Debug, PartialEq, serde_derive::Serialize)]
+pub struct Retranscription {
+    /// The history entry the recording belongs to
+    pub id: u64,
+    /// The model used
     pub model: String,
-    /// Optional ip:port for the OSC endpoint (defaults to 127.0.0.1:9000)
-    pub osc_endpoint: Option<String>,
+    /// The language used
+    pub language: String,
+    /// The prompt used, if any
+    pub prompt: Option<String>,
+    /// The beam size used
+    pub beam_size: i32,
+    /// Exactly what Whisper came up with
+    pub raw_text: String,
+    /// The text after the filter and pipeline were done with it
+    pub text: String,
+    /// How long it took, in seconds
+    pub seconds: f32,
+}
+
+/// A recording on its way through Whisper.  prepare_transcription takes
+/// it out of MelonSTT so the slow part can run without holding on to
+/// everything else, then finish_transcription takes it back.
+pub struct PendingTranscription {
+    /// A handle on the loaded model, with the decode settings at the time
+    processor: STTProcessor,
+    /// The 16kHz audio
+    audio: Vec<f32>,
+    /// The audio exactly as the device recorded it
+    raw_audio: Option<RawAudio>,
+    /// When the recording started
+    started: Option<chrono::DateTime<chrono::Local>>,
+}
+
+impl PendingTranscription {
+    /// Run the audio through Whisper.  This is the slow part.
+    pub fn run(&mut self) -> Result<(), MelonError> {
+        match self.processor.process(&self.audio) {
+            Ok(_) => {
+                debug!("Call to process_audio succeeded");
+                Ok(())
+            },
+            Err(e) => {
+                error!("Error calling process_audio");
+                Err(e)
+            }
+        }
+    }
+}
+
+/// A saved recording waiting to be run through Whisper again.  Made by
+/// prepare_retranscription; everything slow happens in run, which doesn't
+/// need the MelonSTT at all.
+pub struct PendingRetranscription {
+    /// The history entry the recording belongs to
+    id: u64,
+    /// The recording to load
+    recording: PathBuf,
+    /// A different model to load just for this run, if one was picked
+    model: Option<String>,
+    /// A handle on the loaded model, with the settings for this run
+    processor: STTProcessor,
+    /// The filter settings to apply
+    filter: FilterConfig,
+    /// The pipeline rules to apply
+    pipeline: Vec<RuleConfig>,
+}
+
+impl PendingRetranscription {
+    /// Load the recording (and the model, if need be) and run it through Whisper
+    pub fn run(self) -> Result<Retranscription, MelonError> {
+        let started = std::time::Instant::now();
+        let id = self.id;
+        let audio = STTRecordings::load(&self.recording)?;
+        let samples = match audio.sample_rate {
+            16_000 => audio.samples,
+            x => STTRecorder::resample(&audio.samples, x, 16_000)
+        };
+        let decode = self.processor.options.clone();
+        let mut processor = match self.model {
+            Some(x) => {
+                debug!("Loading {} to retranscribe entry {}", x, id);
+                STTProcessor::new(x, decode.clone())?
+            },
+            None => self.processor
+        };
+        match processor.process(&samples) {
+            Ok(_) => { debug!("Retranscribed entry {}", id); },
+            Err(e) => {
+                error!("Error retranscribing entry {}", id);
+                return Err(e);
+            }
+        }
+        let filtered = STTFilter::new(&self.filter).filter(&processor.segments);
+        let text = match filtered.is_empty() {
+            true => filtered,
+            false => STTPipeline::new(&self.pipeline)?.apply(&filtered)
+        };
+        Ok(Retranscription {
+            id,
+            model: processor.current_model(),
+            language: decode.language,
+            prompt: decode.prompt,
+            beam_size: decode.beam_size,
+            raw_text: processor.processed_text,
+            text,
+            seconds: started.elapsed().as_secs_f32(),
+        })
+    }
 }
 
 /// Define the struct that does all the things
@@ -36,39 +324,91 @@ pub struct MelonSTT {
     /// For recording audio
     pub recorder: STTRecorder,
     /// For processing audio
-    pub processor: STTProcessor<'static>,
+    pub processor: STTProcessor,
     /// For sending OSC packets
     pub network: STTNetwork,
+    /// For throwing out hallucinated text
+    pub filter: STTFilter,
+    /// For cleaning up the text before it gets sent
+    pub pipeline: STTPipeline,
+    /// For keeping anything nasty out of the chatbox
+    pub censor: STTCensor,
+    /// Everything transcribed so far
+    pub history: STTHistory,
+    /// For captioning everything said to subtitle files
+    pub subtitles: STTSubtitles,
+    /// For keeping the audio behind each transcription
+    pub recordings: STTRecordings,
+    /// Everywhere besides the chatbox that messages get sent
+    pub outputs: STTOutputs,
+    /// The settings currently in use, with the active profile applied
+    pub config: STTConfig,
+    /// The settings as read from the config file, before any profile
+    pub base_config: STTConfig,
+    /// Where that config was read from
+    pub config_path: PathBuf,
+    /// For telling listeners about things that happen in the background
+    pub events: EventBus,
+    /// A model load kicked off by a config change.  config.model already
+    /// names the new model, so it gets put back if the load fails.
+    model_load: Option<JoinHandle<Result<(), MelonError>>>,
 }
 
 impl MelonSTT {
-    /// Given a config file name, build a new MelonSTT
-    pub fn new(config: &str) -> Result<MelonSTT, anyhow::Error> {
-        let config = match Self::read_config(config) {
+    /// Given a config file name, build a new MelonSTT.  Use
+    /// STTConfig::discover() to find the config file if you don't have one.
+    pub fn new(config: &str) -> Result<MelonSTT, MelonError> {
+        let config_path = PathBuf::from(config);
+        let base_config = match STTConfig::load(&config_path) {
             Ok(x) => {
                 debug!("Read config ok");
                 x
             },
             Err(e) => {
                 error!("Error reading config");
-                return Err(e.into());
+                return Err(e);
             }
         };
-        let recorder = match STTRecorder::new() {
+        let config = match base_config.with_profile(base_config.profile.as_deref()) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Error applying startup profile");
+                return Err(e);
+            }
+        };
+        // A missing device shouldn't stop startup; fall back to the default one
+        let recorder = match STTRecorder::with_device(config.input_device.as_deref()) {
+            Err(MelonError::UnknownDevice(x)) => {
+                warn!("Input device '{}' not found, using the default device", x);
+                STTRecorder::new()
+            },
+            x => x
+        };
+        let mut recorder = match recorder {
             Ok(x) => x,
             Err(e) => {
                 error!("Error creating STTRecorder");
-                return Err(e.into());
+                return Err(e);
             }
         };
-        // I cannot for the life of me get past the borrow checker if I try 
-        // sending the language along here, so it's getting hard-coded to Some(en)
-        // in STTProcessor::new().
-        let processor = match STTProcessor::new(config.model.to_string()) {
+        // Not being able to listen all the time isn't worth failing over either
+        if config.preroll.enabled {
+            if let Err(e) = recorder.set_preroll(Some(config.preroll.length_ms)) {
+                warn!("Unable to keep the input device open for pre-roll: {}", e);
+            }
+        }
+        // Complain now if the model doesn't match the configured language
+        let model = config.model_file(&config.model).to_string_lossy().to_string();
+        let info = ModelInfo::new(Path::new(&model));
+        debug!("Using model {}", info.describe());
+        if let Some(x) = info.language_warning(&config.language) {
+            warn!("{}", x);
+        }
+        let processor = match STTProcessor::new(model, config.decode_options()) {
             Ok(x) => x,
             Err(e) => {
                 error!("Error creating STTProcessor");
-                return Err(e.into());
+                return Err(e);
             }
         };
         // Prep the networking side
@@ -79,43 +419,346 @@ impl MelonSTT {
             },
             Err(e) => {
                 error!("Error initializing networking");
-                return Err(e.into());
+                return Err(e);
             }
         };
-        Ok(MelonSTT { recorder, processor, network })
-    }
-
-    /// Read and process the config file specified by the incoming str
-    fn read_config(config: &str) -> Result<STTConfig, anyhow::Error> {
-        let conf_data = match std::fs::read_to_string(config) {
+        let filter = STTFilter::new(&config.filter);
+        let pipeline = match STTPipeline::new(&config.pipeline) {
             Ok(x) => x,
             Err(e) => {
-                error!("Unable to read config file");
-                return Err(e.into());
+                error!("Error building text pipeline");
+                return Err(e);
             }
         };
-        match toml::from_str(&conf_data) {
-            Ok(x) => Ok(x),
+        let censor = match STTCensor::new(&config.censor) {
+            Ok(x) => x,
             Err(e) => {
-                error!("Error parsing config toml");
-                return Err(e.into());
+                error!("Error building content filter");
+                return Err(e);
             }
+        };
+        let history = STTHistory::new(&config.history_config());
+        let subtitles = STTSubtitles::new(&config.subtitle_config());
+        let recordings = STTRecordings::new(&config.recordings_config());
+        let events = EventBus::new();
+        let outputs = STTOutputs::new(&config.outputs, &config.obs, &events);
+        Ok(MelonSTT { recorder, processor, network, filter, pipeline, censor, history, subtitles, recordings, outputs, config, base_config, config_path, events, model_load: None })
+    }
+
+    /// Start listening for events (config reloads, model loads, etc)
+    pub fn subscribe(&self) -> Receiver<MelonEvent> {
+        self.events.subscribe()
+    }
+
+    /// Start listening for input levels, sent about 30 times a second
+    /// while recording.  Good for driving a level meter.
+    pub fn subscribe_levels(&self) -> Receiver<InputLevel> {
+        self.recorder.levels.subscribe()
+    }
+
+    /// Re-read the config file and apply whatever changed.  Only the parts
+    /// that changed get touched, and the model is only reloaded if `model`
+    /// itself changed.  The active profile is kept unless the file's own
+    /// `profile` setting changed.  The result is also announced as an event.
+    pub fn reload_config(&mut self) -> Result<Vec<String>, MelonError> {
+        let result = STTConfig::load(&self.config_path).and_then(|base| {
+            let profile = match base.profile != self.base_config.profile {
+                true => base.profile.clone(),
+                false => self.config.profile.clone().filter(|x| base.profiles.contains_key(x))
+            };
+            let new = base.with_profile(profile.as_deref())?;
+            let changes = self.apply_config(new)?;
+            self.base_config = base;
+            Ok(changes)
+        });
+        match &result {
+            Ok(changes) => self.events.emit(MelonEvent::ConfigReloaded(changes.clone())),
+            Err(e) => self.events.emit(MelonEvent::ConfigError(e.to_string())),
         }
+        result
     }
 
-    /// Record audio for the specified number of seconds, then process it
-    /// and hand back the transcribed string.
-    pub fn do_recording(&mut self, seconds: u64) -> Result<String, anyhow::Error> {
-        // Toggle the typing indicator on before starting to record
-        // It might be overkill to return Err if it fails but it's more
-        // likely than not if toggle fails then send will fail.
-        match self.network.toggle_typing(true) {
-            Ok(_) => { debug!("Toggled typing indicator on"); },
+    /// Apply a new config on top of the running one, rebuilding only what's
+    /// needed.  Everything is built before anything is swapped in, so a bad
+    /// config leaves the current setup alone.  Hands back a list of what changed.
+    pub fn apply_config(&mut self, new: STTConfig) -> Result<Vec<String>, MelonError> {
+        self.settle_model_load(false);
+        let old = &self.config;
+        let mut changes = Vec::new();
+        let filter = (new.filter != old.filter).then(|| STTFilter::new(&new.filter));
+        let pipeline = match new.pipeline != old.pipeline {
+            true => Some(STTPipeline::new(&new.pipeline)?),
+            false => None
+        };
+        let censor = match new.censor != old.censor {
+            true => Some(STTCensor::new(&new.censor)?),
+            false => None
+        };
+        // Device and pre-roll change together so a failure can't leave
+        // one switched and not the other
+        if (&new.input_device, &new.preroll) != (&old.input_device, &old.preroll) {
+            self.recorder.reconfigure(new.input_device.as_deref(), new.preroll.enabled.then_some(new.preroll.length_ms))?;
+            if new.input_device != old.input_device {
+                changes.push(format!("Input device is now {}", self.recorder.device_name));
+            }
+            if new.preroll != old.preroll {
+                changes.push("Updated pre-roll".to_string());
+            }
+        }
+        if new.osc_endpoint != old.osc_endpoint {
+            self.network.set_endpoint(&new)?;
+            changes.push(format!("OSC endpoint is now {}", self.network.osc_endpoint));
+        }
+        if (new.split_messages, new.split_delay_ms) != (old.split_messages, old.split_delay_ms) {
+            self.network.split_messages = new.split_messages;
+            self.network.split_delay = Duration::from_millis(new.split_delay_ms);
+            changes.push("Updated message splitting".to_string());
+        }
+        if new.decode_options() != old.decode_options() {
+            self.processor.options = new.decode_options();
+            changes.push("Updated decode settings".to_string());
+        }
+        if let Some(x) = filter {
+            self.filter = x;
+            changes.push("Updated hallucination filter".to_string());
+        }
+        if let Some(x) = pipeline {
+            self.pipeline = x;
+            changes.push("Updated text pipeline".to_string());
+        }
+        if let Some(x) = censor {
+            self.censor = x;
+            changes.push("Updated content filter".to_string());
+        }
+        if new.history != old.history {
+            self.history.set_config(&new.history_config());
+            changes.push("Updated history settings".to_string());
+        }
+        if new.subtitles != old.subtitles {
+            self.subtitles.set_config(&new.subtitle_config());
+            changes.push("Updated subtitle settings".to_string());
+        }
+        if new.recordings != old.recordings {
+            let pruned = self.recordings.set_config(&new.recordings_config());
+            self.history.forget_audio(&pruned);
+            changes.push("Updated recording settings".to_string());
+        }
+        if (&new.outputs, &new.obs) != (&old.outputs, &old.obs) {
+            self.outputs = STTOutputs::new(&new.outputs, &new.obs, &self.events);
+            changes.push("Updated outputs".to_string());
+        }
+        // These are set up once by whatever runs MelonSTT, so say so rather
+        // than quietly carrying on with the old settings
+        if new.hotkeys != old.hotkeys {
+            changes.push("Hotkey changes take effect after a restart".to_string());
+        }
+        if new.server != old.server {
+            changes.push("Control server changes take effect after a restart".to_string());
+        }
+        if new.ipc != old.ipc {
+            changes.push("Command socket changes take effect after a restart".to_string());
+        }
+        if let Some(x) = Self::model_to_load(old, &new) {
+            changes.push(format!("Loading model {}", new.model));
+            self.model_load = Some(self.spawn_model_load(x));
+        }
+        self.config = new;
+        // Lowering max_entries may have dropped some entries
+        self.delete_trimmed_recordings();
+        debug!("Applied config changes: {:?}", changes);
+        Ok(changes)
+    }
+
+    /// Save the settings currently in use back to the config file, keeping
+    /// its comments.  If a profile is active, anything that differs from
+    /// the top-level settings is saved into that profile instead.
+    pub fn save_config(&mut self) -> Result<(), MelonError> {
+        // Don't save a model that's about to turn out not to load
+        self.settle_model_load(true);
+        let saved = self.base_config.fold_in(&self.config)?;
+        match saved.save(&self.config_path) {
+            Ok(_) => { debug!("Saved config to {}", self.config_path.display()); },
             Err(e) => {
-                error!("Error toggling typing indicator on");
-                return Err(e.into());
+                error!("Error saving config");
+                return Err(e);
             }
         }
+        self.base_config = saved;
+        Ok(())
+    }
+
+    /// Switch to the named profile, or back to the plain top-level settings
+    /// with None.  Only what the profiles actually change gets rebuilt.
+    pub fn set_profile(&mut self, name: Option<&str>) -> Result<Vec<String>, MelonError> {
+        let new = match self.base_config.with_profile(name) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Error switching to profile {:?}", name);
+                return Err(e);
+            }
+        };
+        let changes = self.apply_config(new)?;
+        debug!("Switched to profile {:?}", name);
+        self.events.emit(MelonEvent::ProfileChanged(name.map(|x| x.to_string())));
+        Ok(changes)
+    }
+
+    /// What's going on right now
+    pub fn status(&self) -> MelonStatus {
+        MelonStatus {
+            recording: self.recorder.is_recording(),
+            device: self.recorder.device_name.to_string(),
+            model: self.processor.current_model(),
+            language: self.config.language.to_string(),
+            profile: self.config.profile.clone(),
+            profiles: self.profiles(),
+            latest: self.history.latest().cloned(),
+        }
+    }
+
+    /// Names of the profiles available to set_profile
+    pub fn profiles(&self) -> Vec<String> {
+        self.base_config.profile_names()
+    }
+
+    /// Change some settings on the fly, e.g. from a settings screen.  The
+    /// changed settings are checked and applied just like a config reload,
+    /// and nothing changes if they don't pass.  Use save_config() to keep them.
+    pub fn update_config(&mut self, change: impl FnOnce(&mut STTConfig)) -> Result<Vec<String>, MelonError> {
+        let mut new = self.config.clone();
+        change(&mut new);
+        let issues = self.check_config(|x| *x = new.clone());
+        if !issues.is_empty() {
+            debug!("Rejected config update: {:?}", issues);
+            return Err(MelonError::ConfigInvalid { path: self.config_path.clone(), issues });
+        }
+        self.apply_config(new)
+    }
+
+    /// Check what would be wrong with the settings after a change, without
+    /// applying it.  An empty list means the change is fine.
+    pub fn check_config(&self, change: impl FnOnce(&mut STTConfig)) -> Vec<ConfigIssue> {
+        let mut new = self.config.clone();
+        change(&mut new);
+        new.validate("", new.profile.as_deref())
+    }
+
+    /// Watch the config file and reload it whenever it changes.  The
+    /// watcher thread stops on its own once the MelonSTT is dropped.
+    pub fn watch_config(melon: &Arc<Mutex<MelonSTT>>) -> JoinHandle<()> {
+        let weak = Arc::downgrade(melon);
+        let path = match melon.lock() {
+            Ok(x) => x.config_path.clone(),
+            Err(_) => PathBuf::new()
+        };
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|x| x.modified()).ok();
+        std::thread::spawn(move || {
+            let mut last: Option<SystemTime> = modified(&path);
+            loop {
+                std::thread::sleep(Duration::from_secs(1));
+                let current = modified(&path);
+                if current == last { continue; }
+                last = current;
+                let melon = match weak.upgrade() {
+                    Some(x) => x,
+                    None => break
+                };
+                debug!("Config file {} changed, reloading", path.display());
+                if let Ok(mut x) = melon.lock() {
+                    let _ = x.reload_config();
+                };
+            }
+        })
+    }
+
+    /// List the .bin model files in the configured models directory
+    pub fn available_models(&self) -> Vec<String> {
+        match self.describe_models(false) {
+            Ok(x) => x.into_iter().map(|x| x.file_name).collect(),
+            Err(_) => Vec::new()
+        }
+    }
+
+    /// Describe every model in the configured models directory, optionally
+    /// verifying them against the directory's SHA256SUMS manifest
+    pub fn describe_models(&self, verify: bool) -> Result<Vec<ModelInfo>, MelonError> {
+        let mut retval = models::scan(&self.config.models_dir(), verify)?;
+        for info in retval.iter_mut() {
+            if let Some(x) = info.language_warning(&self.config.language) {
+                warn!("{}", x);
+            }
+        }
+        Ok(retval)
+    }
+
+    /// Load a different Whisper model in the background and swap it in
+    /// once it's ready.  A bare filename is looked for in models_dir.
+    /// If the new model fails to load the current one stays in use.
+    pub fn load_model(&self, path: &str) -> JoinHandle<Result<(), MelonError>> {
+        let path = self.config.model_file(path).to_string_lossy().to_string();
+        debug!("Requesting model load of {}", path);
+        self.spawn_model_load(path)
+    }
+
+    /// Work out what to put in the config for a model.  A bare filename is
+    /// looked for in models_dir; anything else is used as-is.  See
+    /// STTConfig::model_file for where that ends up pointing.
+    pub fn model_path(&self, path: &str) -> String {
+        match &self.config.models_dir {
+            Some(dir) if Path::new(path).components().count() == 1 => {
+                Path::new(dir).join(path).to_string_lossy().to_string()
+            },
+            _ => path.to_string()
+        }
+    }
+
+    /// Check on a model load started by a config change, waiting for it
+    /// if asked to.  If it failed, config.model goes back to the model
+    /// that's actually loaded so it doesn't get saved.
+    pub fn settle_model_load(&mut self, wait: bool) {
+        if !wait && !self.model_load.as_ref().is_some_and(|x| x.is_finished()) { return; }
+        let handle = match self.model_load.take() {
+            Some(x) => x,
+            None => return
+        };
+        if !matches!(handle.join(), Ok(Ok(_))) {
+            let current = self.processor.current_model();
+            warn!("Model {} didn't load, keeping {} in the config", self.config.model, current);
+            self.config.model = current;
+        }
+    }
+
+    /// The model file a config change needs loading, if any.  Nothing
+    /// else in the config needs the model reloaded; language and the like
+    /// are just decode options.
+    fn model_to_load(old: &STTConfig, new: &STTConfig) -> Option<String> {
+        let file = new.model_file(&new.model);
+        (new.model != old.model || file != old.model_file(&old.model)).then(|| file.to_string_lossy().to_string())
+    }
+
+    /// Kick off a background model load and announce how it went
+    fn spawn_model_load(&self, path: String) -> JoinHandle<Result<(), MelonError>> {
+        let handle = self.processor.load_model(path.to_string());
+        let events = self.events.clone();
+        std::thread::spawn(move || {
+            let result = match handle.join() {
+                Ok(x) => x,
+                Err(_) => Err(MelonError::ThreadPanicked("model loading"))
+            };
+            match &result {
+                Ok(_) => events.emit(MelonEvent::ModelLoaded(path)),
+                Err(e) => events.emit(MelonEvent::ModelLoadFailed { model: path, error: e.to_string() }),
+            }
+            result
+        })
+    }
+
+    /// Record audio for the specified number of seconds, then process it
+    /// and hand back the transcribed string.  An empty string means the
+    /// filter decided nothing was actually said.
+    pub fn do_recording(&mut self, seconds: u64) -> Result<String, MelonError> {
+        self.typing(true)?;
+        self.events.emit(MelonEvent::RecordingStarted);
         // Start recording
         match self.recorder.record_audio(seconds) {
             Ok(_) => {
@@ -123,56 +766,533 @@ impl MelonSTT {
             },
             Err(e) => {
                 error!("Error calling do_recording");
-                return Err(e.into());
+                return Err(e);
             }
         }
-        // Toggle typing indicator back off.
-        match self.network.toggle_typing(false) {
-            Ok(_) => { debug!("Toggled typing indicator off"); },
+        self.typing(false)?;
+        self.transcribe()
+    }
+
+    /// Listen until the speaker says something and then goes quiet, using
+    /// the [vad] settings, then process it like do_recording
+    pub fn record_until_silence(&mut self) -> Result<String, MelonError> {
+        self.typing(true)?;
+        self.events.emit(MelonEvent::RecordingStarted);
+        match self.recorder.record_until_silence(&self.config.vad) {
+            Ok(_) => { debug!("Call to record_until_silence succeeded"); },
             Err(e) => {
-                error!("Error toggling typing indicator off");
-                return Err(e.into());
+                error!("Error calling record_until_silence");
+                return Err(e);
             }
         }
-        // Process the recorded audio in self.recorder.audio_data
-        match self.processor.process(&self.recorder.audio_data) {
+        self.typing(false)?;
+        self.transcribe()
+    }
+
+    /// Start recording for push-to-talk.  Recording carries on until
+    /// stop_recording is called.
+    pub fn start_recording(&mut self) -> Result<(), MelonError> {
+        self.typing(true)?;
+        match self.recorder.start() {
             Ok(_) => {
-                debug!("Call to process_audio succeeded");
+                debug!("Push-to-talk recording started");
+                self.events.emit(MelonEvent::RecordingStarted);
             },
             Err(e) => {
-                error!("Error calling process_audio");
-                return Err(e.into());
+                error!("Error starting push-to-talk recording");
+                return Err(e);
             }
         }
-        // Send back the transcription
-        Ok(self.processor.processed_text.to_string())
+        Ok(())
     }
 
-    /// Process the recorded audio in self.recorder.audio_data
-    pub fn process_audio(&mut self) -> Result<(), anyhow::Error> {
-        match self.processor.process(&self.recorder.audio_data) {
+    /// Stop a push-to-talk recording and hand back the transcribed string
+    pub fn stop_recording(&mut self) -> Result<String, MelonError> {
+        self.stop_recorder()?;
+        self.transcribe()
+    }
+
+    /// Stop the recorder, leaving the audio ready to transcribe
+    fn stop_recorder(&mut self) -> Result<(), MelonError> {
+        match self.recorder.stop() {
+            Ok(_) => { debug!("Push-to-talk recording stopped"); },
+            Err(e) => {
+                error!("Error stopping push-to-talk recording");
+                return Err(e);
+            }
+        }
+        self.typing(false)
+    }
+
+    /// Lock a MelonSTT shared between threads
+    fn lock(melon: &Arc<Mutex<MelonSTT>>) -> Result<MutexGuard<'_, MelonSTT>, MelonError> {
+        melon.lock().map_err(|_| MelonError::LockPoisoned("melonstt"))
+    }
+
+    /// Record for a number of seconds, or until silence with None, then
+    /// transcribe it like do_recording.  The lock is only held to start
+    /// and stop the recorder and around Whisper, so anything else using
+    /// the MelonSTT carries on in the meantime.
+    pub fn record_shared(melon: &Arc<Mutex<MelonSTT>>, seconds: Option<u64>) -> Result<String, MelonError> {
+        let (levels, vad) = {
+            let mut melon = Self::lock(melon)?;
+            melon.start_recording()?;
+            (melon.recorder.levels.clone(), melon.config.vad.clone())
+        };
+        match seconds {
+            Some(x) => std::thread::sleep(Duration::from_secs(x)),
+            None => STTRecorder::wait_for_silence(&levels, &vad)
+        }
+        Self::stop_shared(melon)
+    }
+
+    /// Stop a push-to-talk recording and transcribe it like
+    /// stop_recording, without holding the lock while Whisper runs
+    pub fn stop_shared(melon: &Arc<Mutex<MelonSTT>>) -> Result<String, MelonError> {
+        Self::lock(melon)?.stop_recorder()?;
+        Self::transcribe_shared(melon)
+    }
+
+    /// Transcribe the latest recording, only holding the lock to take the
+    /// audio out and put the results back
+    pub fn transcribe_shared(melon: &Arc<Mutex<MelonSTT>>) -> Result<String, MelonError> {
+        let mut pending = Self::lock(melon)?.prepare_transcription();
+        pending.run()?;
+        Self::lock(melon)?.finish_transcription(pending)
+    }
+
+    /// Carry out a command from the control server or command socket, the
+    /// same way the buttons in the window would, and hand back the response
+    /// as JSON.  Recordings and Whisper run without holding the lock.
+    pub fn control_shared(melon: &Arc<Mutex<MelonSTT>>, command: ControlCommand) -> Result<serde_json::Value, MelonError> {
+        debug!("Running control command {:?}", command);
+        match command {
+            ControlCommand::Status => Ok(json!(Self::lock(melon)?.status())),
+            ControlCommand::Record { seconds } => {
+                let text = Self::record_shared(melon, seconds)?;
+                let sent = Self::lock(melon)?.auto_send(&text)?;
+                Ok(json!({ "text": text, "sent": sent }))
+            },
+            ControlCommand::Start => {
+                Self::lock(melon)?.start_recording()?;
+                Ok(json!({ "recording": true }))
+            },
+            ControlCommand::Stop => {
+                let text = Self::stop_shared(melon)?;
+                let sent = Self::lock(melon)?.auto_send(&text)?;
+                Ok(json!({ "text": text, "sent": sent }))
+            },
+            ControlCommand::Cancel => {
+                Self::lock(melon)?.cancel_recording()?;
+                Ok(json!({ "recording": false }))
+            },
+            ControlCommand::Send { text } => {
+                let mut melon = Self::lock(melon)?;
+                // Without any text the latest transcription goes, and gets marked sent
+                let (text, id) = match text {
+                    Some(x) => (x, None),
+                    None => melon.history.latest().map(|x| (x.text.to_string(), Some(x.id))).unwrap_or_default()
+                };
+                Ok(json!(melon.send_to_osc(&text, id)?))
+            },
+            ControlCommand::Latest => Ok(json!(Self::lock(melon)?.history.latest())),
+            ControlCommand::Profile { name } => {
+                let mut melon = Self::lock(melon)?;
+                let changes = melon.set_profile(name.as_deref())?;
+                Ok(json!({ "profile": melon.config.profile, "changes": changes }))
+            },
+        }
+    }
+
+    /// Stop a push-to-talk recording and throw the audio away
+    pub fn cancel_recording(&mut self) -> Result<(), MelonError> {
+        match self.recorder.stop() {
+            Ok(_) => { debug!("Recording cancelled"); },
+            Err(e) => {
+                error!("Error cancelling recording");
+                return Err(e);
+            }
+        }
+        self.recorder.audio_data.clear();
+        self.events.emit(MelonEvent::RecordingCancelled);
+        self.typing(false)
+    }
+
+    /// Do whatever a hotkey (or trigger command) asks for.  Recording is
+    /// started and stopped the same way as push-to-talk, so a toggle or
+    /// push-to-talk recording can be stopped by either key.
+    pub fn handle_hotkey(&mut self, event: HotkeyEvent) -> Result<HotkeyOutcome, MelonError> {
+        debug!("Handling hotkey {:?}", event);
+        let recording = self.recorder.is_recording();
+        match (event.action, event.pressed) {
+            (HotkeyAction::ToggleRecord, true) | (HotkeyAction::PushToTalk, true) if !recording => {
+                self.start_recording()?;
+                Ok(HotkeyOutcome::Started)
+            },
+            (HotkeyAction::ToggleRecord, true) | (HotkeyAction::PushToTalk, false) if recording => {
+                Ok(HotkeyOutcome::Transcribed(self.stop_recording()?))
+            },
+            (HotkeyAction::SendLast, true) => {
+                let id = match self.history.latest() {
+                    Some(x) => x.id,
+                    None => return Ok(HotkeyOutcome::Sent(SendStatus::Empty))
+                };
+                Ok(HotkeyOutcome::Sent(self.resend(id)?))
+            },
+            (HotkeyAction::Cancel, true) if recording => {
+                self.cancel_recording()?;
+                Ok(HotkeyOutcome::Cancelled)
+            },
+            _ => Ok(HotkeyOutcome::Nothing)
+        }
+    }
+
+    /// Like handle_hotkey, but a hotkey that stops a recording lets go of
+    /// the lock while Whisper runs
+    pub fn hotkey_shared(melon: &Arc<Mutex<MelonSTT>>, event: HotkeyEvent) -> Result<HotkeyOutcome, MelonError> {
+        let stopping = {
+            let melon = Self::lock(melon)?;
+            let stop = matches!((event.action, event.pressed), (HotkeyAction::ToggleRecord, true) | (HotkeyAction::PushToTalk, false));
+            stop && melon.recorder.is_recording()
+        };
+        match stopping {
+            true => {
+                debug!("Handling hotkey {:?}", event);
+                Ok(HotkeyOutcome::Transcribed(Self::stop_shared(melon)?))
+            },
+            false => Self::lock(melon)?.handle_hotkey(event)
+        }
+    }
+
+    /// Toggle the typing indicator on or off.  It might be overkill to
+    /// return Err if it fails but it's more likely than not if toggle
+    /// fails then send will fail.
+    fn typing(&self, on: bool) -> Result<(), MelonError> {
+        // No point if the chatbox isn't one of the outputs
+        if !self.outputs.osc { return Ok(()); }
+        match self.network.toggle_typing(on) {
             Ok(_) => {
-                debug!("Call to process_audio succeeded");
+                debug!("Toggled typing indicator {}", if on { "on" } else { "off" });
+                Ok(())
             },
             Err(e) => {
-                error!("Error calling process_audio");
-                return Err(e.into());
+                error!("Error toggling typing indicator");
+                Err(e)
             }
         }
+    }
+
+    /// Process the recorded audio and run it through the filter and
+    /// pipeline.  An empty string means nothing was actually said.
+    fn transcribe(&mut self) -> Result<String, MelonError> {
+        let mut pending = self.prepare_transcription();
+        pending.run()?;
+        self.finish_transcription(pending)
+    }
+
+    /// Take the latest recording out, ready to run through Whisper
+    pub fn prepare_transcription(&self) -> PendingTranscription {
+        PendingTranscription {
+            processor: self.processor.with_options(self.processor.options.clone()),
+            audio: self.recorder.audio_data.clone(),
+            raw_audio: self.recorder.raw_audio.clone(),
+            started: self.recorder.started,
+        }
+    }
+
+    /// Run what Whisper made of a recording through the filter and
+    /// pipeline, and into the history, subtitles and recordings.  An
+    /// empty string means nothing was actually said.
+    pub fn finish_transcription(&mut self, done: PendingTranscription) -> Result<String, MelonError> {
+        let PendingTranscription { processor, audio, raw_audio, started } = done;
+        // Keep the latest results where they've always been
+        self.processor.segments = processor.segments.clone();
+        self.processor.processed_text = processor.processed_text.to_string();
+        // Throw out any hallucinations and tidy up what's left
+        let filtered = self.filter.filter(&processor.segments);
+        debug!("Filtered '{}' down to '{}'", processor.processed_text, filtered);
+        if filtered.is_empty() {
+            // Still worth keeping the audio, to see what Whisper made of it
+            self.save_recording(None, raw_audio.as_ref(), &audio);
+            self.events.emit(MelonEvent::Transcribed(filtered.to_string()));
+            return Ok(filtered);
+        }
+        let text = self.pipeline.apply(&filtered);
+        self.write_subtitles(&processor.segments, started, audio.len());
+        let entry = HistoryEntry {
+            id: 0,
+            timestamp: chrono::Local::now(),
+            duration: audio.len() as f32 / 16_000.0,
+            model: processor.current_model(),
+            language: processor.options.language.to_string(),
+            raw_text: processor.processed_text.to_string(),
+            text: text.to_string(),
+            sent: false,
+            audio: None,
+            raw_audio: None,
+        };
+        let id = self.history.add(entry);
+        self.delete_trimmed_recordings();
+        self.save_recording(id, raw_audio.as_ref(), &audio);
+        if id.is_some() {
+            self.events.emit(MelonEvent::HistoryChanged);
+        }
+        self.events.emit(MelonEvent::Transcribed(text.to_string()));
+        Ok(text)
+    }
+
+    /// Caption a transcription in the subtitle files, one cue per segment
+    /// Whisper came up with.  Failing to write them is logged but doesn't
+    /// stop the transcription going through.
+    fn write_subtitles(&mut self, segments: &[processor::STTSegment], started: Option<chrono::DateTime<chrono::Local>>, samples: usize) {
+        if !self.subtitles.config.enabled { return; }
+        let started = match started {
+            Some(x) => x,
+            None => chrono::Local::now() - chrono::TimeDelta::milliseconds(samples as i64 / 16)
+        };
+        let mut cues = Vec::new();
+        for segment in self.filter.kept_segments(segments) {
+            let text = self.pipeline.apply(&self.filter.filter_text(&segment.text));
+            let text = match self.censor.check(&text) {
+                Verdict::Clean(x) | Verdict::Masked(x) => x,
+                Verdict::Blocked(_) => continue
+            };
+            if text.is_empty() { continue; }
+            // Segment times are in centiseconds from the start of the recording
+            cues.push(Cue {
+                start: started + chrono::TimeDelta::milliseconds(segment.t0 * 10),
+                end: started + chrono::TimeDelta::milliseconds(segment.t1 * 10),
+                text,
+            });
+        }
+        if let Err(e) = self.subtitles.write(&cues) {
+            error!("Unable to write subtitles: {}", e);
+        }
+    }
+
+    /// Save a recording, if recordings are being kept, and note where it
+    /// went on its history entry.  Like the subtitles, failing to save it
+    /// doesn't stop the transcription going through.
+    fn save_recording(&mut self, id: Option<u64>, raw: Option<&RawAudio>, converted: &[f32]) {
+        if !self.recordings.config.enabled { return; }
+        match self.recordings.save(id, raw, converted) {
+            Ok(x) => {
+                if let Some(id) = id {
+                    self.history.set_audio(id, x.converted, x.raw);
+                }
+                self.history.forget_audio(&x.pruned);
+            },
+            Err(e) => { error!("Unable to save recording: {}", e); }
+        }
+    }
+
+    /// Delete the recordings of history entries dropped to stay within
+    /// max_entries, since nothing can get at them any more
+    fn delete_trimmed_recordings(&mut self) {
+        let trimmed = self.history.take_trimmed();
+        let paths: Vec<&Path> = trimmed.iter()
+            .flat_map(|x| [x.audio.as_deref(), x.raw_audio.as_deref()])
+            .flatten()
+            .collect();
+        if !paths.is_empty() {
+            self.recordings.remove(&paths);
+        }
+    }
+
+    /// Start new subtitle files for everything said from now on
+    pub fn new_subtitle_session(&mut self) {
+        self.subtitles.new_session();
+    }
+
+    /// Send an entry from the history to the chatbox again
+    pub fn resend(&mut self, id: u64) -> Result<SendStatus, MelonError> {
+        let text = match self.history.get(id) {
+            Some(x) => x.text.to_string(),
+            None => return Err(MelonError::UnknownEntry(id))
+        };
+        let result = self.send_text(&text)?;
+        if let SendStatus::Sent(_) = result {
+            self.history.mark_sent(id);
+            self.events.emit(MelonEvent::HistoryChanged);
+        }
+        Ok(result)
+    }
+
+    /// Run a history entry's saved recording through Whisper again with
+    /// some settings changed, to see how they compare.  The result goes
+    /// through the filter and pipeline like a normal transcription but
+    /// isn't added to the history or sent anywhere.  A different model is
+    /// loaded just for this run, so that takes a bit longer.
+    pub fn retranscribe(&self, id: u64, options: &RetranscribeOptions) -> Result<Retranscription, MelonError> {
+        self.prepare_retranscription(id, options)?.run()
+    }
+
+    /// Like retranscribe, but only holding the lock long enough to look
+    /// up the entry, so loading another model doesn't hold anything up
+    pub fn retranscribe_shared(melon: &Arc<Mutex<MelonSTT>>, id: u64, options: &RetranscribeOptions) -> Result<Retranscription, MelonError> {
+        let pending = Self::lock(melon)?.prepare_retranscription(id, options)?;
+        pending.run()
+    }
+
+    /// Gather up everything needed to retranscribe an entry
+    pub fn prepare_retranscription(&self, id: u64, options: &RetranscribeOptions) -> Result<PendingRetranscription, MelonError> {
+        let recording = self.history.recording(id)?;
+        let decode = options.decode_options(self.config.decode_options());
+        let model = options.model_file(&self.config, &self.processor.current_model());
+        Ok(PendingRetranscription {
+            id,
+            recording,
+            model,
+            processor: self.processor.with_options(decode),
+            filter: self.config.filter.clone(),
+            pipeline: self.config.pipeline.clone(),
+        })
+    }
+
+    /// Delete an entry from the history, along with its saved recording
+    pub fn delete_history(&mut self, id: u64) -> Result<(), MelonError> {
+        let audio = match self.history.get(id) {
+            Some(x) => [x.audio.clone(), x.raw_audio.clone()],
+            None => return Err(MelonError::UnknownEntry(id))
+        };
+        self.history.remove(id);
+        let paths: Vec<&Path> = audio.iter().flatten().map(|x| x.as_path()).collect();
+        self.recordings.remove(&paths);
+        self.events.emit(MelonEvent::HistoryChanged);
         Ok(())
     }
 
-    /// Send data to VRChat via OSC
-    pub fn send_to_osc(&self, data: &str) -> Result<(), anyhow::Error> { 
-        match self.network.send_to_osc(data) {
+    /// Process the recorded audio in self.recorder.audio_data
+    pub fn process_audio(&mut self) -> Result<(), MelonError> {
+        match self.processor.process(&self.recorder.audio_data) {
             Ok(_) => {
-                debug!("Call to send_to_osc succeeded");
+                debug!("Call to process_audio succeeded");
             },
             Err(e) => {
-                error!("Error calling send_to_osc");
-                return Err(e.into());
+                error!("Error calling process_audio");
+                return Err(e);
             }
         }
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Send data to VRChat via OSC, after running it past the content filter.
+    /// `entry` is the history entry the text came from (possibly after some
+    /// editing), if it came from one, and gets marked as sent.
+    pub fn send_to_osc(&mut self, data: &str, entry: Option<u64>) -> Result<SendStatus, MelonError> {
+        let result = self.send_text(data)?;
+        let entry = entry.filter(|x| self.history.get(*x).is_some_and(|x| !x.sent));
+        if let (SendStatus::Sent(_), Some(id)) = (&result, entry) {
+            self.history.mark_sent(id);
+            self.events.emit(MelonEvent::HistoryChanged);
+        }
+        Ok(result)
+    }
+
+    /// Send a fresh transcription on if auto_send is turned on.  Hands back
+    /// how the send went, or None if it wasn't sent.
+    pub fn auto_send(&mut self, text: &str) -> Result<Option<SendStatus>, MelonError> {
+        // It's only just been added to the history, if it was added at all
+        let entry = self.history.latest().filter(|x| x.text == text).map(|x| x.id);
+        match self.config.auto_send && !text.is_empty() {
+            true => Ok(Some(self.send_to_osc(text, entry)?)),
+            false => Ok(None)
+        }
+    }
+
+    /// Run some text past the content filter and send it
+    fn send_text(&self, data: &str) -> Result<SendStatus, MelonError> {
+        // Don't bother VRChat with an empty chatbox message
+        if data.trim().is_empty() {
+            debug!("Nothing to send, skipping send_to_osc");
+            return Ok(SendStatus::Empty);
+        }
+        let data = match self.censor.check(data) {
+            Verdict::Clean(x) | Verdict::Masked(x) => x,
+            Verdict::Blocked(reason) => {
+                debug!("Content filter blocked message: {}", reason);
+                return Ok(SendStatus::Blocked(reason));
+            }
+        };
+        if data.is_empty() { return Ok(SendStatus::Empty); }
+        // Everything else goes out in the background first, so a chatbox
+        // that can't be reached doesn't stop the rest getting it
+        self.outputs.send(&data);
+        if self.outputs.osc {
+            match self.network.send_to_osc(&data) {
+                Ok(_) => {
+                    debug!("Call to send_to_osc succeeded");
+                },
+                Err(e) => {
+                    error!("Error calling send_to_osc");
+                    return Err(e);
+                }
+            }
+        }
+        self.events.emit(MelonEvent::Sent(data.to_string()));
+        Ok(SendStatus::Sent(data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn model_only_reloads_when_it_changes() {
+        let old = STTConfig::default();
+        let mut new = old.clone();
+        new.language = "ja".to_string();
+        new.osc_endpoint = Some("127.0.0.1:9001".to_string());
+        new.vad.silence_ms += 100;
+        assert_eq!(MelonSTT::model_to_load(&old, &new), None);
+        // The language still gets through, as a decode option
+        assert_ne!(old.decode_options(), new.decode_options());
+
+        new.model = "ggml-base.bin".to_string();
+        let file = MelonSTT::model_to_load(&old, &new).unwrap();
+        assert!(file.ends_with("ggml-base.bin"), "{}", file);
+    }
+
+    #[test]
+    fn retranscribe_options_swap_in_what_is_set() {
+        let mut config = STTConfig::default();
+        config.prompt = Some("Melondog".to_string());
+        config.threads = 2;
+        let base = config.decode_options();
+        assert_eq!(RetranscribeOptions::default().decode_options(base.clone()), base);
+
+        let options = RetranscribeOptions { language: Some("ja".to_string()), beam_size: Some(5), ..Default::default() };
+        let decode = options.decode_options(base.clone());
+        assert_eq!((decode.language.as_str(), decode.beam_size), ("ja", 5));
+        assert_eq!((decode.prompt.as_deref(), decode.threads), (Some("Melondog"), 2));
+
+        let options = RetranscribeOptions { prompt: Some("Melon".to_string()), ..Default::default() };
+        assert_eq!(options.decode_options(base.clone()).prompt.as_deref(), Some("Melon"));
+        // An empty prompt turns the prompt off rather than leaving it alone
+        let options = RetranscribeOptions { prompt: Some(String::new()), ..Default::default() };
+        assert_eq!(options.decode_options(base).prompt, None);
+    }
+
+    #[test]
+    fn retranscribe_only_loads_a_different_model() {
+        let mut config = STTConfig::default();
+        config.models_dir = Some("models".to_string());
+        let current = config.model_file(&config.model).to_string_lossy().to_string();
+        assert_eq!(RetranscribeOptions::default().model_file(&config, &current), None);
+        let same = RetranscribeOptions { model: Some(config.model.clone()), ..Default::default() };
+        assert_eq!(same.model_file(&config, &current), None);
+        let other = RetranscribeOptions { model: Some("ggml-base.bin".to_string()), ..Default::default() };
+        let file = other.model_file(&config, &current).unwrap();
+        assert_eq!(PathBuf::from(file), PathBuf::from("models").join("ggml-base.bin"));
+    }
+
+    #[test]
+    fn moving_models_dir_reloads_the_model() {
+        let old = STTConfig::default();
+        let mut new = old.clone();
+        new.models_dir = Some("models".to_string());
+        let file = MelonSTT::model_to_load(&old, &new).unwrap();
+        assert_eq!(PathBuf::from(file), PathBuf::from("models").join(&old.model));
+    }
+}
@@ -1,82 +1,779 @@
 use std::str::FromStr;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use log::{debug, error};
-use melonstt::MelonSTT;
-use std::sync::Mutex;
-use lazy_static::lazy_static;
+use melonstt::{HotkeyOutcome, MelonError, MelonSTT, RetranscribeOptions, Retranscription, SendStatus, STTConfig};
+use melonstt::events::MelonEvent;
+use melonstt::recorder::{RecordMode, STTRecorder};
+use melonstt::network::CHATBOX_LIMIT;
+use melonstt::history::ExportFormat;
+use melonstt::hotkeys::{HotkeyConfig, STTHotkeys};
+use std::sync::{Arc, Mutex};
 
 slint::include_modules!();
 
-lazy_static! {
-    static ref MELON: Mutex<MelonSTT> = {
-        let melon = MelonSTT::new("melon.toml").unwrap();
-        Mutex::new(melon)
-    };
-}
-
 fn main() -> Result<(), anyhow::Error> {
     // Init EnvLogger
     env_logger::init();
 
-    // Init MelonSTT struct using specified config filename
-/*     let mut melon = match MelonSTT::new("melon.toml") {
+    // Pull out '--config <file>', leaving any subcommand behind
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let mut config_arg = None;
+    if let Some(i) = args.iter().position(|x| x == "--config" || x == "-c") {
+        if i + 1 < args.len() {
+            config_arg = Some(args.remove(i + 1));
+        }
+        args.remove(i);
+    }
+
+    // 'melonstt models [dir]' lists and verifies models instead of starting the UI.
+    // Without a dir it looks in models_dir, warning about models that don't
+    // suit the configured language.
+    if args.first().map(|x| x.as_str()) == Some("models") {
+        let config = STTConfig::discover(config_arg.as_deref())
+            .and_then(|x| STTConfig::load(&x))
+            .unwrap_or_default();
+        let dir = match args.get(1) {
+            Some(x) => x.to_string(),
+            None => config.models_dir().to_string_lossy().to_string()
+        };
+        for info in melonstt::models::scan(std::path::Path::new(&dir), true)? {
+            println!("{}", info.describe());
+            if let Some(x) = info.language_warning(&config.language) {
+                println!("  warning: {}", x);
+            }
+        }
+        return Ok(());
+    }
+
+    // 'melonstt trigger <action> [down|up]' pokes a running MelonSTT, for
+    // binding to keys in whatever can't use the global hotkeys
+    if args.first().map(|x| x.as_str()) == Some("trigger") {
+        let port = STTConfig::discover(config_arg.as_deref())
+            .and_then(|x| STTConfig::load(&x))
+            .map(|x| x.hotkeys)
+            .unwrap_or_default()
+            .trigger_port
+            .or(HotkeyConfig::default().trigger_port)
+            .unwrap_or_default();
+        melonstt::hotkeys::send_trigger(port, &args[1..].join(" "))?;
+        return Ok(());
+    }
+
+    // Init MelonSTT struct using whichever config file we can find.  If
+    // that doesn't work, say why in a dialog rather than just vanishing.
+    let melon = match STTConfig::discover(config_arg.as_deref())
+        .and_then(|x| MelonSTT::new(&x.to_string_lossy())) {
         Ok(x) => {
             debug!("initialized melonstt ok");
-            x
+            Arc::new(Mutex::new(x))
         },
         Err(e) => {
-            error!("Error initializing melonstt");
+            error!("Error initializing melonstt: {}", e);
+            let dialog = ErrorDialog::new()?;
+            dialog.set_message(e.to_string().into());
+            let dialog_weak = dialog.as_weak();
+            dialog.on_dismiss(move || {
+                if let Some(x) = dialog_weak.upgrade() { let _ = x.hide(); }
+            });
+            let _ = dialog.run();
             return Err(e.into());
         }
     };
-    let device_name = melon.recorder.device_name.to_string();
- */
+
     // Init AppWindow
     let ui = AppWindow::new()?;
     ui.window().on_close_requested(move || { std::process::exit(0); });
 
-    // Create clones of the AppWindow for callback functions
+    // Create clones of the AppWindow and MelonSTT for callback functions
     let ui2 = ui.clone_strong();
     let ui3 = ui.clone_strong();
+    let ui_weak = ui.as_weak();
+    let melon2 = melon.clone();
+    let melon3 = melon.clone();
+    let melon4 = melon.clone();
+    let melon6 = melon.clone();
+    let melon7 = melon.clone();
+    let melon8 = melon.clone();
+    let melon9 = melon.clone();
 
-    // Handle the OSC send button being pressed
-    ui.global::<Logic>().on_send_to_osc(move |value| {
-            debug!("Sending {} to OSC sender function", &value);
-            match MELON.lock().unwrap().send_to_osc(&value) {
+    // Handle a different model being picked.  Loading happens in the background
+    // and the result shows up as an event.
+    ui.global::<Logic>().on_load_model(move |model| {
+        debug!("Loading model {}", &model);
+        let mut melon = melon4.lock().unwrap();
+        let path = melon.model_path(&model);
+        let result = melon.update_config(move |x| x.model = path);
+        if let Some(ui) = ui_weak.upgrade() {
+            match result {
                 Ok(_) => {
-                    ui3.set_status_text("Sent transcribed text to OSC".into());
+                    ui.set_model_error("".into());
+                    ui.set_status_text(format!("Loading model {}...", model).into());
                 },
                 Err(e) => {
-                    ui3.set_status_text(format!("Error sending to OSC: {:?}", e).into());
+                    ui.set_model_error(setting_error(&e).into());
+                    ui.set_current_model(model_name(&melon.processor.current_model()).into());
+                }
+            }
+        }
+    });
+
+    // Check a setting as it's being typed, without applying it
+    ui.global::<Logic>().on_check_setting(move |key, value| {
+        let change = match setting(&key, &value) {
+            Ok(x) => x,
+            Err(e) => return e.into()
+        };
+        let issues = melon7.lock().unwrap().check_config(change);
+        issues.iter().map(|x| x.message.to_string()).collect::<Vec<_>>().join("; ").into()
+    });
+
+    // Apply a setting straight away once it's been picked or entered
+    let ui_weak = ui.as_weak();
+    ui.global::<Logic>().on_apply_setting(move |key, value| {
+        debug!("Changing setting {} to {}", &key, &value);
+        let change = match setting(&key, &value) {
+            Ok(x) => x,
+            Err(e) => return e.into()
+        };
+        let result = melon8.lock().unwrap().update_config(change);
+        match result {
+            Ok(changes) => {
+                if let Some(ui) = ui_weak.upgrade() {
+                    let status = match changes.is_empty() {
+                        true => "Settings updated".to_string(),
+                        false => format!("Settings updated: {}", changes.join(", "))
+                    };
+                    ui.set_status_text(status.into());
                 }
+                "".into()
+            },
+            Err(e) => setting_error(&e).into()
+        }
+    });
+
+    // Write the current settings back to the config file
+    let ui_weak = ui.as_weak();
+    ui.global::<Logic>().on_save_settings(move || {
+        let result = melon9.lock().unwrap().save_config();
+        if let Some(ui) = ui_weak.upgrade() {
+            match result {
+                Ok(_) => ui.set_status_text("Settings saved".into()),
+                Err(e) => ui.set_status_text(format!("Couldn't save settings: {}", e).into())
             }
+        }
+    });
+
+    // Handle a different profile being picked
+    let ui_weak = ui.as_weak();
+    ui.global::<Logic>().on_set_profile(move |index| {
+        let mut melon = melon6.lock().unwrap();
+        // The first entry in the picker is no profile at all
+        let name = usize::try_from(index - 1).ok().and_then(|x| melon.profiles().get(x).cloned());
+        debug!("Switching to profile {:?}", name);
+        if let Err(e) = melon.set_profile(name.as_deref()) {
+            if let Some(ui) = ui_weak.upgrade() {
+                ui.set_current_profile(profile_index(&melon, melon.config.profile.as_deref()));
+                ui.set_status_text(format!("Couldn't switch profile: {}", e).into());
+            }
+        }
+    });
+
+    // Reload the config whenever it changes, and report anything that
+    // happens in the background on the status line
+    MelonSTT::watch_config(&melon);
+
+    // Start the control server, if it's turned on
+    let server_config = melon.lock().unwrap().config.server.clone();
+    if server_config.enabled {
+        if let Err(e) = melonstt::server::spawn(&melon, &server_config) {
+            error!("Error starting control server: {}", e);
+        }
+    }
+    let ipc_config = melon.lock().unwrap().config.ipc.clone();
+    if ipc_config.enabled {
+        if let Err(e) = melonstt::ipc::spawn(&melon, &ipc_config) {
+            error!("Error starting command socket: {}", e);
+        }
+    }
+    let events = melon.lock().unwrap().subscribe();
+    let ui_weak = ui.as_weak();
+    let melon5 = melon.clone();
+    std::thread::spawn(move || {
+        for event in events {
+            let melon = melon5.clone();
+            let _ = ui_weak.upgrade_in_event_loop(move |ui| {
+                // History changes just refresh the list, no need for a status
+                if event == MelonEvent::HistoryChanged {
+                    fill_history(&ui, &melon.lock().unwrap());
+                    return;
+                }
+                let status = match event {
+                    MelonEvent::ConfigReloaded(x) => {
+                        // The list of profiles (or the active one) may have changed too
+                        let melon = melon.lock().unwrap();
+                        fill_profiles(&ui, &melon);
+                        fill_settings(&ui, &melon);
+                        match x.is_empty() {
+                            true => "Config reloaded, nothing changed".to_string(),
+                            false => format!("Config reloaded: {}", x.join(", "))
+                        }
+                    },
+                    MelonEvent::ProfileChanged(x) => {
+                        let melon = melon.lock().unwrap();
+                        ui.set_current_profile(profile_index(&melon, x.as_deref()));
+                        fill_settings(&ui, &melon);
+                        format!("Switched to {}", x.map(|x| format!("profile {}", x)).unwrap_or("default settings".to_string()))
+                    },
+                    MelonEvent::ConfigError(x) => format!("Config not reloaded: {}", x),
+                    MelonEvent::ModelLoaded(x) => {
+                        ui.set_current_model(model_name(&x).into());
+                        format!("Loaded model {}", x)
+                    },
+                    MelonEvent::ModelLoadFailed { model, error } => {
+                        // Put the config and picker back to whatever is still loaded
+                        let mut melon = melon.lock().unwrap();
+                        melon.settle_model_load(true);
+                        ui.set_current_model(model_name(&melon.processor.current_model()).into());
+                        fill_settings(&ui, &melon);
+                        format!("Error loading model {}: {}", model, error)
+                    },
+                    // Recordings and sends started from somewhere else (hotkeys,
+                    // the control server) should still show up in the window.
+                    // The window's own ones already did all this, so it's
+                    // harmless for them.
+                    MelonEvent::RecordingStarted => {
+                        ui.set_stt_entry(-1);
+                        if !ui.get_recording() {
+                            ui.set_recording(true);
+                            ui.set_stt_text("RECORDING...".into());
+                        }
+                        return;
+                    },
+                    MelonEvent::RecordingCancelled => {
+                        ui.set_recording(false);
+                        ui.set_stt_text("".into());
+                        "Recording cancelled".to_string()
+                    },
+                    MelonEvent::Transcribed(x) => {
+                        ui.set_recording(false);
+                        ui.set_stt_text(x.as_str().into());
+                        let melon = melon.lock().unwrap();
+                        ui.set_stt_entry(latest_entry(&melon, &x));
+                        show_message_info(&ui, &melon, &x);
+                        return;
+                    },
+                    MelonEvent::Sent(_) => "Sent transcribed text to OSC".to_string(),
+                    MelonEvent::OutputFailed { output, error } => format!("Couldn't send to {}: {}", output, error),
+                    MelonEvent::HistoryChanged => return,
+                };
+                ui.set_status_text(status.into());
+            });
+        }
+    });
+
+    // Keep the character counter up to date as the transcription is edited
+    let ui_weak = ui.as_weak();
+    let melon13 = melon.clone();
+    ui.global::<Logic>().on_message_edited(move |value| {
+        if let Some(ui) = ui_weak.upgrade() {
+            show_message_info(&ui, &melon13.lock().unwrap(), &value);
+        }
+    });
+
+    // Handle the OSC send button being pressed
+    ui.global::<Logic>().on_send_to_osc(move |value| {
+            debug!("Sending {} to OSC sender function", &value);
+            let entry = u64::try_from(ui3.get_stt_entry()).ok();
+            send_text(&ui3, &mut melon3.lock().unwrap(), &value, entry);
     });
 
     // Handle a record button being pressed
+    // Recording happens on another thread so the level meter keeps moving
     ui.global::<Logic>().on_do_recording(move |len| { 
         let length = u64::from_str(&len).unwrap_or(3);
-        let now = Instant::now();
         ui2.set_stt_text("RECORDING...".into());
         println!("Calling do_recording with length {}", len);
-        match MELON.lock().unwrap().do_recording(length) {
-            Ok(transcription) => {
-                ui2.set_stt_text(transcription.into());
-                ui2.set_status_text(format!("Processing complete.  Took {:.2?} seconds", now.elapsed()).into());
-                debug!("do_recording completed successfully");
+        record_in_background(&ui2, &melon2, move |x| MelonSTT::record_shared(x, Some(length)));
+    });
+
+    // Handle the VAD listen button being pressed
+    let ui4 = ui.clone_strong();
+    let melon10 = melon.clone();
+    ui.global::<Logic>().on_record_until_silence(move || {
+        ui4.set_stt_text("LISTENING...".into());
+        record_in_background(&ui4, &melon10, |x| MelonSTT::record_shared(x, None));
+    });
+
+    // Drive the level meter, and warn about clipping or a dead input
+    let levels = melon.lock().unwrap().subscribe_levels();
+    let ui_weak = ui.as_weak();
+    std::thread::spawn(move || {
+        let mut last_clip: Option<Instant> = None;
+        let mut silent_since: Option<Instant> = None;
+        for level in levels {
+            if level.is_clipping() { last_clip = Some(Instant::now()); }
+            silent_since = match level.is_silent() {
+                true => silent_since.or(Some(Instant::now())),
+                false => None
+            };
+            let clipping = last_clip.is_some_and(|x| x.elapsed() < Duration::from_secs(1));
+            let no_signal = silent_since.is_some_and(|x| x.elapsed() > Duration::from_millis(1500));
+            let _ = ui_weak.upgrade_in_event_loop(move |ui| {
+                ui.set_input_level(level.meter());
+                ui.set_input_peak(level.peak_meter());
+                ui.set_clipping(clipping);
+                ui.set_no_signal(no_signal);
+            });
+        }
+    });
+
+    // Handle the push-to-talk button being held down and let go
+    let ui5 = ui.clone_strong();
+    let melon11 = melon.clone();
+    ui.global::<Logic>().on_start_recording(move || {
+        match melon11.lock().unwrap().start_recording() {
+            Ok(_) => {
+                ui5.set_recording(true);
+                ui5.set_stt_text("RECORDING...".into());
             },
             Err(e) => {
-                error!("do_recording returned an error: {:?}", e);
-                ui2.set_stt_text(e.to_string().into());
-                ui2.set_status_text("ERROR!".into());
+                error!("start_recording returned an error: {:?}", e);
+                ui5.set_status_text(format!("Couldn't start recording: {}", e).into());
+            }
+        }
+    });
+    let ui6 = ui.clone_strong();
+    let melon12 = melon.clone();
+    ui.global::<Logic>().on_stop_recording(move || {
+        if !ui6.get_recording() { return; }
+        ui6.set_recording(false);
+        // Whisper takes a while, so it runs off the UI thread
+        transcribe_in_background(&ui6, &melon12, MelonSTT::stop_shared);
+    });
+
+    // Handle the buttons on the history tab
+    let ui7 = ui.clone_strong();
+    let melon14 = melon.clone();
+    ui.global::<Logic>().on_resend_history(move |id| {
+        let result = melon14.lock().unwrap().resend(id as u64);
+        show_send_result(&ui7, result);
+    });
+    let ui8 = ui.clone_strong();
+    let melon15 = melon.clone();
+    let mut clipboard = arboard::Clipboard::new();
+    ui.global::<Logic>().on_copy_history(move |id| {
+        let text = match melon15.lock().unwrap().history.get(id as u64) {
+            Some(x) => x.text.to_string(),
+            None => return
+        };
+        let result = match clipboard.as_mut() {
+            Ok(x) => x.set_text(text),
+            Err(e) => Err(arboard::Error::Unknown { description: e.to_string() })
+        };
+        match result {
+            Ok(_) => ui8.set_status_text("Copied to clipboard".into()),
+            Err(e) => ui8.set_status_text(format!("Couldn't copy to clipboard: {}", e).into())
+        }
+    });
+    let ui9 = ui.clone_strong();
+    let melon16 = melon.clone();
+    ui.global::<Logic>().on_delete_history(move |id| {
+        if let Err(e) = melon16.lock().unwrap().delete_history(id as u64) {
+            ui9.set_status_text(e.to_string().into());
+        }
+    });
+    let ui10 = ui.clone_strong();
+    let melon17 = melon.clone();
+    ui.global::<Logic>().on_export_history(move |format| {
+        let format = match format.as_str() {
+            "csv" => ExportFormat::Csv,
+            "text" => ExportFormat::Text,
+            _ => ExportFormat::Json
+        };
+        // Exports go next to the config file
+        let melon = melon17.lock().unwrap();
+        let dir = melon.config_path.parent().map(|x| x.to_path_buf()).unwrap_or_default();
+        let path = dir.join(format!("melonstt-history.{}", format.extension()));
+        match melon.history.export_to(&path, format) {
+            Ok(_) => ui10.set_status_text(format!("Exported history to {}", path.display()).into()),
+            Err(e) => ui10.set_status_text(format!("Couldn't export history: {}", e).into())
+        }
+    });
+
+    // Compare an entry's recording run through again with other settings,
+    // starting with what it came out as the first time
+    let ui11 = ui.clone_strong();
+    let melon19 = melon.clone();
+    ui.global::<Logic>().on_compare_history(move |id| {
+        let melon = melon19.lock().unwrap();
+        let entry = match melon.history.get(id as u64) {
+            Some(x) => x,
+            None => return
+        };
+        let original = Comparison {
+            settings: format!("Original: {}, {}", model_name(&entry.model), entry.language).into(),
+            text: entry.text.as_str().into(),
+        };
+        ui11.set_comparisons(std::rc::Rc::new(slint::VecModel::from(vec![original])).into());
+        ui11.set_compare_model(model_name(&melon.processor.current_model()).into());
+        ui11.set_compare_language(melon.config.language.as_str().into());
+        ui11.set_compare_prompt(melon.config.prompt.as_deref().unwrap_or_default().into());
+        ui11.set_compare_beam(melon.config.beam_size.to_string().into());
+        ui11.set_compare_id(id);
+    });
+    let ui12 = ui.clone_strong();
+    let melon20 = melon.clone();
+    ui.global::<Logic>().on_retranscribe(move |id, model, language, prompt, beam| {
+        let beam_size = match beam.trim() {
+            "" => None,
+            x => match i32::from_str(x) {
+                Ok(x) => Some(x),
+                Err(_) => {
+                    ui12.set_status_text(format!("'{}' isn't a beam size", x).into());
+                    return;
+                }
             }
         };
+        let options = RetranscribeOptions {
+            model: Some(model.to_string()).filter(|x| !x.is_empty()),
+            language: Some(language.trim().to_string()).filter(|x| !x.is_empty()),
+            prompt: Some(prompt.to_string()),
+            beam_size,
+        };
+        ui12.set_comparing(true);
+        ui12.set_status_text("Running the recording through again...".into());
+        // Loading another model and transcribing takes a while, so it
+        // happens off the UI thread
+        let ui_weak = ui12.as_weak();
+        let melon = melon20.clone();
+        std::thread::spawn(move || {
+            let result = MelonSTT::retranscribe_shared(&melon, id as u64, &options);
+            let _ = ui_weak.upgrade_in_event_loop(move |ui| {
+                ui.set_comparing(false);
+                show_comparison(&ui, id, result);
+            });
+        });
+    });
+
+    // Start listening for global hotkeys.  This has to happen on this
+    // thread, and the result has to stay alive for as long as the UI runs.
+    let hotkey_config = melon.lock().unwrap().config.hotkeys.clone();
+    let (hotkeys, hotkey_events) = match STTHotkeys::new(&hotkey_config) {
+        Ok((x, y)) => (Some(x), Some(y)),
+        Err(e) => {
+            error!("Error setting up hotkeys: {}", e);
+            ui.set_status_text(format!("Hotkeys unavailable: {}", e).into());
+            (None, None)
+        }
+    };
+    let ui_weak = ui.as_weak();
+    let melon18 = melon.clone();
+    std::thread::spawn(move || {
+        for event in hotkey_events.into_iter().flatten() {
+            let now = Instant::now();
+            let result = MelonSTT::hotkey_shared(&melon18, event);
+            let melon = melon18.clone();
+            let _ = ui_weak.upgrade_in_event_loop(move |ui| {
+                match result {
+                    Ok(HotkeyOutcome::Started) => {
+                        ui.set_recording(true);
+                        ui.set_stt_text("RECORDING...".into());
+                    },
+                    Ok(HotkeyOutcome::Transcribed(x)) => {
+                        ui.set_recording(false);
+                        show_transcription(&ui, &mut melon.lock().unwrap(), Ok(x), now);
+                    },
+                    Ok(HotkeyOutcome::Sent(x)) => show_send_result(&ui, Ok(x)),
+                    Ok(HotkeyOutcome::Cancelled) => {
+                        ui.set_recording(false);
+                        ui.set_stt_text("".into());
+                        ui.set_status_text("Recording cancelled".into());
+                    },
+                    Ok(HotkeyOutcome::Nothing) => {},
+                    Err(e) => {
+                        error!("Hotkey {} returned an error: {:?}", event.action, e);
+                        ui.set_recording(melon.lock().unwrap().recorder.is_recording());
+                        ui.set_status_text(format!("Hotkey {} failed: {}", event.action, e).into());
+                    }
+                }
+            });
+        }
     });
 
+    // Fill in the model picker
+    {
+        let melon = melon.lock().unwrap();
+        let models: Vec<slint::SharedString> = melon.available_models().into_iter().map(|x| x.into()).collect();
+        ui.set_models(std::rc::Rc::new(slint::VecModel::from(models)).into());
+        ui.set_current_model(model_name(&melon.processor.current_model()).into());
+        fill_profiles(&ui, &melon);
+        fill_settings(&ui, &melon);
+        fill_history(&ui, &melon);
+    }
+
     // Set the startup values of the STT Text and Status fields
-    ui.set_stt_text("Transcribed text will appear here.  Click button to send to VRC.".into());
-    ui.set_status_text(format!("Startup OK.  Input device: {}.", MELON.lock().unwrap().recorder.device_name.to_string()).into());
+    ui.set_stt_text("".into());
+    // Unless that would hide why the hotkeys aren't working
+    if hotkeys.is_some() {
+        ui.set_status_text(format!("Startup OK.  Input device: {}.", melon.lock().unwrap().recorder.device_name).into());
+    }
 
     // Start up the Slint UI
     let _ = ui.run();
+    drop(hotkeys);
     Ok(())
+}
+
+/// Run a recording on another thread, then show the result back on the UI thread
+fn record_in_background<F>(ui: &AppWindow, melon: &Arc<Mutex<MelonSTT>>, record: F)
+where F: FnOnce(&Arc<Mutex<MelonSTT>>) -> Result<String, MelonError> + Send + 'static {
+    ui.set_recording(true);
+    transcribe_in_background(ui, melon, record);
+}
+
+/// Transcribe on another thread, then show the result back on the UI
+/// thread.  `transcribe` gets the shared MelonSTT rather than a lock on
+/// it, so the window can still use it while Whisper runs.
+fn transcribe_in_background<F>(ui: &AppWindow, melon: &Arc<Mutex<MelonSTT>>, transcribe: F)
+where F: FnOnce(&Arc<Mutex<MelonSTT>>) -> Result<String, MelonError> + Send + 'static {
+    let ui_weak = ui.as_weak();
+    let melon = melon.clone();
+    std::thread::spawn(move || {
+        let now = Instant::now();
+        let result = transcribe(&melon);
+        let _ = ui_weak.upgrade_in_event_loop(move |ui| {
+            ui.set_recording(false);
+            show_transcription(&ui, &mut melon.lock().unwrap(), result, now);
+        });
+    });
+}
+
+/// Show the result of a recording, and send it on if auto-send is turned on
+fn show_transcription(ui: &AppWindow, melon: &mut MelonSTT, result: Result<String, MelonError>, started: Instant) {
+    match result {
+        Ok(transcription) if transcription.is_empty() => {
+            ui.set_stt_text("".into());
+            show_message_info(ui, melon, "");
+            ui.set_status_text(format!("No speech detected.  Took {:.2?} seconds", started.elapsed()).into());
+            debug!("Recording filtered out everything");
+        },
+        Ok(transcription) => {
+            ui.set_stt_text(transcription.as_str().into());
+            ui.set_stt_entry(latest_entry(melon, &transcription));
+            show_message_info(ui, melon, &transcription);
+            ui.set_status_text(format!("Processing complete.  Took {:.2?} seconds", started.elapsed()).into());
+            debug!("Recording completed successfully");
+            if melon.config.auto_send {
+                send_text(ui, melon, &transcription, u64::try_from(ui.get_stt_entry()).ok());
+            }
+        },
+        Err(e) => {
+            error!("Recording returned an error: {:?}", e);
+            ui.set_stt_text(e.to_string().into());
+            if e.is_retryable() {
+                ui.set_status_text("ERROR!  This might work if you try again.".into());
+            } else {
+                ui.set_status_text("ERROR!".into());
+            }
+        }
+    };
+}
+
+/// Update the character counter, and warn if the message won't fit in the chatbox
+fn show_message_info(ui: &AppWindow, melon: &MelonSTT, text: &str) {
+    let count = text.chars().count();
+    let warning = match melon.network.message_parts(text).len() {
+        _ if count <= CHATBOX_LIMIT => String::new(),
+        1 => format!("Too long: only the first {} characters will be sent", CHATBOX_LIMIT),
+        n => format!("Too long: will be sent as {} messages", n)
+    };
+    ui.set_char_count(count as i32);
+    ui.set_char_limit(CHATBOX_LIMIT as i32);
+    ui.set_message_warning(warning.into());
+}
+
+/// Send some text to the chatbox and say how it went on the status line.
+/// `entry` is the history entry it came from, if any.
+fn send_text(ui: &AppWindow, melon: &mut MelonSTT, value: &str, entry: Option<u64>) {
+    if value.trim().is_empty() {
+        ui.set_status_text("Nothing to send".into());
+        return;
+    }
+    show_send_result(ui, melon.send_to_osc(value, entry));
+}
+
+/// The history entry for a transcription that's just come in, or -1 if it
+/// didn't make it into the history
+fn latest_entry(melon: &MelonSTT, text: &str) -> i32 {
+    match melon.history.latest() {
+        Some(x) if x.text == text => x.id as i32,
+        _ => -1
+    }
+}
+
+/// Say how sending something went on the status line
+fn show_send_result(ui: &AppWindow, result: Result<SendStatus, MelonError>) {
+    match result {
+        Ok(SendStatus::Sent(_)) => {
+            ui.set_status_text("Sent transcribed text to OSC".into());
+        },
+        Ok(SendStatus::Empty) => {
+            ui.set_status_text("Nothing to send".into());
+        },
+        Ok(SendStatus::Blocked(reason)) => {
+            ui.set_status_text(format!("Not sent: {}", reason).into());
+        },
+        Err(e) if e.is_retryable() => {
+            ui.set_status_text(format!("{}  Click send to try again.", e).into());
+        },
+        Err(e) => {
+            ui.set_status_text(format!("Error sending to OSC: {}", e).into());
+        }
+    }
+}
+
+/// Fill in the history tab, newest first
+fn fill_history(ui: &AppWindow, melon: &MelonSTT) {
+    let items: Vec<HistoryItem> = melon.history.entries.iter().rev().map(|x| HistoryItem {
+        id: x.id as i32,
+        time: x.timestamp.format("%H:%M:%S").to_string().into(),
+        text: x.text.as_str().into(),
+        details: format!("{:.1}s, {}, {}", x.duration, model_name(&x.model), x.language).into(),
+        sent: x.sent,
+        has_audio: x.audio.is_some() || x.raw_audio.is_some(),
+    }).collect();
+    ui.set_history(std::rc::Rc::new(slint::VecModel::from(items)).into());
+}
+
+/// Add a retranscription to the ones shown side by side, as long as it's
+/// for the entry still being compared
+fn show_comparison(ui: &AppWindow, id: i32, result: Result<Retranscription, MelonError>) {
+    let run = match result {
+        Ok(x) => x,
+        Err(e) => {
+            error!("retranscribe returned an error: {:?}", e);
+            ui.set_status_text(format!("Couldn't retranscribe: {}", e).into());
+            return;
+        }
+    };
+    ui.set_status_text(format!("Retranscribed in {:.2} seconds", run.seconds).into());
+    if ui.get_compare_id() != id { return; }
+    let mut settings = format!("{}, {}, beam {}", model_name(&run.model), run.language, run.beam_size);
+    if let Some(x) = &run.prompt {
+        settings.push_str(&format!(", prompt '{}'", x));
+    }
+    let text = match run.text.is_empty() {
+        true => "(nothing)".to_string(),
+        false => run.text
+    };
+    use slint::Model;
+    let mut runs: Vec<Comparison> = ui.get_comparisons().iter().collect();
+    runs.push(Comparison { settings: settings.into(), text: text.into() });
+    ui.set_comparisons(std::rc::Rc::new(slint::VecModel::from(runs)).into());
+}
+
+/// What the input device picker shows for the system default device
+const DEFAULT_DEVICE: &str = "(default)";
+
+/// Fill in the settings tab from the settings currently in use
+fn fill_settings(ui: &AppWindow, melon: &MelonSTT) {
+    let config = &melon.config;
+    let mut devices: Vec<slint::SharedString> = vec![DEFAULT_DEVICE.into()];
+    devices.extend(STTRecorder::list_devices().into_iter().map(|x| x.into()));
+    ui.set_devices(std::rc::Rc::new(slint::VecModel::from(devices)).into());
+    ui.set_current_device(config.input_device.as_deref().unwrap_or(DEFAULT_DEVICE).into());
+    ui.set_language(config.language.as_str().into());
+    ui.set_osc_endpoint(config.osc_endpoint.as_deref().unwrap_or_default().into());
+    let mode = match config.record_mode {
+        RecordMode::Fixed => "fixed",
+        RecordMode::Vad => "vad",
+        RecordMode::PushToTalk => "push-to-talk",
+    };
+    ui.set_record_mode(mode.into());
+    ui.set_auto_send(config.auto_send);
+    ui.set_split_messages(config.split_messages);
+    ui.set_filter_enabled(config.filter.enabled);
+    ui.set_drop_bracketed(config.filter.drop_bracketed);
+    ui.set_no_speech_threshold(config.filter.no_speech_threshold.to_string().into());
+    ui.set_censor_enabled(config.censor.enabled);
+    ui.set_device_error("".into());
+    ui.set_model_error("".into());
+    ui.set_language_error("".into());
+    ui.set_endpoint_error("".into());
+    ui.set_threshold_error("".into());
+}
+
+/// A change to make to the settings in use
+type SettingChange = Box<dyn FnOnce(&mut STTConfig)>;
+
+/// Turn a setting edited on the settings tab into a change to the config
+fn setting(key: &str, value: &str) -> Result<SettingChange, String> {
+    let value = value.trim().to_string();
+    let optional = (!value.is_empty() && value != DEFAULT_DEVICE).then(|| value.to_string());
+    let on = value == "true";
+    let change: SettingChange = match key {
+        "input-device" => Box::new(move |x| x.input_device = optional),
+        "language" => Box::new(move |x| x.language = value),
+        "osc-endpoint" => Box::new(move |x| x.osc_endpoint = optional),
+        "record-mode" => {
+            let mode = match value.as_str() {
+                "vad" => RecordMode::Vad,
+                "push-to-talk" => RecordMode::PushToTalk,
+                _ => RecordMode::Fixed
+            };
+            Box::new(move |x| x.record_mode = mode)
+        },
+        "auto-send" => Box::new(move |x| x.auto_send = on),
+        "split-messages" => Box::new(move |x| x.split_messages = on),
+        "filter-enabled" => Box::new(move |x| x.filter.enabled = on),
+        "drop-bracketed" => Box::new(move |x| x.filter.drop_bracketed = on),
+        "no-speech-threshold" => {
+            let threshold = match value.parse::<f32>() {
+                Ok(x) => x,
+                Err(_) => return Err("Should be a number between 0.0 and 1.0".to_string())
+            };
+            Box::new(move |x| x.filter.no_speech_threshold = threshold)
+        },
+        "censor-enabled" => Box::new(move |x| x.censor.enabled = on),
+        _ => return Err(format!("Unknown setting {}", key))
+    };
+    Ok(change)
+}
+
+/// Short version of an error for showing next to a setting
+fn setting_error(e: &MelonError) -> String {
+    match e {
+        MelonError::ConfigInvalid { issues, .. } => {
+            issues.iter().map(|x| x.message.to_string()).collect::<Vec<_>>().join("; ")
+        },
+        e => e.to_string()
+    }
+}
+
+/// What the profile picker shows for "no profile"
+const NO_PROFILE: &str = "(none)";
+
+/// Fill in the profile picker and show which profile is active
+fn fill_profiles(ui: &AppWindow, melon: &MelonSTT) {
+    let mut profiles: Vec<slint::SharedString> = vec![NO_PROFILE.into()];
+    profiles.extend(melon.profiles().into_iter().map(|x| x.into()));
+    ui.set_profiles(std::rc::Rc::new(slint::VecModel::from(profiles)).into());
+    ui.set_current_profile(profile_index(melon, melon.config.profile.as_deref()));
+}
+
+/// Where a profile is in the profile picker, going by index rather than
+/// name so a profile can't be mistaken for NO_PROFILE
+fn profile_index(melon: &MelonSTT, profile: Option<&str>) -> i32 {
+    match profile {
+        Some(x) => melon.profiles().iter().position(|y| y == x).map(|x| x as i32 + 1).unwrap_or(0),
+        None => 0
+    }
+}
+
+/// Strip the directory off a model path for display in the model picker
+fn model_name(path: &str) -> String {
+    match std::path::Path::new(path).file_name() {
+        Some(x) => x.to_string_lossy().to_string(),
+        None => path.to_string()
+    }
 }
\ No newline at end of file
@@ -0,0 +1,309 @@
+//! This module handles finding and checking Whisper ggml model files.
+//! It reads just enough of the ggml header to say what kind of model a
+//! file is, and can verify files against a local SHA256SUMS manifest
+//! (the same format `sha256sum` spits out) so a truncated or corrupted
+//! download gets caught before Whisper chokes on it.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use sha2::{Digest, Sha256};
+use log::{debug, error, warn};
+use crate::MelonError;
+
+/// The magic number at the start of every ggml file ("ggml" as a LE u32)
+const GGML_MAGIC: u32 = 0x6767_6d6c;
+
+/// Name of the checksum manifest looked for in the models directory
+pub const MANIFEST_NAME: &str = "SHA256SUMS";
+
+/// The hyperparameters stored at the top of a Whisper ggml file
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModelHeader {
+    /// Size of the token vocabulary
+    pub n_vocab: i32,
+    /// Audio context size
+    pub n_audio_ctx: i32,
+    /// Audio embedding width
+    pub n_audio_state: i32,
+    /// Number of audio attention heads
+    pub n_audio_head: i32,
+    /// Number of audio encoder layers
+    pub n_audio_layer: i32,
+    /// Text context size
+    pub n_text_ctx: i32,
+    /// Text embedding width
+    pub n_text_state: i32,
+    /// Number of text attention heads
+    pub n_text_head: i32,
+    /// Number of text decoder layers
+    pub n_text_layer: i32,
+    /// Number of mel bins
+    pub n_mels: i32,
+    /// ggml file type, which says how the weights are stored
+    pub ftype: i32,
+}
+
+impl ModelHeader {
+    /// Read the header from the start of a ggml model file
+    pub fn read(path: &Path) -> Result<ModelHeader, MelonError> {
+        let mut file = match File::open(path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                error!("Model file {} doesn't exist", path.display());
+                return Err(MelonError::ModelMissing(path.to_path_buf()));
+            },
+            Err(e) => {
+                error!("Unable to open model file {}", path.display());
+                return Err(MelonError::ModelInvalid { path: path.to_path_buf(), reason: e.to_string() });
+            }
+        };
+        let mut buf = [0u8; 48];
+        if file.read_exact(&mut buf).is_err() {
+            error!("Model file {} is too short to be a ggml model", path.display());
+            return Err(MelonError::ModelInvalid { path: path.to_path_buf(), reason: "file is too short".to_string() });
+        }
+        let field = |i: usize| i32::from_le_bytes([buf[i * 4], buf[i * 4 + 1], buf[i * 4 + 2], buf[i * 4 + 3]]);
+        if field(0) as u32 != GGML_MAGIC {
+            return Err(MelonError::ModelInvalid { path: path.to_path_buf(), reason: "bad magic number".to_string() });
+        }
+        Ok(ModelHeader {
+            n_vocab: field(1),
+            n_audio_ctx: field(2),
+            n_audio_state: field(3),
+            n_audio_head: field(4),
+            n_audio_layer: field(5),
+            n_text_ctx: field(6),
+            n_text_state: field(7),
+            n_text_head: field(8),
+            n_text_layer: field(9),
+            n_mels: field(10),
+            ftype: field(11),
+        })
+    }
+
+    /// Which Whisper model size this is, going by the number of encoder layers
+    pub fn model_type(&self) -> &'static str {
+        match (self.n_audio_layer, self.n_text_layer, self.n_mels) {
+            (4, _, _) => "tiny",
+            (6, _, _) => "base",
+            (12, _, _) => "small",
+            (24, _, _) => "medium",
+            (32, 4, 128) => "large-v3-turbo",
+            (32, _, 128) => "large-v3",
+            (32, _, _) => "large",
+            _ => "unknown",
+        }
+    }
+
+    /// English-only (.en) models have one less token in their vocabulary
+    pub fn multilingual(&self) -> bool {
+        self.n_vocab >= 51865
+    }
+
+    /// How the weights are stored.  Newer quantized files add a
+    /// quantization version times 1000 to the ftype, so strip that first.
+    pub fn quantization(&self) -> &'static str {
+        match self.ftype % 1000 {
+            0 => "f32",
+            1 => "f16",
+            2 => "q4_0",
+            3 => "q4_1",
+            4 => "q4_1 (some f16)",
+            7 => "q8_0",
+            8 => "q5_0",
+            9 => "q5_1",
+            10 => "q2_k",
+            11 => "q3_k",
+            12 => "q4_k",
+            13 => "q5_k",
+            14 => "q6_k",
+            _ => "unknown",
+        }
+    }
+}
+
+/// Everything we know about one model file
+#[derive(Clone, Debug)]
+pub struct ModelInfo {
+    /// Full path to the model file
+    pub path: PathBuf,
+    /// Just the filename
+    pub file_name: String,
+    /// Size of the file in bytes
+    pub size: u64,
+    /// The parsed header, or the reason it couldn't be read
+    pub header: Result<ModelHeader, String>,
+    /// Some(true/false) if the manifest lists this file, None if it doesn't
+    pub checksum_ok: Option<bool>,
+}
+
+impl ModelInfo {
+    /// Read the header of a single model file.  Checksums aren't
+    /// verified here since hashing a large model takes a while.
+    pub fn new(path: &Path) -> ModelInfo {
+        let file_name = path.file_name().map(|x| x.to_string_lossy().to_string()).unwrap_or_default();
+        let size = std::fs::metadata(path).map(|x| x.len()).unwrap_or(0);
+        let header = ModelHeader::read(path).map_err(|e| e.to_string());
+        ModelInfo { path: path.to_path_buf(), file_name, size, header, checksum_ok: None }
+    }
+
+    /// A one-line human readable description of the model
+    pub fn describe(&self) -> String {
+        let size = format!("{:.1} MiB", self.size as f64 / (1024.0 * 1024.0));
+        let checksum = match self.checksum_ok {
+            Some(true) => ", checksum ok",
+            Some(false) => ", CHECKSUM MISMATCH",
+            None => "",
+        };
+        match &self.header {
+            Ok(h) => format!("{}: {} {}, {}, {}{}", self.file_name, h.model_type(),
+                if h.multilingual() { "multilingual" } else { "English-only" },
+                h.quantization(), size, checksum),
+            Err(e) => format!("{}: unreadable ({}), {}{}", self.file_name, e, size, checksum),
+        }
+    }
+
+    /// If the model is English-only but a different language is configured,
+    /// hand back a warning saying so
+    pub fn language_warning(&self, language: &str) -> Option<String> {
+        let header = self.header.as_ref().ok()?;
+        let language = language.trim().to_lowercase();
+        if header.multilingual() || language == "en" || language == "english" {
+            return None;
+        }
+        Some(format!("{} is an English-only model but language is set to '{}'", self.file_name, language))
+    }
+}
+
+/// List and describe every .bin file in a directory.  If the directory has
+/// a SHA256SUMS manifest and `verify` is set, every listed file is hashed
+/// and checked against it.
+pub fn scan(dir: &Path, verify: bool) -> Result<Vec<ModelInfo>, MelonError> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Unable to read models directory {}", dir.display());
+            return Err(e.into());
+        }
+    };
+    let manifest = if verify { read_manifest(&dir.join(MANIFEST_NAME)) } else { HashMap::new() };
+    let mut retval = Vec::new();
+    for entry in entries.filter_map(|x| x.ok()) {
+        let path = entry.path();
+        let is_model = path.is_file() && path.extension().is_some_and(|x| x == "bin");
+        if !is_model { continue; }
+        let mut info = ModelInfo::new(&path);
+        if let Some(expected) = manifest.get(&info.file_name) {
+            info.checksum_ok = match sha256_file(&path) {
+                Ok(actual) => Some(&actual == expected),
+                Err(_) => Some(false),
+            };
+        }
+        debug!("Found model {}", info.describe());
+        retval.push(info);
+    }
+    retval.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+    Ok(retval)
+}
+
+/// Read a `sha256sum`-style manifest into a map of filename to hex digest.
+/// A missing manifest is just an empty map.
+pub fn read_manifest(path: &Path) -> HashMap<String, String> {
+    let data = match std::fs::read_to_string(path) {
+        Ok(x) => x,
+        Err(_) => {
+            debug!("No checksum manifest at {}", path.display());
+            return HashMap::new();
+        }
+    };
+    let mut retval = HashMap::new();
+    for line in data.lines() {
+        let mut parts = line.split_whitespace();
+        if let (Some(hash), Some(name)) = (parts.next(), parts.next()) {
+            // sha256sum marks binary mode with a leading '*' on the filename
+            retval.insert(name.trim_start_matches('*').to_string(), hash.to_lowercase());
+        } else if !line.trim().is_empty() {
+            warn!("Skipping malformed manifest line: {}", line);
+        }
+    }
+    retval
+}
+
+/// Hash a file with SHA-256 and return the lowercase hex digest
+pub fn sha256_file(path: &Path) -> Result<String, MelonError> {
+    let file = match File::open(path) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Unable to open {} for hashing", path.display());
+            return Err(e.into());
+        }
+    };
+    let mut reader = BufReader::new(file);
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1024 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 { break; }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().iter().map(|x| format!("{:02x}", x)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch;
+
+    /// Write a fake model file into `dir` with the given magic and vocab size
+    fn fake_model(dir: &Path, magic: u32, n_vocab: i32) -> PathBuf {
+        let path = dir.join("ggml-fake.bin");
+        let mut fields = vec![magic as i32, n_vocab, 1500, 384, 6, 4, 448, 384, 6, 4, 80, 1];
+        fields.extend([0; 4]);
+        let bytes: Vec<u8> = fields.iter().flat_map(|x| x.to_le_bytes()).collect();
+        std::fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    #[test]
+    fn reads_header() {
+        let dir = scratch("model-tiny");
+        let header = ModelHeader::read(&fake_model(&dir, GGML_MAGIC, 51865)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(header.model_type(), "tiny");
+        assert_eq!(header.quantization(), "f16");
+        assert!(header.multilingual());
+    }
+
+    #[test]
+    fn english_only_vocab() {
+        let dir = scratch("model-tiny-en");
+        let header = ModelHeader::read(&fake_model(&dir, GGML_MAGIC, 51864)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(!header.multilingual());
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let dir = scratch("model-bad-magic");
+        let result = ModelHeader::read(&fake_model(&dir, 0x1234_5678, 51865));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(result, Err(MelonError::ModelInvalid { .. })));
+    }
+
+    #[test]
+    fn rejects_short_file() {
+        let dir = scratch("model-short");
+        let path = dir.join("ggml-short.bin");
+        std::fs::write(&path, b"ggml").unwrap();
+        let result = ModelHeader::read(&path);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(result, Err(MelonError::ModelInvalid { .. })));
+    }
+
+    #[test]
+    fn missing_file() {
+        let result = ModelHeader::read(Path::new("/nonexistent/melonstt-model.bin"));
+        assert!(matches!(result, Err(MelonError::ModelMissing(_))));
+    }
+}
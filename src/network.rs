@@ -4,20 +4,28 @@
 use rosc::{encoder, OscMessage, OscPacket, OscType};
 use std::net::{SocketAddrV4, UdpSocket};
 use std::str::FromStr;
-use crate::STTConfig;
+use std::time::Duration;
+use crate::{MelonError, STTConfig};
 use log::{debug, error};
 
+/// The most characters the VRChat chatbox will show in one message
+pub const CHATBOX_LIMIT: usize = 144;
+
 /// A struct for holding socket(s) and endpoint(s)
 pub struct STTNetwork {
     /// Local side of the UDP socket for OSC
     pub socket: UdpSocket,
     /// Address of remote side of UDP socket for OSC
     pub osc_endpoint: SocketAddrV4,
+    /// Send long messages as several chatbox messages instead of cutting them off
+    pub split_messages: bool,
+    /// How long to leave each part of a split message up before sending the next
+    pub split_delay: Duration,
 }
 
 impl STTNetwork {
     /// Create the local socket and parse the endpoint config
-    pub fn new(conf: &STTConfig) -> Result<STTNetwork, anyhow::Error> {
+    pub fn new(conf: &STTConfig) -> Result<STTNetwork, MelonError> {
         let dest = match &conf.osc_endpoint {
             Some(x) => x,
             None => &"127.0.0.1:9000".to_string()
@@ -26,28 +34,95 @@ impl STTNetwork {
             Ok(x) => x,
             Err(e) => { 
                 error!("Error creating SocektAddr for local address");
-                return Err(e.into());
+                return Err(MelonError::InvalidEndpoint { addr: "127.0.0.1:49001".to_string(), source: e });
             }
         };
-        let dest_addr = match SocketAddrV4::from_str(&dest) {
+        let dest_addr = match SocketAddrV4::from_str(dest) {
             Ok(x) => x,
             Err(e) => {
                 error!("Error parsing osc_endpoint address string");
-                return Err(e.into())
+                return Err(MelonError::InvalidEndpoint { addr: dest.to_string(), source: e })
             }
         };
         let socket = match UdpSocket::bind(from_addr) { 
             Ok(x) => x,
             Err(e) => {
                 error!("Error binding local side of UDP socket");
-                return Err(e.into());
+                return Err(MelonError::SocketBind(e));
             }
         };
-        Ok(STTNetwork { socket, osc_endpoint: dest_addr })
+        Ok(STTNetwork {
+            socket,
+            osc_endpoint: dest_addr,
+            split_messages: conf.split_messages,
+            split_delay: Duration::from_millis(conf.split_delay_ms)
+        })
+    }
+
+    /// Work out the chatbox messages a piece of text will actually go out
+    /// as: split into several if split_messages is on, otherwise cut off at
+    /// the chatbox limit
+    pub fn message_parts(&self, text: &str) -> Vec<String> {
+        if text.chars().count() <= CHATBOX_LIMIT {
+            return vec![text.to_string()];
+        }
+        match self.split_messages {
+            true => split_message(text, CHATBOX_LIMIT),
+            false => vec![text.chars().take(CHATBOX_LIMIT).collect()]
+        }
+    }
+
+    /// Point the socket at a different OSC endpoint.  The local side of the
+    /// socket stays as it is, so there's no need to bind it again.
+    pub fn set_endpoint(&mut self, conf: &STTConfig) -> Result<(), MelonError> {
+        let dest = conf.osc_endpoint.as_deref().unwrap_or("127.0.0.1:9000");
+        match SocketAddrV4::from_str(dest) {
+            Ok(x) => {
+                debug!("OSC endpoint changed to {}", x);
+                self.osc_endpoint = x;
+                Ok(())
+            },
+            Err(e) => {
+                error!("Error parsing osc_endpoint address string");
+                Err(MelonError::InvalidEndpoint { addr: dest.to_string(), source: e })
+            }
+        }
     }
 
-    /// Sends the provided text string to the /chatbox/input OSC Endpoint
-    pub fn send_to_osc(&self, text: &str) -> Result<(), anyhow::Error> {
+    /// Sends the provided text string to the /chatbox/input OSC Endpoint.
+    /// Text that won't fit in the chatbox is either cut off or, with
+    /// split_messages on, sent in parts with split_delay between them.  The
+    /// first part goes out straight away and the rest in the background.
+    pub fn send_to_osc(&self, text: &str) -> Result<(), MelonError> {
+        let mut parts = self.message_parts(text).into_iter();
+        if let Some(first) = parts.next() {
+            Self::send_chatbox(&self.socket, self.osc_endpoint, &first)?;
+        }
+        let rest: Vec<String> = parts.collect();
+        if rest.is_empty() { return Ok(()); }
+        let socket = match self.socket.try_clone() {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Error cloning UDP socket for split message");
+                return Err(MelonError::OscSend(e));
+            }
+        };
+        let (endpoint, delay) = (self.osc_endpoint, self.split_delay);
+        debug!("Sending {} more parts of a split message", rest.len());
+        std::thread::spawn(move || {
+            for part in rest {
+                std::thread::sleep(delay);
+                if let Err(e) = Self::send_chatbox(&socket, endpoint, &part) {
+                    error!("Error sending part of a split message: {}", e);
+                    break;
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Send a single message to /chatbox/input
+    fn send_chatbox(socket: &UdpSocket, endpoint: SocketAddrV4, text: &str) -> Result<(), MelonError> {
         // I'm doin this kinda weird because wrapping this in a match is just ugly
         let msg_buf = encoder::encode(
             &OscPacket::Message(
@@ -66,18 +141,18 @@ impl STTNetwork {
             }
         };
 
-        match self.socket.send_to(&buf, self.osc_endpoint) {
+        match socket.send_to(&buf, endpoint) {
             Ok(_) => { debug!("Sent OSC packet successfully"); },
             Err(e) => { 
                 error!("Error sending OSC packet: {:?}", e); 
-                return Err(e.into());
+                return Err(MelonError::OscSend(e));
             }
         }
         Ok(())
     }
 
     /// Toggles the typing indicator via an OSC packet to /chatbox/typing
-    pub fn toggle_typing(&self, on: bool) -> Result<(), anyhow::Error> {
+    pub fn toggle_typing(&self, on: bool) -> Result<(), MelonError> {
         let toggle = match on {
             true => OscType::Bool(true),
             false => OscType::Bool(false)
@@ -104,9 +179,112 @@ impl STTNetwork {
             Ok(_) => { debug!("Sent toggle_typing OSC packet"); },
             Err(e) => { 
                 error!("Error sending toggle_typing OSC packet: {:?}", e); 
-                return Err(e.into());
+                return Err(MelonError::OscSend(e));
             }
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Break text into pieces of at most `limit` characters, splitting between
+/// words where possible.  Words longer than the limit are split wherever.
+pub fn split_message(text: &str, limit: usize) -> Vec<String> {
+    let mut retval = Vec::new();
+    let mut current = String::new();
+    for word in text.split_whitespace() {
+        let needed = match current.is_empty() {
+            true => word.chars().count(),
+            false => current.chars().count() + 1 + word.chars().count()
+        };
+        if needed <= limit {
+            if !current.is_empty() { current.push(' '); }
+            current.push_str(word);
+            continue;
+        }
+        if !current.is_empty() {
+            retval.push(std::mem::take(&mut current));
+        }
+        let chars: Vec<char> = word.chars().collect();
+        for chunk in chars.chunks(limit) {
+            current = chunk.iter().collect();
+            if chunk.len() == limit { retval.push(std::mem::take(&mut current)); }
+        }
+    }
+    if !current.is_empty() { retval.push(current); }
+    retval
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(split_messages: bool) -> STTNetwork {
+        STTNetwork {
+            socket: UdpSocket::bind("127.0.0.1:0").unwrap(),
+            osc_endpoint: SocketAddrV4::from_str("127.0.0.1:9000").unwrap(),
+            split_messages,
+            split_delay: Duration::from_millis(0),
+        }
+    }
+
+    #[test]
+    fn splits_between_words() {
+        let text = "word ".repeat(60);
+        let parts = split_message(&text, CHATBOX_LIMIT);
+        assert_eq!(parts.len(), 3);
+        for part in parts.iter() {
+            assert!(part.chars().count() <= CHATBOX_LIMIT);
+            assert!(part.split(' ').all(|x| x == "word"), "split mid-word: {}", part);
+        }
+        assert_eq!(parts.join(" "), text.trim());
+    }
+
+    #[test]
+    fn fills_up_to_the_limit() {
+        // Five 28-letter words and the spaces between them come to exactly 144
+        let word = "x".repeat(28);
+        let text = [word.as_str(); 6].join(" ");
+        let parts = split_message(&text, CHATBOX_LIMIT);
+        assert_eq!(parts[0].chars().count(), CHATBOX_LIMIT);
+        assert_eq!(parts[1], word);
+    }
+
+    #[test]
+    fn breaks_up_long_words() {
+        let word = "a".repeat(CHATBOX_LIMIT * 2 + 10);
+        let parts = split_message(&format!("hi {} there", word), CHATBOX_LIMIT);
+        assert_eq!(parts, vec![
+            "hi".to_string(),
+            "a".repeat(CHATBOX_LIMIT),
+            "a".repeat(CHATBOX_LIMIT),
+            format!("{} there", "a".repeat(10)),
+        ]);
+    }
+
+    #[test]
+    fn counts_characters_not_bytes() {
+        // 100 three-byte characters fit in one message
+        let text = "あ".repeat(100);
+        assert_eq!(split_message(&text, CHATBOX_LIMIT), vec![text.to_string()]);
+        let text = format!("{} {}", "あ".repeat(100), "い".repeat(100));
+        let parts = split_message(&text, CHATBOX_LIMIT);
+        assert_eq!(parts, vec!["あ".repeat(100), "い".repeat(100)]);
+        let parts = split_message(&"🍈".repeat(200), CHATBOX_LIMIT);
+        assert_eq!(parts, vec!["🍈".repeat(CHATBOX_LIMIT), "🍈".repeat(56)]);
+    }
+
+    #[test]
+    fn message_parts_cuts_off_or_splits() {
+        let text = "word ".repeat(40);
+        let text = text.trim();
+        assert_eq!(network(true).message_parts("hello"), vec!["hello".to_string()]);
+        let cut = network(false).message_parts(text);
+        assert_eq!(cut.len(), 1);
+        assert_eq!(cut[0].chars().count(), CHATBOX_LIMIT);
+        let split = network(true).message_parts(text);
+        assert_eq!(split.len(), 2);
+        assert_eq!(split.join(" "), text);
+        let multibyte = "é".repeat(CHATBOX_LIMIT);
+        assert_eq!(network(false).message_parts(&multibyte), vec![multibyte.to_string()]);
+    }
+}
@@ -0,0 +1,304 @@
+//! This module sends transcriptions to OBS as live captions, through
+//! obs-websocket (the v5 protocol built into OBS 28 and up).  Captions
+//! can go out as proper stream captions (SendStreamCaption, which only
+//! does anything while streaming) or into a text source on screen.
+//!
+//! The connection is made the first time something's sent, and made
+//! again if OBS was restarted in the meantime.
+use std::net::TcpStream;
+use std::time::Duration;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tungstenite::{Message, WebSocket};
+use tungstenite::stream::MaybeTlsStream;
+use log::{debug, error, warn};
+use crate::MelonError;
+
+/// How long to wait on OBS before giving up
+const TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Where captions end up in OBS
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CaptionMode {
+    /// Embedded CEA-608 captions in the stream itself
+    #[default]
+    StreamCaption,
+    /// The text of a text source, to show them on screen
+    TextSource,
+}
+
+/// Settings for OBS captions, read from the [obs] table
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct ObsConfig {
+    /// Send captions to OBS at all
+    pub enabled: bool,
+    /// Address of obs-websocket
+    pub url: String,
+    /// The obs-websocket password, if authentication is turned on
+    pub password: Option<String>,
+    /// Where the captions go
+    pub mode: CaptionMode,
+    /// Name of the text source to update in text-source mode
+    pub source: String,
+}
+
+impl Default for ObsConfig {
+    fn default() -> Self {
+        ObsConfig {
+            enabled: false,
+            url: "ws://127.0.0.1:4455".to_string(),
+            password: None,
+            mode: CaptionMode::StreamCaption,
+            source: "MelonSTT Captions".to_string(),
+        }
+    }
+}
+
+/// Sends captions to OBS.  It lives on the outputs thread, which makes a
+/// new one whenever the settings change.
+pub struct STTObs {
+    /// The settings in use
+    pub config: ObsConfig,
+    /// The connection to OBS, once there is one
+    socket: Option<WebSocket<MaybeTlsStream<TcpStream>>>,
+    /// Id of the latest request, so responses can be matched up
+    last_id: u64,
+}
+
+impl STTObs {
+    /// Set up OBS captions.  Nothing connects until the first caption.
+    pub fn new(config: &ObsConfig) -> STTObs {
+        STTObs { config: config.clone(), socket: None, last_id: 0 }
+    }
+
+    /// Send a caption to OBS.  If the connection has gone stale (OBS was
+    /// restarted, say) it's made again and the caption tried once more.
+    pub fn send_caption(&mut self, text: &str) -> Result<(), MelonError> {
+        let (request, data) = match self.config.mode {
+            CaptionMode::StreamCaption => ("SendStreamCaption", json!({ "captionText": text })),
+            CaptionMode::TextSource => ("SetInputSettings", json!({
+                "inputName": self.config.source,
+                "inputSettings": { "text": text },
+            })),
+        };
+        // A connection made earlier might have gone stale, so it gets a retry
+        let attempts = if self.socket.is_some() { 2 } else { 1 };
+        let mut last_error = None;
+        for _ in 0..attempts {
+            if self.socket.is_none() {
+                self.socket = Some(self.connect()?);
+            }
+            self.last_id += 1;
+            let id = self.last_id.to_string();
+            let Some(connection) = self.socket.as_mut() else { continue };
+            match Self::request(connection, request, &id, data.clone()) {
+                Ok(_) => {
+                    debug!("Sent caption to OBS");
+                    return Ok(());
+                },
+                // OBS answered but said no, so trying again won't help
+                Err(MelonError::ObsRequest(e)) => {
+                    error!("OBS refused {}: {}", request, e);
+                    return Err(MelonError::ObsRequest(e));
+                },
+                Err(e) => {
+                    warn!("Lost connection to OBS ({})", e);
+                    self.socket = None;
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| MelonError::Obs("couldn't get through to OBS".to_string())))
+    }
+
+    /// Connect to obs-websocket and go through the hello/identify dance
+    fn connect(&self) -> Result<WebSocket<MaybeTlsStream<TcpStream>>, MelonError> {
+        debug!("Connecting to OBS at {}", self.config.url);
+        let mut socket = match tungstenite::connect(&self.config.url) {
+            Ok((x, _)) => x,
+            Err(e) => {
+                error!("Error connecting to OBS at {}", self.config.url);
+                return Err(MelonError::Obs(format!("can't connect to {}: {}", self.config.url, e)));
+            }
+        };
+        if let MaybeTlsStream::Plain(x) = socket.get_mut() {
+            let _ = x.set_read_timeout(Some(TIMEOUT));
+            let _ = x.set_write_timeout(Some(TIMEOUT));
+        }
+        // OBS starts with a Hello (op 0), with a challenge if it wants a password
+        let hello = Self::receive(&mut socket, 0)?;
+        let mut identify = json!({ "rpcVersion": 1, "eventSubscriptions": 0 });
+        if let Some(auth) = hello.get("authentication") {
+            let password = match &self.config.password {
+                Some(x) => x,
+                None => return Err(MelonError::Obs("OBS wants a password; set password under [obs]".to_string()))
+            };
+            let challenge = auth["challenge"].as_str().unwrap_or_default();
+            let salt = auth["salt"].as_str().unwrap_or_default();
+            identify["authentication"] = json!(Self::authentication(password, salt, challenge));
+        }
+        Self::transmit(&mut socket, 1, identify)?;
+        // Identified (op 2) means we're in; a wrong password just hangs up
+        match Self::receive(&mut socket, 2) {
+            Ok(_) => {
+                debug!("Connected to OBS");
+                Ok(socket)
+            },
+            Err(e) => {
+                error!("OBS didn't accept us, is the password right?");
+                Err(e)
+            }
+        }
+    }
+
+    /// Work out the authentication string from the password and OBS's
+    /// challenge: base64(sha256(base64(sha256(password + salt)) + challenge))
+    fn authentication(password: &str, salt: &str, challenge: &str) -> String {
+        let secret = BASE64.encode(Sha256::digest(format!("{}{}", password, salt)));
+        BASE64.encode(Sha256::digest(format!("{}{}", secret, challenge)))
+    }
+
+    /// Make a request (op 6) and wait for its response (op 7)
+    fn request(socket: &mut WebSocket<MaybeTlsStream<TcpStream>>, request: &str, id: &str, data: Value) -> Result<Value, MelonError> {
+        Self::transmit(socket, 6, json!({ "requestType": request, "requestId": id, "requestData": data }))?;
+        loop {
+            let response = Self::receive(socket, 7)?;
+            if response["requestId"].as_str() != Some(id) { continue; }
+            let status = &response["requestStatus"];
+            return match status["result"].as_bool() {
+                Some(true) => Ok(response),
+                _ => Err(MelonError::ObsRequest(status["comment"].as_str()
+                    .map(|x| x.to_string())
+                    .unwrap_or_else(|| format!("code {}", status["code"]))))
+            };
+        }
+    }
+
+    /// Send a message with the given opcode
+    fn transmit(socket: &mut WebSocket<MaybeTlsStream<TcpStream>>, op: u8, data: Value) -> Result<(), MelonError> {
+        let message = json!({ "op": op, "d": data }).to_string();
+        socket.send(Message::text(message)).map_err(|e| MelonError::Obs(e.to_string()))
+    }
+
+    /// Wait for a message with the given opcode and hand back its data,
+    /// skipping over anything else OBS sends in the meantime
+    fn receive(socket: &mut WebSocket<MaybeTlsStream<TcpStream>>, op: u64) -> Result<Value, MelonError> {
+        loop {
+            let message = match socket.read() {
+                Ok(Message::Text(x)) => x,
+                Ok(Message::Close(x)) => {
+                    let reason = x.map(|x| format!(" ({})", x.reason)).unwrap_or_default();
+                    return Err(MelonError::Obs(format!("OBS closed the connection{}", reason)));
+                },
+                Ok(_) => continue,
+                Err(e) => return Err(MelonError::Obs(e.to_string()))
+            };
+            let value: Value = serde_json::from_str(&message)?;
+            if value["op"].as_u64() == Some(op) {
+                return Ok(value["d"].clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+
+    // The example from the obs-websocket protocol docs
+    const PASSWORD: &str = "supersecretpassword";
+    const SALT: &str = "lM1GncleQOaCu9lT1yeUZhFYnqhsLLP1G5lAGo3ixaI=";
+    const CHALLENGE: &str = "+IxH4CnCiqpX1rM9scsNynZzbOe4KhDeYcTNS3PDaeY=";
+
+    /// Pretend to be OBS for one connection: say hello with a challenge,
+    /// check the identify, then answer one request with `result`.  Hands
+    /// back the identify and request it got.
+    fn fake_obs(result: bool) -> (String, JoinHandle<(Value, Value)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            let send = |socket: &mut WebSocket<TcpStream>, x: Value| socket.send(Message::text(x.to_string())).unwrap();
+            let read = |socket: &mut WebSocket<TcpStream>| -> Value {
+                serde_json::from_str(socket.read().unwrap().to_text().unwrap()).unwrap()
+            };
+            send(&mut socket, json!({ "op": 0, "d": {
+                "obsWebSocketVersion": "5.0.0",
+                "rpcVersion": 1,
+                "authentication": { "challenge": CHALLENGE, "salt": SALT },
+            }}));
+            let identify = read(&mut socket);
+            send(&mut socket, json!({ "op": 2, "d": { "negotiatedRpcVersion": 1 } }));
+            let request = read(&mut socket);
+            let id = request["d"]["requestId"].clone();
+            // Something unrelated first, which should be skipped over
+            send(&mut socket, json!({ "op": 5, "d": { "eventType": "CurrentSceneChanged" } }));
+            send(&mut socket, json!({ "op": 7, "d": {
+                "requestType": request["d"]["requestType"],
+                "requestId": id,
+                "requestStatus": { "result": result, "code": 600, "comment": "No source was found" },
+            }}));
+            (identify, request)
+        });
+        (url, handle)
+    }
+
+    fn config(url: String, mode: CaptionMode) -> ObsConfig {
+        ObsConfig { enabled: true, url, password: Some(PASSWORD.to_string()), mode, ..Default::default() }
+    }
+
+    #[test]
+    fn authentication_matches_protocol() {
+        assert_eq!(STTObs::authentication(PASSWORD, SALT, CHALLENGE), "1Ct943GAT+6YQUUX47Ia/ncufilbe6+oD6lY+5kaCu4=");
+    }
+
+    #[test]
+    fn stream_caption_is_sent() {
+        let (url, server) = fake_obs(true);
+        let mut obs = STTObs::new(&config(url, CaptionMode::StreamCaption));
+        obs.send_caption("hello there").unwrap();
+        let (identify, request) = server.join().unwrap();
+        assert_eq!(identify["op"], 1);
+        assert_eq!(identify["d"]["authentication"], "1Ct943GAT+6YQUUX47Ia/ncufilbe6+oD6lY+5kaCu4=");
+        assert_eq!(request["op"], 6);
+        assert_eq!(request["d"]["requestType"], "SendStreamCaption");
+        assert_eq!(request["d"]["requestData"]["captionText"], "hello there");
+    }
+
+    #[test]
+    fn text_source_is_set() {
+        let (url, server) = fake_obs(true);
+        let mut obs = STTObs::new(&config(url, CaptionMode::TextSource));
+        obs.send_caption("hello there").unwrap();
+        let (_, request) = server.join().unwrap();
+        assert_eq!(request["d"]["requestType"], "SetInputSettings");
+        assert_eq!(request["d"]["requestData"]["inputName"], "MelonSTT Captions");
+        assert_eq!(request["d"]["requestData"]["inputSettings"]["text"], "hello there");
+    }
+
+    #[test]
+    fn refused_request_is_an_obs_request_error() {
+        let (url, server) = fake_obs(false);
+        let mut obs = STTObs::new(&config(url, CaptionMode::TextSource));
+        match obs.send_caption("hello there") {
+            Err(MelonError::ObsRequest(x)) => assert_eq!(x, "No source was found"),
+            x => panic!("expected ObsRequest, got {:?}", x),
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn missing_password_is_reported() {
+        let (url, _server) = fake_obs(true);
+        let mut obs = STTObs::new(&ObsConfig { password: None, ..config(url, CaptionMode::StreamCaption) });
+        assert!(matches!(obs.send_caption("hello there"), Err(MelonError::Obs(_))));
+    }
+}
@@ -0,0 +1,339 @@
+//! This module defines the places a message can be sent once it's been
+//! transcribed and checked.  Outputs are listed as [[outputs]] tables in
+//! melon.toml and every message goes to all of them.  The chatbox (OSC)
+//! is sent to straight away, since whether that worked is what the UI
+//! reports on; everything else gets a background thread of its own so a
+//! slow webhook can't hold anything up, not even the other outputs.
+//! Anything that goes wrong there is reported per output through a
+//! MelonEvent::OutputFailed.
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Sender};
+use std::time::Duration;
+use chrono::Local;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use log::{debug, error};
+use crate::MelonError;
+use crate::events::{EventBus, MelonEvent};
+use crate::irc::{IrcConfig, IrcSink};
+use crate::keyboard::{KeyboardBackend, KeyboardSink};
+use crate::network::STTNetwork;
+use crate::obs::{ObsConfig, STTObs};
+
+/// Somewhere a message can be sent
+pub trait OutputSink: Send {
+    /// A short description used in logs and error messages
+    fn name(&self) -> String;
+    /// Send a message
+    fn send(&mut self, text: &str) -> Result<(), MelonError>;
+}
+
+/// A single output as written in melon.toml
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum OutputConfig {
+    /// The VRChat chatbox, via osc_endpoint
+    Osc,
+    /// Print each message on its own line
+    Stdout {
+        /// Put the time in front of each message
+        #[serde(default)]
+        timestamps: bool,
+    },
+    /// Add each message to the end of a file
+    File {
+        /// The file to write to
+        path: String,
+        /// Put the time in front of each message
+        #[serde(default)]
+        timestamps: bool,
+    },
+    /// POST each message as JSON: {"text": ..., "timestamp": ...}
+    Webhook {
+        /// Where to POST to
+        url: String,
+        /// Extra headers to send, e.g. for authentication
+        #[serde(default)]
+        headers: HashMap<String, String>,
+    },
+    /// Copy each message to the clipboard
+    Clipboard,
+    /// Send each message to an IRC channel, such as Twitch chat
+    Irc(IrcConfig),
+    /// Type each message into whatever window has focus
+    Keyboard {
+        /// Milliseconds to wait between characters
+        #[serde(default = "default_char_delay")]
+        char_delay_ms: u64,
+        /// Press Enter once the message is typed
+        #[serde(default)]
+        press_enter: bool,
+        /// How key presses get faked
+        #[serde(default)]
+        backend: KeyboardBackend,
+    },
+}
+
+/// An output plus whether it's switched on
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct OutputEntry {
+    /// Send to this output at all
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    /// What kind of output it is and its settings
+    #[serde(flatten)]
+    pub output: OutputConfig,
+}
+
+/// Outputs listed in melon.toml are on unless they say otherwise
+fn enabled_by_default() -> bool {
+    true
+}
+
+/// Fast enough not to drag, slow enough that most games keep up
+fn default_char_delay() -> u64 {
+    10
+}
+
+/// The outputs used when melon.toml doesn't list any
+pub fn default_outputs() -> Vec<OutputEntry> {
+    vec![OutputEntry { enabled: true, output: OutputConfig::Osc }]
+}
+
+/// Prints messages
+pub struct StdoutSink {
+    /// Put the time in front of each message
+    timestamps: bool,
+}
+
+impl OutputSink for StdoutSink {
+    fn name(&self) -> String {
+        "stdout".to_string()
+    }
+
+    fn send(&mut self, text: &str) -> Result<(), MelonError> {
+        println!("{}", stamp(text, self.timestamps));
+        Ok(())
+    }
+}
+
+/// Appends messages to a file
+pub struct FileSink {
+    /// The file to write to
+    path: PathBuf,
+    /// Put the time in front of each message
+    timestamps: bool,
+}
+
+impl OutputSink for FileSink {
+    fn name(&self) -> String {
+        format!("file {}", self.path.display())
+    }
+
+    fn send(&mut self, text: &str) -> Result<(), MelonError> {
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{}", stamp(text, self.timestamps))?;
+        Ok(())
+    }
+}
+
+/// POSTs messages as JSON
+pub struct WebhookSink {
+    /// Where to POST to
+    url: String,
+    /// Extra headers to send
+    headers: HashMap<String, String>,
+}
+
+impl OutputSink for WebhookSink {
+    fn name(&self) -> String {
+        format!("webhook {}", self.url)
+    }
+
+    fn send(&mut self, text: &str) -> Result<(), MelonError> {
+        let mut request = ureq::post(&self.url).timeout(Duration::from_secs(10));
+        for (k, v) in self.headers.iter() {
+            request = request.set(k, v);
+        }
+        match request.send_json(json!({ "text": text, "timestamp": Local::now().to_rfc3339() })) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(MelonError::Output { output: self.name(), reason: e.to_string() })
+        }
+    }
+}
+
+/// Copies messages to the clipboard.  The clipboard is opened the first
+/// time it's needed and kept open, since on Linux the copied text goes
+/// away when the clipboard that copied it does.
+#[derive(Default)]
+pub struct ClipboardSink {
+    /// The clipboard, once it's been opened
+    clipboard: Option<arboard::Clipboard>,
+}
+
+impl OutputSink for ClipboardSink {
+    fn name(&self) -> String {
+        "clipboard".to_string()
+    }
+
+    fn send(&mut self, text: &str) -> Result<(), MelonError> {
+        let error = |e: arboard::Error| MelonError::Output { output: "clipboard".to_string(), reason: e.to_string() };
+        if self.clipboard.is_none() {
+            self.clipboard = Some(arboard::Clipboard::new().map_err(error)?);
+        }
+        match self.clipboard.as_mut() {
+            Some(x) => x.set_text(text).map_err(error),
+            None => Ok(())
+        }
+    }
+}
+
+impl OutputSink for STTNetwork {
+    fn name(&self) -> String {
+        "OSC chatbox".to_string()
+    }
+
+    fn send(&mut self, text: &str) -> Result<(), MelonError> {
+        self.send_to_osc(text)
+    }
+}
+
+impl OutputSink for STTObs {
+    fn name(&self) -> String {
+        "OBS".to_string()
+    }
+
+    fn send(&mut self, text: &str) -> Result<(), MelonError> {
+        self.send_caption(text)
+    }
+}
+
+/// Put the time in front of a message if asked to
+fn stamp(text: &str, timestamps: bool) -> String {
+    match timestamps {
+        true => format!("[{}] {}", Local::now().format("%Y-%m-%d %H:%M:%S"), text),
+        false => text.to_string()
+    }
+}
+
+/// All the outputs a message goes to
+pub struct STTOutputs {
+    /// Whether the chatbox is one of them
+    pub osc: bool,
+    /// Names of the background outputs, in order
+    pub names: Vec<String>,
+    /// Hands messages to each background output's thread
+    senders: Vec<Sender<String>>,
+}
+
+impl STTOutputs {
+    /// Build the outputs from the config.  The chatbox itself is sent to
+    /// by MelonSTT, so it's only noted here.  OBS comes from its own
+    /// [obs] table and is added to the end if it's turned on.
+    pub fn new(outputs: &[OutputEntry], obs: &ObsConfig, events: &EventBus) -> STTOutputs {
+        let mut osc = false;
+        let mut sinks: Vec<Box<dyn OutputSink>> = Vec::new();
+        for entry in outputs.iter().filter(|x| x.enabled) {
+            match &entry.output {
+                OutputConfig::Osc => { osc = true; },
+                OutputConfig::Stdout { timestamps } => sinks.push(Box::new(StdoutSink { timestamps: *timestamps })),
+                OutputConfig::File { path, timestamps } => {
+                    sinks.push(Box::new(FileSink { path: PathBuf::from(path), timestamps: *timestamps }));
+                },
+                OutputConfig::Webhook { url, headers } => {
+                    sinks.push(Box::new(WebhookSink { url: url.to_string(), headers: headers.clone() }));
+                },
+                OutputConfig::Clipboard => sinks.push(Box::<ClipboardSink>::default()),
+                OutputConfig::Irc(x) => sinks.push(Box::new(IrcSink::new(x))),
+                OutputConfig::Keyboard { char_delay_ms, press_enter, backend } => {
+                    sinks.push(Box::new(KeyboardSink::new(*char_delay_ms, *press_enter, *backend)));
+                },
+            }
+        }
+        if obs.enabled {
+            sinks.push(Box::new(STTObs::new(obs)));
+        }
+        let names: Vec<String> = sinks.iter().map(|x| x.name()).collect();
+        debug!("Outputs: {}{:?}", if osc { "OSC chatbox, " } else { "" }, names);
+        let senders = sinks.into_iter().map(|x| Self::spawn(x, events.clone())).collect();
+        STTOutputs { osc, names, senders }
+    }
+
+    /// Start the thread that sends to one background output, taking
+    /// messages in order.  It stops once the STTOutputs it belongs to is
+    /// dropped.
+    fn spawn(mut sink: Box<dyn OutputSink>, events: EventBus) -> Sender<String> {
+        let (sender, messages) = channel::<String>();
+        std::thread::spawn(move || {
+            for text in messages {
+                match sink.send(&text) {
+                    Ok(_) => { debug!("Sent message to {}", sink.name()); },
+                    Err(e) => {
+                        error!("Error sending message to {}: {}", sink.name(), e);
+                        events.emit(MelonEvent::OutputFailed { output: sink.name(), error: e.to_string() });
+                    }
+                }
+            }
+        });
+        sender
+    }
+
+    /// Queue a message for every background output
+    pub fn send(&self, text: &str) {
+        for (sender, name) in self.senders.iter().zip(self.names.iter()) {
+            if sender.send(text.to_string()).is_err() {
+                error!("Output thread for {} has gone away, message not sent to it", name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::Receiver;
+    use crate::testing::scratch;
+
+    /// An output that can't send anything until it's told to
+    struct StuckSink(Receiver<()>);
+
+    impl OutputSink for StuckSink {
+        fn name(&self) -> String {
+            "stuck".to_string()
+        }
+
+        fn send(&mut self, _text: &str) -> Result<(), MelonError> {
+            let _ = self.0.recv();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn slow_output_doesnt_hold_up_the_others() {
+        let dir = scratch("outputs");
+        let path = dir.join("outputs.txt");
+        let (release, stuck) = channel();
+        let events = EventBus::default();
+        let sinks: Vec<Box<dyn OutputSink>> = vec![
+            Box::new(StuckSink(stuck)),
+            Box::new(FileSink { path: path.clone(), timestamps: false }),
+        ];
+        let outputs = STTOutputs {
+            osc: false,
+            names: sinks.iter().map(|x| x.name()).collect(),
+            senders: sinks.into_iter().map(|x| STTOutputs::spawn(x, events.clone())).collect(),
+        };
+        outputs.send("one");
+        outputs.send("two");
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while std::fs::read_to_string(&path).unwrap_or_default() != "one\ntwo\n" {
+            assert!(std::time::Instant::now() < deadline, "file output never got both messages");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        release.send(()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
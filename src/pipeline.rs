@@ -0,0 +1,291 @@
+//! This module defines a rule-based text pipeline that runs on the
+//! transcription after filtering and before it gets sent anywhere.
+//! Rules are listed as [[pipeline]] tables in melon.toml and are applied
+//! in exactly the order they're written.  Each rule is self-contained so
+//! it can be poked at on its own.
+use std::collections::HashMap;
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+use log::{debug, error};
+use crate::MelonError;
+
+/// Built-in spoken phrases that get turned into emoji by the emoji rule
+const EMOJI_SHORTCODES: &[(&str, &str)] = &[
+    ("smiley face", "🙂"),
+    ("happy face", "😀"),
+    ("sad face", "🙁"),
+    ("crying face", "😢"),
+    ("laughing face", "😂"),
+    ("winking face", "😉"),
+    ("thumbs up", "👍"),
+    ("thumbs down", "👎"),
+    ("heart emoji", "❤️"),
+    ("fire emoji", "🔥"),
+    ("party emoji", "🎉"),
+    ("skull emoji", "💀"),
+];
+
+/// How the case rule should change the text
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CaseMode {
+    /// all lowercase
+    Lower,
+    /// ALL UPPERCASE
+    Upper,
+    /// First letter of each sentence capitalized
+    Sentence,
+}
+
+/// A single rule as written in melon.toml
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum RuleConfig {
+    /// Strip the leading/trailing whitespace Whisper likes to add
+    Trim,
+    /// Replace everything matching a regular expression
+    Regex {
+        /// The regular expression to look for
+        pattern: String,
+        /// What to replace it with ($1 etc refer to capture groups)
+        #[serde(default)]
+        replace: String,
+    },
+    /// Swap whole words or phrases for something else (case-insensitive)
+    Words {
+        /// Map of word or phrase to its replacement
+        words: HashMap<String, String>,
+    },
+    /// Change the case of the text
+    Case {
+        /// Which kind of case change to make
+        mode: CaseMode,
+    },
+    /// Turn spoken shortcodes like "smiley face" into emoji
+    Emoji {
+        /// Extra phrase to emoji mappings on top of the built-in ones
+        #[serde(default)]
+        extra: HashMap<String, String>,
+    },
+    /// Tidy up spacing around punctuation and doubled-up punctuation
+    Punctuation,
+}
+
+/// The pipeline used when melon.toml doesn't list any rules
+pub fn default_rules() -> Vec<RuleConfig> {
+    vec![RuleConfig::Trim, RuleConfig::Punctuation]
+}
+
+/// A rule that's been checked and compiled and is ready to run
+pub enum Rule {
+    /// See RuleConfig::Trim
+    Trim,
+    /// A compiled regex and its replacement string
+    Regex(Regex, String),
+    /// A list of compiled whole-word patterns and their replacements
+    Words(Vec<(Regex, String)>),
+    /// See RuleConfig::Case
+    Case(CaseMode),
+    /// See RuleConfig::Punctuation
+    Punctuation,
+}
+
+impl Rule {
+    /// Compile a rule from its config
+    pub fn new(config: &RuleConfig) -> Result<Rule, MelonError> {
+        let rule = match config {
+            RuleConfig::Trim => Rule::Trim,
+            RuleConfig::Regex { pattern, replace } => {
+                match Regex::new(pattern) {
+                    Ok(x) => Rule::Regex(x, replace.to_string()),
+                    Err(e) => {
+                        error!("Invalid regex in pipeline rule: {}", pattern);
+                        return Err(MelonError::InvalidPattern { pattern: pattern.to_string(), source: e });
+                    }
+                }
+            },
+            RuleConfig::Words { words } => Rule::Words(Self::word_patterns(words.iter())?),
+            RuleConfig::Case { mode } => Rule::Case(*mode),
+            RuleConfig::Emoji { extra } => {
+                let builtin = EMOJI_SHORTCODES.iter().map(|(k, v)| (k.to_string(), v.to_string()));
+                let mut all: HashMap<String, String> = builtin.collect();
+                all.extend(extra.iter().map(|(k, v)| (k.to_lowercase(), v.to_string())));
+                Rule::Words(Self::word_patterns(all.iter())?)
+            },
+            RuleConfig::Punctuation => Rule::Punctuation,
+        };
+        Ok(rule)
+    }
+
+    /// Build case-insensitive whole-word patterns for a set of substitutions.
+    /// Longer phrases go first so "thumbs up" wins over a plain "up".
+    fn word_patterns<'a>(words: impl Iterator<Item = (&'a String, &'a String)>) -> Result<Vec<(Regex, String)>, MelonError> {
+        let mut words: Vec<(&String, &String)> = words.collect();
+        words.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then(a.0.cmp(b.0)));
+        let mut retval = Vec::new();
+        for (word, replacement) in words {
+            let pattern = format!(r"(?i)\b{}\b", regex::escape(word));
+            match Regex::new(&pattern) {
+                Ok(x) => retval.push((x, replacement.to_string())),
+                Err(e) => {
+                    error!("Unable to build pattern for word '{}'", word);
+                    return Err(MelonError::InvalidPattern { pattern, source: e });
+                }
+            }
+        }
+        Ok(retval)
+    }
+
+    /// Run this rule on some text
+    pub fn apply(&self, text: &str) -> String {
+        match self {
+            Rule::Trim => text.trim().to_string(),
+            Rule::Regex(re, replace) => re.replace_all(text, replace.as_str()).to_string(),
+            Rule::Words(words) => {
+                let mut text = text.to_string();
+                for (re, replace) in words {
+                    text = re.replace_all(&text, regex::NoExpand(replace)).to_string();
+                }
+                text
+            },
+            Rule::Case(CaseMode::Lower) => text.to_lowercase(),
+            Rule::Case(CaseMode::Upper) => text.to_uppercase(),
+            Rule::Case(CaseMode::Sentence) => Self::sentence_case(text),
+            Rule::Punctuation => Self::fix_punctuation(text),
+        }
+    }
+
+    /// Capitalize the first letter of every sentence
+    fn sentence_case(text: &str) -> String {
+        let mut retval = String::with_capacity(text.len());
+        let mut capitalize = true;
+        for c in text.chars() {
+            if capitalize && c.is_alphabetic() {
+                retval.extend(c.to_uppercase());
+                capitalize = false;
+            } else {
+                retval.push(c);
+            }
+            if matches!(c, '.' | '!' | '?') { capitalize = true; }
+        }
+        retval
+    }
+
+    /// Remove spaces before punctuation, squash runs of whitespace and
+    /// doubled-up commas/periods (but leave ellipses alone)
+    fn fix_punctuation(text: &str) -> String {
+        let mut retval = String::with_capacity(text.len());
+        for word in text.split_whitespace() {
+            let starts_with_punct = word.starts_with([',', '.', '!', '?', ';', ':']);
+            if !retval.is_empty() && (!starts_with_punct || word.starts_with("...")) {
+                retval.push(' ');
+            }
+            retval.push_str(word);
+        }
+        let mut fixed = String::with_capacity(retval.len());
+        let chars: Vec<char> = retval.chars().collect();
+        for (i, &c) in chars.iter().enumerate() {
+            if c == ',' && i > 0 && chars[i - 1] == ',' { continue; }
+            if c == '.' && i > 0 && chars[i - 1] == '.' {
+                // Keep exactly three dots for an ellipsis, drop any other run
+                let run_start = chars[..i].iter().rposition(|&x| x != '.').map(|x| x + 1).unwrap_or(0);
+                let run_end = chars[i..].iter().position(|&x| x != '.').map(|x| x + i).unwrap_or(chars.len());
+                if run_end - run_start != 3 { continue; }
+            }
+            fixed.push(c);
+        }
+        fixed
+    }
+}
+
+/// An ordered list of rules to run the transcription through
+pub struct STTPipeline {
+    /// The compiled rules, in the order they get applied
+    pub rules: Vec<Rule>,
+}
+
+impl STTPipeline {
+    /// Compile all the rules from the config
+    pub fn new(config: &[RuleConfig]) -> Result<STTPipeline, MelonError> {
+        let mut rules = Vec::new();
+        for rule in config {
+            rules.push(Rule::new(rule)?);
+        }
+        debug!("Built text pipeline with {} rules", rules.len());
+        Ok(STTPipeline { rules })
+    }
+
+    /// Run the text through every rule in order
+    pub fn apply(&self, text: &str) -> String {
+        self.rules.iter().fold(text.to_string(), |acc, rule| rule.apply(&acc))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(config: RuleConfig, text: &str) -> String {
+        Rule::new(&config).unwrap().apply(text)
+    }
+
+    fn words(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn trim() {
+        assert_eq!(run(RuleConfig::Trim, "  hello there \n"), "hello there");
+    }
+
+    #[test]
+    fn regex() {
+        let rule = RuleConfig::Regex { pattern: r"(\d+) percent".to_string(), replace: "$1%".to_string() };
+        assert_eq!(run(rule, "about 50 percent done"), "about 50% done");
+    }
+
+    #[test]
+    fn bad_regex_is_an_error() {
+        let rule = RuleConfig::Regex { pattern: "(".to_string(), replace: String::new() };
+        assert!(matches!(Rule::new(&rule), Err(MelonError::InvalidPattern { .. })));
+    }
+
+    #[test]
+    fn words_match_whole_words_ignoring_case() {
+        let rule = RuleConfig::Words { words: words(&[("gonna", "going to"), ("cat", "$dog")]) };
+        assert_eq!(run(rule, "Gonna feed the cat, not the catalog"), "going to feed the $dog, not the catalog");
+    }
+
+    #[test]
+    fn case() {
+        assert_eq!(run(RuleConfig::Case { mode: CaseMode::Lower }, "Hello THERE"), "hello there");
+        assert_eq!(run(RuleConfig::Case { mode: CaseMode::Upper }, "Hello there"), "HELLO THERE");
+        assert_eq!(run(RuleConfig::Case { mode: CaseMode::Sentence }, "hi. how are you? fine"), "Hi. How are you? Fine");
+    }
+
+    #[test]
+    fn emoji() {
+        let rule = RuleConfig::Emoji { extra: words(&[("Melon Time", "🍈")]) };
+        assert_eq!(run(rule, "thumbs up, it's melon time"), "👍, it's 🍈");
+    }
+
+    #[test]
+    fn punctuation() {
+        assert_eq!(run(RuleConfig::Punctuation, "hello ,  there , , friend .."), "hello, there, friend.");
+        assert_eq!(run(RuleConfig::Punctuation, "well ... okay"), "well ... okay");
+    }
+
+    #[test]
+    fn rules_run_in_order() {
+        let lower_then_words = STTPipeline::new(&[
+            RuleConfig::Case { mode: CaseMode::Lower },
+            RuleConfig::Words { words: words(&[("hello", "Howdy")]) },
+        ]).unwrap();
+        assert_eq!(lower_then_words.apply("HELLO"), "Howdy");
+        let words_then_lower = STTPipeline::new(&[
+            RuleConfig::Words { words: words(&[("hello", "Howdy")]) },
+            RuleConfig::Case { mode: CaseMode::Lower },
+        ]).unwrap();
+        assert_eq!(words_then_lower.apply("HELLO"), "howdy");
+    }
+}
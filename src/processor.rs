@@ -1,22 +1,90 @@
 //! This module handles processing recorded audio samples through
 //! Whisper to transcribe text.
 use whisper_rs::{FullParams, SamplingStrategy, WhisperContext, WhisperState, WhisperContextParameters};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
 use log::{debug, error};
+use crate::models::ModelHeader;
+use crate::MelonError;
+
+/// One chunk of transcribed text as reported back by Whisper
+#[derive(Clone, Debug)]
+pub struct STTSegment {
+    /// The transcribed text of this segment
+    pub text: String,
+    /// Start time of the segment in centiseconds
+    pub t0: i64,
+    /// End time of the segment in centiseconds
+    pub t1: i64,
+    /// Rough guess at how likely it is this segment isn't actually speech.
+    /// whisper-rs doesn't expose whisper.cpp's real no-speech probability,
+    /// so this is 1.0 minus the average probability of the text tokens.
+    pub no_speech_prob: f32,
+}
+
+/// The settings that control how Whisper decodes audio.  These can be
+/// changed between runs without reloading the model.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecodeOptions {
+    /// Language code to transcribe in ("auto" to let Whisper guess)
+    pub language: String,
+    /// Optional initial prompt to nudge Whisper's vocabulary and style
+    pub prompt: Option<String>,
+    /// Translate the speech into English instead of transcribing it
+    pub translate: bool,
+    /// Number of CPU threads to use
+    pub threads: i32,
+    /// Beam search width; 1 or less means plain greedy decoding
+    pub beam_size: i32,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        DecodeOptions { language: "en".to_string(), prompt: None, translate: false, threads: 8, beam_size: 1 }
+    }
+}
 
 /// Define a struct to hold our Whisper processing junk
-pub struct STTProcessor<'a> {
-    /// The Whisper object that does the transcription work
-    pub state: WhisperState,
-    /// Parameters to pass to the state whenever it's working
-    pub params: FullParams<'a, 'a>,
+pub struct STTProcessor {
+    /// The Whisper object that does the transcription work.  It lives behind
+    /// an Arc<Mutex> so a freshly loaded model can be swapped in from the
+    /// background thread that loaded it.
+    pub state: Arc<Mutex<WhisperState>>,
+    /// Filename of the model currently loaded into state
+    pub model: Arc<Mutex<String>>,
+    /// Settings used to build the Whisper parameters for each run
+    pub options: DecodeOptions,
     /// The results of transcription
-    pub processed_text: String
+    pub processed_text: String,
+    /// The individual segments that make up processed_text
+    pub segments: Vec<STTSegment>
 }
 
-impl STTProcessor<'_> {
+impl STTProcessor {
     /// Initialize a new STTProcessor using the given language model
-    pub fn new<'a>(model: String) -> Result<STTProcessor<'a>, anyhow::Error> {
-        let context = match WhisperContext::new_with_params(&model, WhisperContextParameters::default()) {
+    pub fn new(model: String, options: DecodeOptions) -> Result<STTProcessor, MelonError> {
+        let state = Self::load_state(&model)?;
+        Ok(STTProcessor {
+            state: Arc::new(Mutex::new(state)),
+            model: Arc::new(Mutex::new(model)),
+            options,
+            processed_text: String::new(),
+            segments: Vec::new()
+        })
+    }
+
+    /// Load a model file and create a fresh WhisperState from it
+    fn load_state(model: &str) -> Result<WhisperState, MelonError> {
+        // Check the header first so a bad file gets a better error than
+        // whatever WhisperContext has to say about it
+        match ModelHeader::read(std::path::Path::new(model)) {
+            Ok(x) => { debug!("Model {} is {} ({})", model, x.model_type(), x.quantization()); },
+            Err(e) => {
+                error!("Model file {} doesn't look usable", model);
+                return Err(e);
+            }
+        }
+        let context = match WhisperContext::new_with_params(model, WhisperContextParameters::default()) {
             Ok(x) => {
                 debug!("Created WhisperContext ok");
                 x
@@ -26,36 +94,102 @@ impl STTProcessor<'_> {
                 return Err(e.into());
             }
         };
-        let state = match context.create_state() {
+        match context.create_state() {
             Ok(x) => {
                 debug!("Created WhisperState ok");
-                x
+                Ok(x)
             },
             Err(e) => {
                 error!("Error creating WhisperState");
-                return Err(e.into());
+                Err(e.into())
             }
-        };
-        let mut params = FullParams::new(SamplingStrategy::Greedy { best_of: 1 });
-        params.set_n_threads(8);
-        params.set_translate(false);
-        params.set_language(Some("en"));
-        params.set_suppress_non_speech_tokens(true);
-        params.set_print_progress(false);
-        params.set_single_segment(true);
-        Ok(STTProcessor { state, params, processed_text: String::new() })
+        }
+    }
+
+    /// Load a different model in a background thread and swap it in once
+    /// it's ready.  If loading fails the current model is left alone.
+    /// The returned handle can be joined to find out how it went.
+    pub fn load_model(&self, model: String) -> JoinHandle<Result<(), MelonError>> {
+        let state = self.state.clone();
+        let current = self.model.clone();
+        std::thread::spawn(move || {
+            debug!("Loading model {} in the background", model);
+            let new_state = match Self::load_state(&model) {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("Error loading model {}, keeping the current one", model);
+                    return Err(e);
+                }
+            };
+            match (state.lock(), current.lock()) {
+                (Ok(mut s), Ok(mut m)) => {
+                    *s = new_state;
+                    *m = model;
+                    debug!("Swapped in model {}", *m);
+                    Ok(())
+                },
+                _ => {
+                    error!("Error locking processor state to swap models");
+                    Err(MelonError::LockPoisoned("processor state"))
+                }
+            }
+        })
+    }
+
+    /// A processor sharing this one's loaded model but with its own
+    /// options and results, for one-off runs that shouldn't disturb the
+    /// latest transcription
+    pub fn with_options(&self, options: DecodeOptions) -> STTProcessor {
+        STTProcessor {
+            state: self.state.clone(),
+            model: self.model.clone(),
+            options,
+            processed_text: String::new(),
+            segments: Vec::new()
+        }
+    }
+
+    /// Filename of the model currently in use
+    pub fn current_model(&self) -> String {
+        match self.model.lock() {
+            Ok(x) => x.to_string(),
+            Err(_) => String::new()
+        }
     }
 
     /// Process the audio data recorded by STTRecorder
     /// The audio_data should be 32-bit float 16kHz; pretty sure it'll be mono?
-    pub fn process(&mut self, audio_data: &[f32]) -> Result<(), anyhow::Error> {
-        match self.state.full(self.params.clone(), &audio_data[..]) {
+    pub fn process(&mut self, audio_data: &[f32]) -> Result<(), MelonError> {
+        // Hold the lock for the whole run so a model swap can't happen mid-transcription
+        let mut state = match self.state.lock() {
+            Ok(x) => x,
+            Err(_) => {
+                error!("Error locking processor state");
+                return Err(MelonError::LockPoisoned("processor state"));
+            }
+        };
+        // Build the params fresh each time.  They borrow the language and
+        // prompt strings, which is why they can't just live in the struct.
+        let mut params = match self.options.beam_size {
+            x if x > 1 => FullParams::new(SamplingStrategy::BeamSearch { beam_size: x, patience: -1.0 }),
+            _ => FullParams::new(SamplingStrategy::Greedy { best_of: 1 }),
+        };
+        params.set_n_threads(self.options.threads);
+        params.set_translate(self.options.translate);
+        params.set_language(Some(&self.options.language));
+        if let Some(x) = &self.options.prompt {
+            params.set_initial_prompt(x);
+        }
+        params.set_suppress_non_speech_tokens(true);
+        params.set_print_progress(false);
+        params.set_single_segment(true);
+        match state.full(params, audio_data) {
             Ok(_) => { debug!("Model ran successfully"); },
             Err(e) => { error!("Error running model"); return Err(e.into()); }
         }
 
         // Iterate through the results and store in self.processed_text
-        let num_segments = match self.state.full_n_segments() {
+        let num_segments = match state.full_n_segments() {
             Ok(x) => {
                 debug!("Got full_n_segments");
                 x
@@ -65,8 +199,9 @@ impl STTProcessor<'_> {
                 return Err(e.into());
             }
         };
+        self.segments.clear();
         for i in 0..num_segments {
-            let segment = match self.state.full_get_segment_text(i) {
+            let segment = match state.full_get_segment_text(i) {
                 Ok(x) => {
                     debug!("Got STT segment {}: {}", i, x);
                     x
@@ -76,8 +211,31 @@ impl STTProcessor<'_> {
                     return Err(e.into());
                 }
             };
-            if i == 0 { self.processed_text = format!("{}", segment); }
+            let t0 = state.full_get_segment_t0(i).unwrap_or(0);
+            let t1 = state.full_get_segment_t1(i).unwrap_or(0);
+            let no_speech_prob = Self::estimate_no_speech(&state, i);
+            self.segments.push(STTSegment { text: segment, t0, t1, no_speech_prob });
         }
+        self.processed_text = self.segments.iter().map(|x| x.text.as_str()).collect();
         Ok(())
     }
+
+    /// Estimate the no-speech probability of a segment from its token
+    /// probabilities.  Special tokens ([_BEG_], [_TT_123], <|endoftext|> etc)
+    /// are skipped since they don't say anything about the speech itself.
+    fn estimate_no_speech(state: &WhisperState, segment: i32) -> f32 {
+        let num_tokens = state.full_n_tokens(segment).unwrap_or(0);
+        let mut total = 0.0;
+        let mut count = 0;
+        for t in 0..num_tokens {
+            let text = state.full_get_token_text_lossy(segment, t).unwrap_or_default();
+            if text.starts_with("[_") || text.starts_with("<|") { continue; }
+            if let Ok(p) = state.full_get_token_prob(segment, t) {
+                total += p;
+                count += 1;
+            }
+        }
+        if count == 0 { return 1.0; }
+        1.0 - (total / count as f32)
+    }
 }
\ No newline at end of file
@@ -3,11 +3,396 @@
 //! and ensuring it is in the format required for whisper-rs (16KHz mono f32).
 //! Some of this logic is extraneous on Linux and MacOS but since this is ultimately
 //! meant to run under Windows, it's written to run under Windows.
+//!
+//! Recordings can run for a fixed time, until the speaker goes quiet (VAD),
+//! or between explicit start and stop calls (push-to-talk).  Input levels
+//! are measured as audio comes in, for level meters and for the VAD.
+//!
+//! Opening the device takes long enough to clip the first word, so it
+//! can optionally be kept open all the time instead.  The last few hundred
+//! milliseconds are then kept in a pre-roll buffer and every recording
+//! starts with them.
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use std::collections::VecDeque;
 use std::sync::{Arc, Mutex};
-use anyhow::anyhow;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use crate::MelonError;
 use dasp::{interpolate::sinc::Sinc, ring_buffer, signal, Signal};
-use log::{debug, error};
+use serde_derive::{Deserialize, Serialize};
+use log::{debug, error, warn};
+
+/// How a recording gets started and stopped
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RecordMode {
+    /// Record for a fixed number of seconds
+    #[default]
+    Fixed,
+    /// Start listening and stop once speech is followed by silence
+    Vad,
+    /// Record for as long as the button (or key) is held down
+    PushToTalk,
+}
+
+/// Settings for voice activity detection, read from the [vad] table
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct VadConfig {
+    /// Input level (RMS, 0.0 to 1.0) that counts as speech
+    #[serde(serialize_with = "crate::filter::tidy_f32")]
+    pub threshold: f32,
+    /// How long it has to be quiet after speech before recording stops
+    pub silence_ms: u64,
+    /// Stop after this many seconds no matter what
+    pub max_seconds: u64,
+}
+
+impl Default for VadConfig {
+    fn default() -> Self {
+        VadConfig {
+            threshold: 0.02,
+            silence_ms: 800,
+            max_seconds: 30,
+        }
+    }
+}
+
+/// Settings for always-on capture, read from the [preroll] table
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct PrerollConfig {
+    /// Keep the input device open all the time so recordings can include
+    /// what was said just before they started
+    pub enabled: bool,
+    /// How much audio from before the recording started to include
+    pub length_ms: u64,
+}
+
+impl Default for PrerollConfig {
+    fn default() -> Self {
+        PrerollConfig {
+            enabled: false,
+            length_ms: 300,
+        }
+    }
+}
+
+/// Longest pre-roll allowed, so it can't take up much memory
+pub const MAX_PREROLL_MS: u64 = 5000;
+
+/// How many levels a listener can fall behind by before it misses some
+const LEVEL_BACKLOG: usize = 8;
+
+/// How often input levels get handed out to listeners (about 30 a second)
+const LEVEL_INTERVAL: Duration = Duration::from_millis(33);
+
+/// How loud the input is, measured over a short block of audio
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct InputLevel {
+    /// Root mean square level, 0.0 to 1.0
+    pub rms: f32,
+    /// Loudest single sample, 0.0 to 1.0
+    pub peak: f32,
+}
+
+impl InputLevel {
+    /// A sample this loud or louder counts as clipped
+    pub const CLIP: f32 = 0.99;
+    /// RMS below this (about -66 dB) is treated as no signal at all
+    pub const SILENCE: f32 = 0.0005;
+
+    /// The RMS level on a 0.0 to 1.0 scale covering -60 dB to 0 dB, for meters
+    pub fn meter(&self) -> f32 {
+        Self::scale(self.rms)
+    }
+
+    /// The peak level on the same scale as meter()
+    pub fn peak_meter(&self) -> f32 {
+        Self::scale(self.peak)
+    }
+
+    /// Whether any sample in the block hit the top of the range
+    pub fn is_clipping(&self) -> bool {
+        self.peak >= Self::CLIP
+    }
+
+    /// Whether the block was so quiet there's probably nothing connected
+    pub fn is_silent(&self) -> bool {
+        self.rms < Self::SILENCE
+    }
+
+    /// Turn a linear level into 0.0 to 1.0 across -60 dB to 0 dB
+    fn scale(level: f32) -> f32 {
+        if level <= 0.0 { return 0.0; }
+        ((20.0 * level.log10() + 60.0) / 60.0).clamp(0.0, 1.0)
+    }
+}
+
+/// Running totals for the block of audio currently being measured
+#[derive(Default)]
+struct MeterState {
+    /// Sum of the squares of every sample so far
+    sum_squares: f64,
+    /// Number of samples so far
+    count: usize,
+    /// Loudest sample so far
+    peak: f32,
+    /// When listeners were last told the level
+    last_sent: Option<Instant>,
+    /// The last level handed out
+    latest: InputLevel,
+    /// Everyone listening for levels
+    listeners: Vec<SyncSender<InputLevel>>,
+}
+
+/// Measures input levels as audio arrives and hands them out to anyone
+/// listening about 30 times a second
+#[derive(Clone, Default)]
+pub struct LevelMeter {
+    /// Shared with the audio callback
+    state: Arc<Mutex<MeterState>>,
+}
+
+impl LevelMeter {
+    /// Create a level meter with nobody listening yet
+    pub fn new() -> LevelMeter {
+        LevelMeter::default()
+    }
+
+    /// Start listening for levels.  Dropping the receiver unsubscribes.
+    /// Levels are handed out from the audio callback, so a listener that
+    /// falls behind misses some rather than making anything wait.
+    pub fn subscribe(&self) -> Receiver<InputLevel> {
+        let (tx, rx) = sync_channel(LEVEL_BACKLOG);
+        if let Ok(mut state) = self.state.lock() {
+            state.listeners.push(tx);
+        }
+        rx
+    }
+
+    /// The most recent level measured
+    pub fn latest(&self) -> InputLevel {
+        match self.state.lock() {
+            Ok(x) => x.latest,
+            Err(_) => InputLevel::default()
+        }
+    }
+
+    /// Feed in a block of samples.  This runs in the audio callback, so
+    /// it never waits on the lock; a block that can't get it is skipped.
+    pub fn measure(&self, data: &[f32]) {
+        let mut state = match self.state.try_lock() {
+            Ok(x) => x,
+            Err(_) => return
+        };
+        for &sample in data.iter() {
+            state.sum_squares += (sample * sample) as f64;
+            state.peak = state.peak.max(sample.abs());
+        }
+        state.count += data.len();
+        let now = Instant::now();
+        if state.count == 0 || state.last_sent.is_some_and(|x| now.duration_since(x) < LEVEL_INTERVAL) {
+            return;
+        }
+        let level = InputLevel {
+            rms: (state.sum_squares / state.count as f64).sqrt() as f32,
+            peak: state.peak,
+        };
+        state.sum_squares = 0.0;
+        state.count = 0;
+        state.peak = 0.0;
+        state.last_sent = Some(now);
+        Self::publish(&mut state, level);
+    }
+
+    /// Drop back to silence, e.g. when a recording stops
+    pub fn reset(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.sum_squares = 0.0;
+            state.count = 0;
+            state.peak = 0.0;
+            state.last_sent = None;
+            Self::publish(&mut state, InputLevel::default());
+        }
+    }
+
+    /// Hand a level out to every listener, forgetting any that have gone away
+    fn publish(state: &mut MeterState, level: InputLevel) {
+        state.latest = level;
+        state.listeners.retain(|x| !matches!(x.try_send(level), Err(TrySendError::Disconnected(_))));
+    }
+}
+
+/// A recording exactly as it came off the device
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RawAudio {
+    /// Interleaved f32 samples
+    pub samples: Vec<f32>,
+    /// How many channels are interleaved
+    pub channels: u16,
+    /// Samples per second, per channel
+    pub sample_rate: u32,
+}
+
+/// A ring buffer holding the last few moments of audio.  Its size is
+/// fixed when it's made, so listening all the time can't eat up memory.
+#[derive(Clone, Debug, Default)]
+pub struct PreRoll {
+    /// The samples, oldest first
+    samples: VecDeque<f32>,
+    /// Most samples it will hold
+    capacity: usize,
+}
+
+impl PreRoll {
+    /// Make a pre-roll holding length_ms of interleaved audio
+    pub fn new(length_ms: u64, sample_rate: u32, channels: u16) -> PreRoll {
+        let frames = (sample_rate as u64 * length_ms.min(MAX_PREROLL_MS) / 1000) as usize;
+        let capacity = frames * channels.max(1) as usize;
+        PreRoll { samples: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    /// Most samples it will hold
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// How many samples it's holding right now
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Whether it's holding anything at all
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Add some samples, dropping the oldest to make room.  Whole frames
+    /// go in and the capacity is a whole number of frames, so channels
+    /// stay lined up.
+    pub fn push(&mut self, data: &[f32]) {
+        if self.capacity == 0 { return; }
+        let data = &data[data.len().saturating_sub(self.capacity)..];
+        let overflow = (self.samples.len() + data.len()).saturating_sub(self.capacity);
+        self.samples.drain(..overflow);
+        self.samples.extend(data.iter().copied());
+    }
+
+    /// Everything it's holding, oldest first
+    pub fn contents(&self) -> Vec<f32> {
+        self.samples.iter().copied().collect()
+    }
+}
+
+/// What the audio callback writes into
+#[derive(Default)]
+struct TapState {
+    /// The recording in progress, if there is one
+    recording: Option<Vec<f32>>,
+    /// The last few moments of audio, when listening all the time
+    preroll: Option<PreRoll>,
+}
+
+/// Where incoming audio goes: into the recording in progress, if there
+/// is one, and into the pre-roll when listening all the time.  Anything
+/// producing f32 samples can feed it, so a synthetic source can stand in
+/// for the device.
+#[derive(Clone)]
+pub struct AudioTap {
+    /// Shared with the audio callback
+    state: Arc<Mutex<TapState>>,
+    /// Whether a recording is in progress, so that can be checked
+    /// without getting in the audio callback's way
+    recording: Arc<AtomicBool>,
+    /// Measures the input
+    levels: LevelMeter,
+}
+
+impl AudioTap {
+    /// Make a tap, with a pre-roll if it's going to be listening all the time
+    pub fn new(preroll: Option<PreRoll>, levels: LevelMeter) -> AudioTap {
+        AudioTap {
+            state: Arc::new(Mutex::new(TapState { recording: None, preroll })),
+            recording: Arc::new(AtomicBool::new(false)),
+            levels
+        }
+    }
+
+    /// Feed in a block of samples.  This runs in the audio callback.  The
+    /// only other things taking the lock are begin and finish, which are
+    /// quick about it, so waiting for it beats losing a block of audio.
+    pub fn feed(&self, data: &[f32]) {
+        let mut state = match self.state.lock() {
+            Ok(x) => x,
+            Err(_) => return
+        };
+        // Measured whenever the stream is open, so the meter (and its
+        // no-signal warning) works before anything's being recorded
+        self.levels.measure(data);
+        if let Some(x) = state.preroll.as_mut() {
+            x.push(data);
+        }
+        if let Some(x) = state.recording.as_mut() {
+            x.extend_from_slice(data);
+        }
+    }
+
+    /// Start a recording, beginning with whatever's in the pre-roll.
+    /// Hands back how many samples of pre-roll it started with.
+    pub fn begin(&self) -> usize {
+        match self.state.lock() {
+            Ok(mut state) => {
+                let recording = state.preroll.as_ref().map(|x| x.contents()).unwrap_or_default();
+                let preroll = recording.len();
+                state.recording = Some(recording);
+                self.recording.store(true, Ordering::Release);
+                preroll
+            },
+            Err(_) => 0
+        }
+    }
+
+    /// Finish the recording, handing back everything in it
+    pub fn finish(&self) -> Option<Vec<f32>> {
+        let mut state = self.state.lock().ok()?;
+        self.recording.store(false, Ordering::Release);
+        state.recording.take()
+    }
+
+    /// Whether a recording is in progress
+    pub fn is_recording(&self) -> bool {
+        self.recording.load(Ordering::Acquire)
+    }
+}
+
+/// An open input stream.  cpal streams can't be moved between threads,
+/// so the stream lives on a thread of its own until it's told to stop.
+struct Capture {
+    /// Tells the stream thread to stop
+    stop: Sender<()>,
+    /// The stream thread itself
+    thread: JoinHandle<()>,
+    /// Where the stream puts what it hears
+    tap: AudioTap,
+    /// The format the raw samples are in
+    spec: hound::WavSpec,
+    /// The device it's reading from
+    device: String,
+    /// Length of the pre-roll if it's listening all the time, or None if
+    /// it was opened for one recording
+    preroll_ms: Option<u64>,
+}
+
+impl Capture {
+    /// Stop the stream and wait for its thread to finish
+    fn close(self) -> Result<(), MelonError> {
+        let _ = self.stop.send(());
+        self.thread.join().map_err(|_| MelonError::ThreadPanicked("audio capture"))
+    }
+}
 
 /// Struct representing the recorded audio sample
 pub struct STTRecorder {
@@ -16,29 +401,204 @@ pub struct STTRecorder {
     /// For convenience sake to avoid extraneous 'match input_device.name()' blocks
     pub device_name: String,
     /// Audio data as a vec of f32 samples
-    pub audio_data: Vec<f32>
+    pub audio_data: Vec<f32>,
+    /// The latest recording before it was converted, kept until the next
+    /// one so it can be saved
+    pub raw_audio: Option<RawAudio>,
+    /// Input levels measured while recording
+    pub levels: LevelMeter,
+    /// When the latest recording started, counting any pre-roll
+    pub started: Option<chrono::DateTime<chrono::Local>>,
+    /// Length of the pre-roll if the device is kept open all the time
+    pub preroll_ms: Option<u64>,
+    /// The open input stream, if there is one
+    capture: Option<Capture>,
 }
 
 impl STTRecorder {
     /// Create a new STTRecorder struct with some default values
-    pub fn new() -> Result<STTRecorder, anyhow::Error> {
-        // Get the default device info
+    pub fn new() -> Result<STTRecorder, MelonError> {
+        Self::with_device(None)
+    }
+
+    /// Create a new STTRecorder using the named input device, or the
+    /// default device if no name is given
+    pub fn with_device(name: Option<&str>) -> Result<STTRecorder, MelonError> {
+        let (input_device, device_name) = Self::find_device(name)?;
+        let audio_data = Vec::new();
+        Ok(STTRecorder { input_device, device_name, audio_data, raw_audio: None, levels: LevelMeter::new(), started: None, preroll_ms: None, capture: None })
+    }
+
+    /// Names of all the input devices that can be recorded from
+    pub fn list_devices() -> Vec<String> {
+        match cpal::default_host().input_devices() {
+            Ok(x) => x.filter_map(|x| x.name().ok()).collect(),
+            Err(e) => {
+                error!("Error listing input devices: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Switch to a different input device.  A recording already running
+    /// carries on with the old one.
+    pub fn set_device(&mut self, name: Option<&str>) -> Result<(), MelonError> {
+        self.reconfigure(name, self.preroll_ms)
+    }
+
+    /// Keep the input device open all the time, holding on to the last
+    /// length_ms of audio so recordings start from just before they were
+    /// asked for.  None goes back to opening it for each recording.  A
+    /// recording already running carries on as it was.
+    pub fn set_preroll(&mut self, length_ms: Option<u64>) -> Result<(), MelonError> {
+        let device = (self.input_device.clone(), self.device_name.to_string());
+        self.switch(device, length_ms)
+    }
+
+    /// Change the input device and pre-roll together, like set_device
+    /// and set_preroll.  Either both change or, if the new stream can't be
+    /// opened, neither does.
+    pub fn reconfigure(&mut self, name: Option<&str>, preroll_ms: Option<u64>) -> Result<(), MelonError> {
+        let device = Self::find_device(name)?;
+        self.switch(device, preroll_ms)
+    }
+
+    /// Swap in a device and pre-roll, putting the old ones (and their
+    /// stream) back if the new stream won't open
+    fn switch(&mut self, device: (cpal::Device, String), preroll_ms: Option<u64>) -> Result<(), MelonError> {
+        let old = (self.input_device.clone(), self.device_name.to_string(), self.preroll_ms);
+        (self.input_device, self.device_name) = device;
+        self.preroll_ms = preroll_ms.map(|x| x.min(MAX_PREROLL_MS));
+        match self.reopen() {
+            Ok(_) => {
+                debug!("Input is now {} with pre-roll {:?}", self.device_name, self.preroll_ms);
+                Ok(())
+            },
+            Err(e) => {
+                error!("Error switching input, going back to {}", old.1);
+                (self.input_device, self.device_name, self.preroll_ms) = old;
+                if let Err(e) = self.reopen() {
+                    error!("Error reopening the previous input stream: {}", e);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Make the open stream match the device and pre-roll settings, unless
+    /// a recording is using it
+    fn reopen(&mut self) -> Result<(), MelonError> {
+        if self.is_recording() { return Ok(()); }
+        let matches = match (&self.capture, self.preroll_ms) {
+            (Some(x), y) => x.preroll_ms == y && x.device == self.device_name,
+            (None, y) => y.is_none()
+        };
+        if matches { return Ok(()); }
+        if let Some(x) = self.capture.take() {
+            x.close()?;
+        }
+        if let Some(x) = self.preroll_ms {
+            self.capture = Some(self.open(Some(x))?);
+            debug!("Listening all the time with {}ms of pre-roll", x);
+        }
+        Ok(())
+    }
+
+    /// Look up an input device by name, or the default device for None
+    fn find_device(name: Option<&str>) -> Result<(cpal::Device, String), MelonError> {
         let host = cpal::default_host();
-        let input_device = match host.default_input_device() {
-            Some(x) => x,
-            None => { return Err(anyhow!("No recording devices found!")); }
+        let device = match name {
+            Some(name) => {
+                let devices = host.input_devices().map_err(|e| MelonError::audio_device("listing input devices", e))?;
+                let mut devices = devices.filter(|x| x.name().map(|x| x == name).unwrap_or(false));
+                match devices.next() {
+                    Some(x) => x,
+                    None => return Err(MelonError::UnknownDevice(name.to_string()))
+                }
+            },
+            None => match host.default_input_device() {
+                Some(x) => x,
+                None => { return Err(MelonError::NoInputDevice); }
+            }
         };
-        let device_name = match input_device.name() {
+        let device_name = match device.name() {
             Ok(x) => x,
-            Err(e) => return Err(anyhow!("Error getting device name: {:?}", e))
+            Err(e) => return Err(MelonError::audio_device("getting device name", e))
         };
-        let audio_data = Vec::new();
-        Ok(STTRecorder { input_device, device_name, audio_data })
+        Ok((device, device_name))
     }
 
-    /// Use the default input device to record an audio sample of the specified
+    /// Use the input device to record an audio sample of the specified
     /// length (in seconds)
-    pub fn record_audio(&mut self, duration: u64) -> Result<(), anyhow::Error> {
+    pub fn record_audio(&mut self, duration: u64) -> Result<(), MelonError> {
+        self.start()?;
+        // Let recording go for the configurable duration variable.
+        std::thread::sleep(Duration::from_secs(duration));
+        self.stop()
+    }
+
+    /// Record until the speaker has said something and then gone quiet,
+    /// or until max_seconds is up
+    pub fn record_until_silence(&mut self, vad: &VadConfig) -> Result<(), MelonError> {
+        self.start()?;
+        Self::wait_for_silence(&self.levels, vad);
+        self.stop()
+    }
+
+    /// Wait for the speaker to say something and then go quiet, or for
+    /// max_seconds to be up, going by the levels of a running recording
+    pub fn wait_for_silence(levels: &LevelMeter, vad: &VadConfig) {
+        let started = Instant::now();
+        let mut heard_speech = false;
+        let mut last_speech = Instant::now();
+        while started.elapsed() < Duration::from_secs(vad.max_seconds) {
+            std::thread::sleep(Duration::from_millis(50));
+            if levels.latest().rms >= vad.threshold {
+                heard_speech = true;
+                last_speech = Instant::now();
+            } else if heard_speech && last_speech.elapsed() >= Duration::from_millis(vad.silence_ms) {
+                debug!("Silence detected, stopping recording");
+                break;
+            }
+        }
+        if !heard_speech { warn!("VAD never heard anything above the threshold"); }
+    }
+
+    /// Whether a recording is currently running
+    pub fn is_recording(&self) -> bool {
+        self.capture.as_ref().is_some_and(|x| x.tap.is_recording())
+    }
+
+    /// Start recording from the input device.  Recording carries on in the
+    /// background until stop() is called.  When the device is kept open
+    /// the recording starts with the pre-roll.
+    pub fn start(&mut self) -> Result<(), MelonError> {
+        if self.is_recording() {
+            debug!("Already recording");
+            return Ok(());
+        }
+        let mut started = chrono::Local::now();
+        match &self.capture {
+            Some(x) => {
+                let preroll = x.tap.begin();
+                let rate = (x.spec.sample_rate as i64 * x.spec.channels as i64).max(1);
+                started -= chrono::TimeDelta::milliseconds(preroll as i64 * 1000 / rate);
+                debug!("Recording started with {} samples of pre-roll", preroll);
+            },
+            None => {
+                let capture = self.open(None)?;
+                capture.tap.begin();
+                self.capture = Some(capture);
+                debug!("Recording started");
+            }
+        }
+        self.started = Some(started);
+        Ok(())
+    }
+
+    /// Open an input stream on the current device, listening all the time
+    /// with a pre-roll or just for one recording
+    fn open(&self, preroll_ms: Option<u64>) -> Result<Capture, MelonError> {
         // Get the default input config for our recording device
         let config = match self.input_device.default_input_config() {
             Ok(x) => {
@@ -47,7 +607,7 @@ impl STTRecorder {
             },
             Err(e) => {
                 error!("Error getting default input config");
-                return Err(e.into()); 
+                return Err(MelonError::audio_device("getting default input config", e)); 
             }
         };
         
@@ -60,59 +620,91 @@ impl STTRecorder {
             error!("an error occurred on stream: {}", err);
         };
 
-        let data_buffer = Vec::<f32>::new();
-        let arcbuf = Arc::new(Mutex::new(Some(data_buffer)));
-        let arcbuf2 = arcbuf.clone();
-
-        // Being lazy and using ? on these.  Create the
-        // input stream with a callback to the store-in-memory function
-        let stream = self.input_device.build_input_stream(
-            &config.into(), 
-            move |data, _: &_| Self::store_input_data(data, &arcbuf2),
-            err_fn, 
-            None)?;
+        let preroll = preroll_ms.map(|x| PreRoll::new(x, spec.sample_rate, spec.channels));
+        let tap = AudioTap::new(preroll, self.levels.clone());
+        let tap2 = tap.clone();
+        let device = self.input_device.clone();
+        let (stop, stopped) = channel::<()>();
+        let (ready_tx, ready) = channel::<Result<(), MelonError>>();
 
-        // Start recording
-        stream.play()?;
+        let thread = std::thread::spawn(move || {
+            // Create the input stream with a callback to the store-in-memory function
+            let stream = match device.build_input_stream(
+                &config.into(),
+                move |data: &[f32], _: &_| tap2.feed(data),
+                err_fn,
+                None) {
+                Ok(x) => x,
+                Err(e) => {
+                    let _ = ready_tx.send(Err(MelonError::audio_device("building input stream", e)));
+                    return;
+                }
+            };
+            // Start recording
+            if let Err(e) = stream.play() {
+                let _ = ready_tx.send(Err(MelonError::audio_device("starting input stream", e)));
+                return;
+            }
+            let _ = ready_tx.send(Ok(()));
+            // Keep the stream alive until told to stop (or the recorder goes away)
+            let _ = stopped.recv();
+            drop(stream);
+            debug!("Recording completed.");
+        });
 
-        // Let recording go for the configurable duration variable.
-        std::thread::sleep(std::time::Duration::from_secs(duration));
+        match ready.recv() {
+            Ok(Ok(_)) => { debug!("Input stream open"); },
+            Ok(Err(e)) => {
+                error!("Error starting recording");
+                let _ = thread.join();
+                return Err(e);
+            },
+            Err(_) => return Err(MelonError::ThreadPanicked("audio capture"))
+        }
+        Ok(Capture { stop, thread, tap, spec, device: self.device_name.to_string(), preroll_ms })
+    }
 
-        // Stop recording and drop the stream
-        drop(stream);
-        debug!("Recording completed.");
+    /// Stop the running recording and convert what was recorded, leaving
+    /// it in audio_data.  A stream that's listening all the time is left
+    /// open; one opened just for this recording is closed.
+    pub fn stop(&mut self) -> Result<(), MelonError> {
+        let capture = match self.capture.take() {
+            Some(x) if x.tap.is_recording() => x,
+            x => {
+                self.capture = x;
+                return Err(MelonError::NotRecording);
+            }
+        };
+        let samples = capture.tap.finish();
+        let spec = capture.spec;
+        match capture.preroll_ms {
+            Some(_) => { self.capture = Some(capture); },
+            // Stop recording and drop the stream
+            None => capture.close()?
+        }
+        self.levels.reset();
+        // Settings changed during the recording catch up now
+        if let Err(e) = self.reopen() {
+            error!("Error reopening input stream: {}", e);
+        }
 
         // Convert the recorded samples to 16kHz/32-bit
-        let converted = match Self::convert_samples(&arcbuf, &spec) {
-            Ok(x) => {
-                debug!("Conversion ok");
-                x
-            },
-            Err(e) => {
-                error!("Error converting samples");
-                return Err(e.into());
+        let samples = match samples {
+            Some(x) => x,
+            None => {
+                error!("Error extracting samples from Mutex");
+                return Err(MelonError::AudioConversion("no samples in buffer".to_string()));
             }
         };
+        let converted = Self::convert_samples(&samples, &spec);
+        debug!("Conversion ok");
 
         // Stash the recorded audio in self and return
         self.audio_data = converted;
+        self.raw_audio = Some(RawAudio { samples, channels: spec.channels, sample_rate: spec.sample_rate });
         Ok(())
     }
 
-    /// Move input data into buffer vec.  We're using this gross 
-    /// arc mutex thingy because cloning or something.
-    fn store_input_data(data: &[f32], buf: &Arc<Mutex<Option<Vec<f32>>>>) {
-        use cpal::Sample;
-        if let Ok(mut guard) = buf.try_lock() {
-            if let Some(buffer) = guard.as_mut() {
-                for &sample in data.iter() {
-                    let sample: f32 = f32::from_sample(sample);
-                    buffer.push(sample);
-                }
-            }
-        }
-    }
-
     /// Determine if sample format is float or int
     fn sample_format(format: cpal::SampleFormat) -> hound::SampleFormat {
         if format.is_float() { hound::SampleFormat::Float } 
@@ -131,37 +723,108 @@ impl STTRecorder {
 
     /// Take whatever format the recorded samples are in and conver them to
     /// 16kHz/mono/32-bit.  Gonna be honest, I don't know WHAT the heck this is doing.
-    fn convert_samples(buf: &Arc<Mutex<Option<Vec<f32>>>>, spec: &hound::WavSpec) -> Result<Vec<f32>, anyhow::Error> {
-        use dasp::Sample;
-        // Get the vec back out of the big mess up there
+    fn convert_samples(samples: &[f32], spec: &hound::WavSpec) -> Vec<f32> {
         let mut retval: Vec<f32> = Vec::new();
-        let mut samples = Vec::new();
-        if let Ok(mut guard) = buf.try_lock() {
-            if let Some(data) = guard.as_mut() {
-                for &sample in data.iter() {
-                    samples.push(sample);
-                }
-            } else { 
-                error!("Error extracting samples from Mutex"); 
-                return Err(anyhow!("Error in convert_samples on guard_as_mut()"));
-            }
-        } else { 
-            error!("Error extracting samples from Mutex");
-            return Err(anyhow!("Error in convert_samples on buf_try_lock()"));
-        }
         // I don't even think most of this is necessary just 'sample_rate'
         let mut target = hound::WavSpec::from(*spec);
         target.sample_rate = 16_000;
         target.sample_format = hound::SampleFormat::Float;
         target.bits_per_sample = 32;
         target.channels = 1;
+        retval.extend(Self::resample(samples, spec.sample_rate, target.sample_rate));
+        retval
+    }
+
+    /// Resample raw samples from one rate to another, the same way
+    /// recordings get converted for Whisper.  Handy for saved recordings.
+    pub fn resample(samples: &[f32], from: u32, to: u32) -> Vec<f32> {
+        use dasp::Sample;
         let signal = signal::from_interleaved_samples_iter(samples.iter().cloned());
         let ring_buffer = ring_buffer::Fixed::from([[0.0]; 100]);
         let sinc = Sinc::new(ring_buffer);
-        let new_signal = signal.from_hz_to_hz(sinc, spec.sample_rate as f64, target.sample_rate as f64);
-        for frame in new_signal.until_exhausted() {
-            retval.push(frame[0].to_sample());
+        let new_signal = signal.from_hz_to_hz(sinc, from as f64, to as f64);
+        new_signal.until_exhausted().map(|frame: [f32; 1]| frame[0].to_sample()).collect()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stereo frames numbered from start, left channel positive and right
+    /// negative, so it's easy to tell where each sample came from
+    fn frames(start: usize, count: usize) -> Vec<f32> {
+        (start..start + count).flat_map(|x| [x as f32, -(x as f32)]).collect()
+    }
+
+    #[test]
+    fn preroll_is_capped() {
+        assert_eq!(PreRoll::new(300, 16_000, 1).capacity(), 4_800);
+        assert_eq!(PreRoll::new(300, 48_000, 2).capacity(), 28_800);
+        assert_eq!(PreRoll::new(60_000, 48_000, 2).capacity(), PreRoll::new(MAX_PREROLL_MS, 48_000, 2).capacity());
+        let mut preroll = PreRoll::new(60_000, 1_000, 1);
+        preroll.push(&[0.1; 20_000]);
+        assert_eq!(preroll.len(), 5_000);
+    }
+
+    #[test]
+    fn preroll_keeps_newest_whole_frames() {
+        // 10ms of 1kHz stereo is 10 frames
+        let mut preroll = PreRoll::new(10, 1_000, 2);
+        assert!(preroll.is_empty());
+        for block in 0..7 {
+            preroll.push(&frames(block * 3, 3));
+        }
+        assert_eq!(preroll.len(), preroll.capacity());
+        assert_eq!(preroll.contents(), frames(11, 10));
+        // A block bigger than the whole thing keeps just its end
+        preroll.push(&frames(100, 25));
+        assert_eq!(preroll.contents(), frames(115, 10));
+    }
+
+    #[test]
+    fn recording_starts_with_preroll() {
+        let tap = AudioTap::new(Some(PreRoll::new(10, 1_000, 2)), LevelMeter::new());
+        tap.feed(&frames(0, 15));
+        assert!(!tap.is_recording());
+        assert_eq!(tap.begin(), 20);
+        assert!(tap.is_recording());
+        tap.feed(&frames(15, 5));
+        assert_eq!(tap.finish(), Some(frames(5, 15)));
+        assert!(!tap.is_recording());
+        assert_eq!(tap.finish(), None);
+    }
+
+    #[test]
+    fn recording_without_preroll_starts_empty() {
+        let tap = AudioTap::new(None, LevelMeter::new());
+        tap.feed(&frames(0, 5));
+        assert_eq!(tap.begin(), 0);
+        tap.feed(&frames(5, 5));
+        assert_eq!(tap.finish(), Some(frames(5, 5)));
+    }
+
+    #[test]
+    fn nothing_dropped_while_checking_state() {
+        let tap = AudioTap::new(Some(PreRoll::new(10, 1_000, 2)), LevelMeter::new());
+        tap.begin();
+        let feeder = tap.clone();
+        let feeding = std::thread::spawn(move || {
+            for block in 0..1_000 {
+                feeder.feed(&frames(block * 4, 4));
+            }
+        });
+        while !feeding.is_finished() {
+            assert!(tap.is_recording());
         }
-        Ok(retval)
+        feeding.join().unwrap();
+        assert_eq!(tap.finish().map(|x| x.len()), Some(8_000));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn levels_measured_without_recording() {
+        let levels = LevelMeter::new();
+        let tap = AudioTap::new(None, levels.clone());
+        tap.feed(&[0.5; 256]);
+        assert_eq!(levels.latest().peak, 0.5);
+    }
+}
@@ -0,0 +1,251 @@
+//! This module keeps the audio behind each transcription as WAV files, so
+//! anything Whisper got wrong can be listened back to, reported, or kept
+//! as a test case.  Recordings can be saved as they came off the device
+//! (its own sample rate and channels), as the 16kHz mono audio Whisper
+//! was actually given, or both.  Files are named after the history entry
+//! they belong to and the entry notes where they are.
+//!
+//! Old recordings are deleted once there are more than max_files of them
+//! or they take up more than max_megabytes, oldest first, so leaving this
+//! turned on can't slowly fill the disk.
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use chrono::Local;
+use serde_derive::{Deserialize, Serialize};
+use log::{debug, error, warn};
+use crate::MelonError;
+use crate::recorder::RawAudio;
+
+/// Settings for saving recordings, read from the [recordings] table
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct RecordingsConfig {
+    /// Save recordings at all
+    pub enabled: bool,
+    /// Where to put them (defaults to melonstt/recordings in the platform data dir)
+    pub dir: Option<String>,
+    /// Save the audio exactly as the device recorded it
+    pub raw: bool,
+    /// Save the 16kHz mono audio Whisper was given
+    pub converted: bool,
+    /// Most files to keep (0 for no limit)
+    pub max_files: usize,
+    /// Most space to take up, in megabytes (0 for no limit)
+    pub max_megabytes: u64,
+}
+
+impl Default for RecordingsConfig {
+    fn default() -> Self {
+        RecordingsConfig {
+            enabled: false,
+            dir: None,
+            raw: false,
+            converted: true,
+            max_files: 200,
+            max_megabytes: 500,
+        }
+    }
+}
+
+/// Where a recording ended up
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SavedRecording {
+    /// The 16kHz mono file, if one was written
+    pub converted: Option<PathBuf>,
+    /// The file straight from the device, if one was written
+    pub raw: Option<PathBuf>,
+    /// Older recordings deleted to stay within the limits
+    pub pruned: Vec<PathBuf>,
+}
+
+/// Saves recordings and keeps them within the limits
+pub struct STTRecordings {
+    /// The settings in use
+    pub config: RecordingsConfig,
+}
+
+impl STTRecordings {
+    /// Set up saving recordings
+    pub fn new(config: &RecordingsConfig) -> STTRecordings {
+        STTRecordings { config: config.clone() }
+    }
+
+    /// Switch to new settings, trimming what's already there if the
+    /// limits came down.  Hands back the recordings that were deleted.
+    pub fn set_config(&mut self, config: &RecordingsConfig) -> Vec<PathBuf> {
+        self.config = config.clone();
+        match self.config.enabled {
+            true => self.prune(),
+            false => Vec::new()
+        }
+    }
+
+    /// The directory recordings go in
+    pub fn dir(&self) -> PathBuf {
+        match &self.config.dir {
+            Some(x) => PathBuf::from(x),
+            None => dirs::data_dir().unwrap_or_default().join("melonstt").join("recordings")
+        }
+    }
+
+    /// Save a recording, naming it after its history entry if it has one,
+    /// then delete old ones if that's gone over the limits
+    pub fn save(&self, id: Option<u64>, raw: Option<&RawAudio>, converted: &[f32]) -> Result<SavedRecording, MelonError> {
+        let mut saved = SavedRecording::default();
+        if !self.config.enabled { return Ok(saved); }
+        let dir = self.dir();
+        if let Err(e) = std::fs::create_dir_all(&dir) {
+            error!("Unable to create recordings directory {}", dir.display());
+            return Err(MelonError::Io(e));
+        }
+        let name = match id {
+            Some(x) => format!("melonstt-{}-{}", Local::now().format("%Y%m%d-%H%M%S"), x),
+            None => format!("melonstt-{}", Local::now().format("%Y%m%d-%H%M%S"))
+        };
+        if self.config.converted && !converted.is_empty() {
+            let path = dir.join(format!("{}.wav", name));
+            Self::write(&path, converted, 1, 16_000)?;
+            saved.converted = Some(path);
+        }
+        if let Some(x) = raw.filter(|x| self.config.raw && !x.samples.is_empty()) {
+            let path = dir.join(format!("{}-raw.wav", name));
+            Self::write(&path, &x.samples, x.channels, x.sample_rate)?;
+            saved.raw = Some(path);
+        }
+        debug!("Saved recording {}", name);
+        saved.pruned = self.prune();
+        Ok(saved)
+    }
+
+    /// Read a saved recording back in
+    pub fn load(path: &Path) -> Result<RawAudio, MelonError> {
+        let error = |e: hound::Error| {
+            error!("Unable to read recording {}", path.display());
+            MelonError::RecordingRead { path: path.to_path_buf(), source: e }
+        };
+        let mut reader = hound::WavReader::open(path).map_err(error)?;
+        let spec = reader.spec();
+        let samples: Result<Vec<f32>, hound::Error> = match spec.sample_format {
+            hound::SampleFormat::Float => reader.samples::<f32>().collect(),
+            // Anything saved some other way gets scaled to -1.0 to 1.0
+            hound::SampleFormat::Int => {
+                let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+                reader.samples::<i32>().map(|x| x.map(|x| x as f32 / scale)).collect()
+            }
+        };
+        Ok(RawAudio { samples: samples.map_err(error)?, channels: spec.channels, sample_rate: spec.sample_rate })
+    }
+
+    /// Delete the files belonging to a recording
+    pub fn remove(&self, paths: &[&Path]) {
+        for path in paths {
+            match std::fs::remove_file(path) {
+                Ok(_) => { debug!("Deleted recording {}", path.display()); },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+                Err(e) => { error!("Unable to delete recording {}: {}", path.display(), e); }
+            }
+        }
+    }
+
+    /// Write samples out as a 32-bit float WAV file
+    fn write(path: &Path, samples: &[f32], channels: u16, sample_rate: u32) -> Result<(), MelonError> {
+        let spec = hound::WavSpec { channels, sample_rate, bits_per_sample: 32, sample_format: hound::SampleFormat::Float };
+        let result = hound::WavWriter::create(path, spec).and_then(|mut writer| {
+            for &sample in samples {
+                writer.write_sample(sample)?;
+            }
+            writer.finalize()
+        });
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("Unable to write recording to {}", path.display());
+                Err(MelonError::RecordingWrite { path: path.to_path_buf(), source: e })
+            }
+        }
+    }
+
+    /// Delete the oldest recordings until they're back within the limits,
+    /// handing back the ones that went
+    fn prune(&self) -> Vec<PathBuf> {
+        let (max_files, max_bytes) = (self.config.max_files, self.config.max_megabytes * 1024 * 1024);
+        if max_files == 0 && max_bytes == 0 { return Vec::new(); }
+        let entries = match std::fs::read_dir(self.dir()) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Unable to look through recordings: {}", e);
+                return Vec::new();
+            }
+        };
+        // Only files that look like ours, in case dir is shared with anything else
+        let mut files: Vec<(SystemTime, u64, PathBuf)> = entries
+            .filter_map(|x| x.ok())
+            .filter(|x| {
+                let name = x.file_name().to_string_lossy().to_string();
+                name.starts_with("melonstt-") && name.ends_with(".wav")
+            })
+            .filter_map(|x| {
+                let metadata = x.metadata().ok()?;
+                Some((metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH), metadata.len(), x.path()))
+            })
+            .collect();
+        files.sort();
+        let mut total: u64 = files.iter().map(|x| x.1).sum();
+        let mut count = files.len();
+        let mut doomed = Vec::new();
+        for (_, size, path) in files.iter() {
+            let too_many = max_files > 0 && count > max_files;
+            let too_big = max_bytes > 0 && total > max_bytes;
+            if !too_many && !too_big { break; }
+            doomed.push(path.as_path());
+            count -= 1;
+            total -= size;
+        }
+        if !doomed.is_empty() {
+            debug!("Deleting {} old recordings to stay within the limits", doomed.len());
+            self.remove(&doomed);
+        }
+        doomed.iter().map(|x| x.to_path_buf()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch;
+
+    #[test]
+    fn pruned_recordings_are_reported() {
+        let dir = scratch("recordings");
+        let config = RecordingsConfig {
+            enabled: true,
+            dir: Some(dir.to_string_lossy().to_string()),
+            max_files: 2,
+            ..Default::default()
+        };
+        let mut recordings = STTRecordings::new(&config);
+        let audio = [0.0; 160];
+        let first = recordings.save(Some(1), None, &audio).unwrap();
+        assert!(first.pruned.is_empty());
+        recordings.save(Some(2), None, &audio).unwrap();
+        let third = recordings.save(Some(3), None, &audio).unwrap();
+        assert_eq!(third.pruned, first.converted.into_iter().collect::<Vec<_>>());
+        assert!(third.converted.as_ref().unwrap().is_file());
+        let pruned = recordings.set_config(&RecordingsConfig { max_files: 1, ..config });
+        assert_eq!(pruned.len(), 1);
+        assert!(!pruned[0].exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn saved_recording_reads_back() {
+        let dir = scratch("recording-roundtrip");
+        let path = dir.join("roundtrip.wav");
+        let samples = [0.0, 0.5, -0.5, 0.25];
+        STTRecordings::write(&path, &samples, 2, 48_000).unwrap();
+        let audio = STTRecordings::load(&path).unwrap();
+        assert_eq!(audio.samples, samples);
+        assert_eq!((audio.channels, audio.sample_rate), (2, 48_000));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,367 @@
+//! This module runs an optional little HTTP server so MelonSTT can be
+//! driven from stream decks, scripts and the like instead of the window.
+//!
+//! Endpoints (everything answers in JSON):
+//! - GET  /status                 what's going on right now
+//! - POST /record/start           start recording (like holding push-to-talk)
+//! - POST /record/stop            stop and transcribe, sending it on if auto_send is on
+//! - POST /record/cancel          stop and throw the recording away
+//! - POST /record?seconds=N       record for N seconds, or until silence without seconds
+//! - GET  /transcription/latest   the latest history entry
+//! - POST /send {"text": ...}     send text to the chatbox (the latest transcription without text)
+//! - POST /profile {"name": ...}  switch profile, null for none
+//! - GET  /events                 WebSocket streaming every MelonEvent as JSON
+//!
+//! It only listens on localhost by default.  Set a token to require an
+//! "Authorization: Bearer <token>" header (or ?token= for the WebSocket).
+//! Without one, requests from web pages (anything with an Origin header)
+//! are turned away so a random website can't start recording.
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use axum::{Json, Router};
+use axum::extract::{Query, Request, State};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::http::StatusCode;
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use log::{debug, error};
+use crate::{ControlCommand, MelonError, MelonSTT};
+
+/// Settings for the control server, read from the [server] table
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Run the server at all
+    pub enabled: bool,
+    /// Address to listen on
+    pub bind: String,
+    /// If set, every request needs to carry this token
+    pub token: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            enabled: false,
+            bind: "127.0.0.1:8745".to_string(),
+            token: None,
+        }
+    }
+}
+
+/// What every handler gets to work with
+#[derive(Clone)]
+struct ServerState {
+    melon: Arc<Mutex<MelonSTT>>,
+    token: Option<String>,
+}
+
+/// Query for POST /record
+#[derive(Deserialize)]
+struct RecordQuery {
+    seconds: Option<u64>,
+}
+
+/// The token, for clients that can't set headers (like browser WebSockets)
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+/// Body for POST /send
+#[derive(Deserialize)]
+struct SendRequest {
+    text: Option<String>,
+}
+
+/// Body for POST /profile
+#[derive(Deserialize)]
+struct ProfileRequest {
+    name: Option<String>,
+}
+
+/// A MelonError on its way back to the client
+struct ApiError(MelonError);
+
+impl From<MelonError> for ApiError {
+    fn from(e: MelonError) -> Self {
+        ApiError(e)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = match &self.0 {
+            MelonError::NotRecording => StatusCode::CONFLICT,
+            MelonError::UnknownProfile(_) | MelonError::UnknownEntry(_) => StatusCode::NOT_FOUND,
+            MelonError::ConfigInvalid { .. } => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, Json(json!({ "error": self.0.to_string() }))).into_response()
+    }
+}
+
+/// The result handlers hand back
+type ApiResult = Result<Json<serde_json::Value>, ApiError>;
+
+/// Start the control server on its own thread.  The address is bound
+/// straight away so a port that's already taken gets reported here
+/// rather than vanishing into the log.
+pub fn spawn(melon: &Arc<Mutex<MelonSTT>>, config: &ServerConfig) -> Result<JoinHandle<()>, MelonError> {
+    let listener = match TcpListener::bind(&config.bind) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Error binding control server to {}", config.bind);
+            return Err(MelonError::SocketBind(e));
+        }
+    };
+    listener.set_nonblocking(true)?;
+    let state = ServerState { melon: melon.clone(), token: config.token.clone() };
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()?;
+    debug!("Control server listening on {}", config.bind);
+    Ok(std::thread::spawn(move || {
+        runtime.block_on(async move {
+            let listener = match tokio::net::TcpListener::from_std(listener) {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("Error starting control server: {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = axum::serve(listener, router(state)).await {
+                error!("Control server stopped: {}", e);
+            }
+        });
+    }))
+}
+
+/// Every endpoint the server answers
+fn router(state: ServerState) -> Router {
+    Router::new()
+        .route("/status", get(status))
+        .route("/record", post(record))
+        .route("/record/start", post(record_start))
+        .route("/record/stop", post(record_stop))
+        .route("/record/cancel", post(record_cancel))
+        .route("/transcription/latest", get(latest))
+        .route("/send", post(send))
+        .route("/profile", post(profile))
+        .route("/events", get(events))
+        .layer(middleware::from_fn_with_state(state.token.clone(), authorize))
+        .with_state(state)
+}
+
+/// Check the token, or without one make sure it isn't a web page asking
+async fn authorize(State(token): State<Option<String>>, request: Request, next: Next) -> Response {
+    let headers = request.headers();
+    let allowed = match &token {
+        Some(token) => {
+            let bearer = headers.get("authorization")
+                .and_then(|x| x.to_str().ok())
+                .and_then(|x| x.strip_prefix("Bearer "));
+            // Query does the percent-decoding, so tokens with symbols in work
+            let query = Query::<TokenQuery>::try_from_uri(request.uri()).ok().and_then(|x| x.0.token);
+            bearer == Some(token) || query.as_ref() == Some(token)
+        },
+        None => !headers.contains_key("origin")
+    };
+    match allowed {
+        true => next.run(request).await,
+        false => {
+            debug!("Refused control request for {}", request.uri().path());
+            (StatusCode::UNAUTHORIZED, Json(json!({ "error": "not allowed" }))).into_response()
+        }
+    }
+}
+
+/// Carry out a command off the async threads, since recording and
+/// transcribing block for a good while
+async fn control(state: &ServerState, command: ControlCommand) -> ApiResult {
+    let melon = state.melon.clone();
+    match tokio::task::spawn_blocking(move || MelonSTT::control_shared(&melon, command)).await {
+        Ok(x) => Ok(Json(x?)),
+        Err(_) => Err(ApiError(MelonError::ThreadPanicked("control server request"))),
+    }
+}
+
+/// GET /status
+async fn status(State(state): State<ServerState>) -> ApiResult {
+    control(&state, ControlCommand::Status).await
+}
+
+/// POST /record?seconds=N, or until silence if seconds is left off
+async fn record(State(state): State<ServerState>, Query(query): Query<RecordQuery>) -> ApiResult {
+    control(&state, ControlCommand::Record { seconds: query.seconds }).await
+}
+
+/// POST /record/start
+async fn record_start(State(state): State<ServerState>) -> ApiResult {
+    control(&state, ControlCommand::Start).await
+}
+
+/// POST /record/stop
+async fn record_stop(State(state): State<ServerState>) -> ApiResult {
+    control(&state, ControlCommand::Stop).await
+}
+
+/// POST /record/cancel
+async fn record_cancel(State(state): State<ServerState>) -> ApiResult {
+    control(&state, ControlCommand::Cancel).await
+}
+
+/// GET /transcription/latest
+async fn latest(State(state): State<ServerState>) -> ApiResult {
+    control(&state, ControlCommand::Latest).await
+}
+
+/// POST /send
+async fn send(State(state): State<ServerState>, Json(body): Json<SendRequest>) -> ApiResult {
+    control(&state, ControlCommand::Send { text: body.text }).await
+}
+
+/// POST /profile
+async fn profile(State(state): State<ServerState>, Json(body): Json<ProfileRequest>) -> ApiResult {
+    control(&state, ControlCommand::Profile { name: body.name }).await
+}
+
+/// GET /events, upgraded to a WebSocket
+async fn events(State(state): State<ServerState>, ws: WebSocketUpgrade) -> Response {
+    // Subscribing takes the lock too, so it can't happen on an async thread
+    let melon = state.melon.clone();
+    let events = match tokio::task::spawn_blocking(move || melon.lock().map(|x| x.subscribe()).ok()).await {
+        Ok(Some(x)) => x,
+        Ok(None) => return ApiError(MelonError::LockPoisoned("melonstt")).into_response(),
+        Err(_) => return ApiError(MelonError::ThreadPanicked("control server request")).into_response()
+    };
+    ws.on_upgrade(move |socket| stream_events(socket, events))
+}
+
+/// Pass events along to a WebSocket until it goes away
+async fn stream_events(mut socket: WebSocket, events: std::sync::mpsc::Receiver<crate::events::MelonEvent>) {
+    debug!("Event stream client connected");
+    // The event bus blocks, so it gets a thread of its own to wait on.
+    // It's bounded too, so a client that stops reading backs up into the
+    // event bus and gets dropped there rather than piling events up here.
+    let (tx, mut rx) = tokio::sync::mpsc::channel(crate::events::EVENT_BACKLOG);
+    tokio::task::spawn_blocking(move || {
+        for event in events {
+            if tx.blocking_send(event).is_err() { break; }
+        }
+    });
+    loop {
+        tokio::select! {
+            event = rx.recv() => {
+                let text = match event.map(|x| serde_json::to_string(&x)) {
+                    Some(Ok(x)) => x,
+                    Some(Err(e)) => {
+                        error!("Error serializing event: {}", e);
+                        continue;
+                    },
+                    None => break
+                };
+                if socket.send(Message::Text(text.into())).await.is_err() { break; }
+            },
+            message = socket.recv() => {
+                // Nothing's expected from the client, just notice it leaving
+                match message {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+    debug!("Event stream client disconnected");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    /// A stand-in for the real router, behind the same check.  The real
+    /// one needs a MelonSTT, and with it a model and an input device.
+    fn guarded(token: Option<&str>) -> Router {
+        Router::new()
+            .route("/status", get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(token.map(|x| x.to_string()), authorize))
+    }
+
+    /// Send a GET through the router and hand back the status
+    async fn status_of(router: Router, uri: &str, headers: &[(&str, &str)]) -> StatusCode {
+        let mut request = Request::builder().uri(uri);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        router.oneshot(request.body(Body::empty()).unwrap()).await.unwrap().status()
+    }
+
+    /// The JSON body of a response
+    async fn body_of(response: Response) -> serde_json::Value {
+        let bytes = axum::body::to_bytes(response.into_body(), 4096).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn token_from_header_or_query() {
+        let token = Some("s3cret");
+        assert_eq!(status_of(guarded(token), "/status", &[("authorization", "Bearer s3cret")]).await, StatusCode::OK);
+        assert_eq!(status_of(guarded(token), "/status?token=s3cret", &[]).await, StatusCode::OK);
+        assert_eq!(status_of(guarded(token), "/status?seconds=5&token=s3cret", &[]).await, StatusCode::OK);
+        // A good token gets in even from a web page
+        assert_eq!(status_of(guarded(token), "/status", &[("authorization", "Bearer s3cret"), ("origin", "http://example.com")]).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn query_token_is_percent_decoded() {
+        let token = Some("a b&c/d=");
+        assert_eq!(status_of(guarded(token), "/status?token=a%20b%26c%2Fd%3D", &[]).await, StatusCode::OK);
+        assert_eq!(status_of(guarded(token), "/status?token=a+b%26c/d%3D", &[]).await, StatusCode::OK);
+        assert_eq!(status_of(guarded(token), "/status?token=a%20b", &[]).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn missing_or_wrong_token_is_refused() {
+        let token = Some("s3cret");
+        assert_eq!(status_of(guarded(token), "/status", &[]).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status_of(guarded(token), "/status", &[("authorization", "Bearer nope")]).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status_of(guarded(token), "/status", &[("authorization", "s3cret")]).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status_of(guarded(token), "/status?token=nope", &[]).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status_of(guarded(token), "/status?other=s3cret", &[]).await, StatusCode::UNAUTHORIZED);
+
+        let request = Request::builder().uri("/status").body(Body::empty()).unwrap();
+        let response = guarded(token).oneshot(request).await.unwrap();
+        assert_eq!(body_of(response).await, json!({ "error": "not allowed" }));
+    }
+
+    #[tokio::test]
+    async fn web_pages_are_refused_without_a_token() {
+        assert_eq!(status_of(guarded(None), "/status", &[]).await, StatusCode::OK);
+        assert_eq!(status_of(guarded(None), "/status", &[("origin", "http://example.com")]).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status_of(guarded(None), "/status", &[("origin", "null")]).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn errors_map_to_statuses() {
+        let cases = [
+            (MelonError::NotRecording, StatusCode::CONFLICT),
+            (MelonError::UnknownProfile("loud".to_string()), StatusCode::NOT_FOUND),
+            (MelonError::UnknownEntry(7), StatusCode::NOT_FOUND),
+            (MelonError::ConfigInvalid { path: "melon.toml".into(), issues: Vec::new() }, StatusCode::BAD_REQUEST),
+            (MelonError::ThreadPanicked("test"), StatusCode::INTERNAL_SERVER_ERROR),
+        ];
+        for (error, status) in cases {
+            let message = error.to_string();
+            let response = ApiError(error).into_response();
+            assert_eq!(response.status(), status, "{}", message);
+            assert_eq!(body_of(response).await, json!({ "error": message }));
+        }
+    }
+}
@@ -0,0 +1,363 @@
+//! This module writes everything transcribed out as subtitle files (SRT
+//! and/or WebVTT), for captioning a stream or keeping a record of an
+//! event.  Each session gets its own files, named after when it started.
+//! A session lasts until MelonSTT is closed, a new one is asked for, or
+//! nothing has been said for gap_minutes.
+//!
+//! Cue times come from Whisper's segment timings, offset by when the
+//! recording started.  They're either relative to the start of the
+//! session (what a video player wants) or the wall-clock time of day.
+//! WebVTT cues also get the full date and time as their identifier.
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Local, TimeDelta, Timelike};
+use serde_derive::{Deserialize, Serialize};
+use log::{debug, error};
+use crate::MelonError;
+
+/// What the cue times in the subtitle files count from
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Timestamps {
+    /// Time since the session started, to line up with a recording of it
+    #[default]
+    Relative,
+    /// Time of day, to line up with anything else logged at the time
+    WallClock,
+}
+
+/// Settings for subtitle output, read from the [subtitles] table
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct SubtitleConfig {
+    /// Write subtitle files at all
+    pub enabled: bool,
+    /// Where to put them (defaults to melonstt/subtitles in the platform data dir)
+    pub dir: Option<String>,
+    /// Write an .srt file
+    pub srt: bool,
+    /// Write a .vtt file
+    pub vtt: bool,
+    /// What cue times count from
+    pub timestamps: Timestamps,
+    /// Start a new session after this many minutes of nothing being said (0 never does)
+    pub gap_minutes: u64,
+}
+
+impl Default for SubtitleConfig {
+    fn default() -> Self {
+        SubtitleConfig {
+            enabled: false,
+            dir: None,
+            srt: true,
+            vtt: true,
+            timestamps: Timestamps::Relative,
+            gap_minutes: 30,
+        }
+    }
+}
+
+/// One caption
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cue {
+    /// When it started being said
+    pub start: DateTime<Local>,
+    /// When it stopped being said
+    pub end: DateTime<Local>,
+    /// What was said
+    pub text: String,
+}
+
+/// The files currently being written to
+struct Session {
+    /// When the session started; relative times count from here
+    started: DateTime<Local>,
+    /// When the last cue ended, to spot a long gap
+    last: DateTime<Local>,
+    /// How many cues have been written, for SRT numbering
+    cues: usize,
+    /// The .srt file, if one's being written
+    srt: Option<PathBuf>,
+    /// The .vtt file, if one's being written
+    vtt: Option<PathBuf>,
+}
+
+/// Writes cues out to subtitle files
+pub struct STTSubtitles {
+    /// The settings in use
+    pub config: SubtitleConfig,
+    /// The session being written, if one has started
+    session: Option<Session>,
+}
+
+impl STTSubtitles {
+    /// Set up subtitle output.  Nothing is written until the first cue.
+    pub fn new(config: &SubtitleConfig) -> STTSubtitles {
+        STTSubtitles { config: config.clone(), session: None }
+    }
+
+    /// Switch to new settings.  Anything that changes where or how the
+    /// files are written starts a new session.
+    pub fn set_config(&mut self, config: &SubtitleConfig) {
+        if *config != self.config {
+            self.session = None;
+        }
+        self.config = config.clone();
+    }
+
+    /// The directory subtitle files go in
+    pub fn dir(&self) -> PathBuf {
+        match &self.config.dir {
+            Some(x) => PathBuf::from(x),
+            None => dirs::data_dir().unwrap_or_default().join("melonstt").join("subtitles")
+        }
+    }
+
+    /// The files being written for the current session, if it's started
+    pub fn files(&self) -> Vec<PathBuf> {
+        match &self.session {
+            Some(x) => x.srt.iter().chain(x.vtt.iter()).cloned().collect(),
+            None => Vec::new()
+        }
+    }
+
+    /// Finish the current session; the next cue starts new files
+    pub fn new_session(&mut self) {
+        if self.session.take().is_some() {
+            debug!("Subtitle session ended");
+        }
+    }
+
+    /// Write some cues out, starting a session first if need be
+    pub fn write(&mut self, cues: &[Cue]) -> Result<(), MelonError> {
+        if !self.config.enabled || cues.is_empty() { return Ok(()); }
+        let first = cues[0].start;
+        let gap = TimeDelta::minutes(self.config.gap_minutes as i64);
+        if self.config.gap_minutes > 0 && self.session.as_ref().is_some_and(|x| first - x.last > gap) {
+            debug!("Nothing said for {} minutes, starting a new subtitle session", self.config.gap_minutes);
+            self.session = None;
+        }
+        let session = match self.session.take() {
+            Some(x) => x,
+            None => self.start_session(first)?
+        };
+        let session = self.session.insert(session);
+        let mut srt = String::new();
+        let mut vtt = String::new();
+        for cue in cues {
+            let text = Self::cue_text(&cue.text);
+            if text.is_empty() { continue; }
+            session.cues += 1;
+            let (start, end) = match self.config.timestamps {
+                Timestamps::Relative => (cue.start - session.started, cue.end - session.started),
+                Timestamps::WallClock => (Self::time_of_day(cue.start), Self::time_of_day(cue.end)),
+            };
+            srt.push_str(&format!("{}\n{} --> {}\n{}\n\n",
+                session.cues, Self::timestamp(start, ','), Self::timestamp(end, ','), text));
+            vtt.push_str(&format!("{}\n{} --> {}\n{}\n\n",
+                cue.start.format("%Y-%m-%d %H:%M:%S%.3f"), Self::timestamp(start, '.'), Self::timestamp(end, '.'), Self::vtt_text(&text)));
+            session.last = cue.end;
+        }
+        if let Some(x) = &session.srt { Self::append(x, &srt)?; }
+        if let Some(x) = &session.vtt { Self::append(x, &vtt)?; }
+        Ok(())
+    }
+
+    /// Work out the file names for a new session and write the headers
+    fn start_session(&self, started: DateTime<Local>) -> Result<Session, MelonError> {
+        let dir = self.dir();
+        if let Err(e) = std::fs::create_dir_all(&dir) {
+            error!("Unable to create subtitle directory {}", dir.display());
+            return Err(MelonError::SubtitleWrite { path: dir, source: e });
+        }
+        // Sessions started within the same second (or left over from an
+        // earlier run) get a number on the end rather than sharing files
+        let stamp = format!("melonstt-{}", started.format("%Y%m%d-%H%M%S"));
+        let (name, srt, vtt) = (1..).map(|i| {
+            let name = match i {
+                1 => stamp.to_string(),
+                i => format!("{}-{}", stamp, i)
+            };
+            let srt = self.config.srt.then(|| dir.join(format!("{}.srt", name)));
+            let vtt = self.config.vtt.then(|| dir.join(format!("{}.vtt", name)));
+            (name, srt, vtt)
+        }).find(|(_, srt, vtt)| !srt.iter().chain(vtt.iter()).any(|x| x.exists())).unwrap_or_default();
+        if let Some(x) = &vtt {
+            Self::append(x, &format!("WEBVTT - MelonSTT session started {}\n\n", started.format("%Y-%m-%d %H:%M:%S")))?;
+        }
+        debug!("Started subtitle session {}", name);
+        Ok(Session { started, last: started, cues: 0, srt, vtt })
+    }
+
+    /// Add some text to the end of a file
+    fn append(path: &Path, text: &str) -> Result<(), MelonError> {
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut x| x.write_all(text.as_bytes()));
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("Unable to write subtitles to {}", path.display());
+                Err(MelonError::SubtitleWrite { path: path.to_path_buf(), source: e })
+            }
+        }
+    }
+
+    /// Put a cue's text on one line.  A blank line ends a cue and an
+    /// arrow starts a new one's timings, so neither can be left in.
+    fn cue_text(text: &str) -> String {
+        let mut text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        // Once isn't enough; "--->" would just turn into another arrow
+        while text.contains("-->") {
+            text = text.replace("-->", "->");
+        }
+        text
+    }
+
+    /// WebVTT cue text is HTML-ish, so anything that looks like markup
+    /// has to be escaped
+    fn vtt_text(text: &str) -> String {
+        text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+    }
+
+    /// How far into the day a time is
+    fn time_of_day(time: DateTime<Local>) -> TimeDelta {
+        TimeDelta::seconds(time.num_seconds_from_midnight() as i64)
+            + TimeDelta::nanoseconds(time.nanosecond() as i64 % 1_000_000_000)
+    }
+
+    /// Format a time as HH:MM:SS,mmm (SRT) or HH:MM:SS.mmm (WebVTT)
+    fn timestamp(time: TimeDelta, separator: char) -> String {
+        let ms = time.num_milliseconds().max(0);
+        format!("{:02}:{:02}:{:02}{}{:03}",
+            ms / 3_600_000, ms / 60_000 % 60, ms / 1000 % 60, separator, ms % 1000)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use crate::testing::scratch;
+
+    /// Subtitle settings writing both formats into a fresh scratch dir
+    fn config(name: &str) -> SubtitleConfig {
+        let dir = scratch(name);
+        SubtitleConfig { enabled: true, dir: Some(dir.to_string_lossy().to_string()), ..Default::default() }
+    }
+
+    /// 12:00 on a day with no DST change, plus however many milliseconds
+    fn at(ms: i64) -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 5, 6, 12, 0, 0).unwrap() + TimeDelta::milliseconds(ms)
+    }
+
+    fn cue(start_ms: i64, end_ms: i64, text: &str) -> Cue {
+        Cue { start: at(start_ms), end: at(end_ms), text: text.to_string() }
+    }
+
+    /// The session's .srt and .vtt, read back
+    fn read(subtitles: &STTSubtitles) -> (String, String) {
+        let files = subtitles.files();
+        assert!(files[0].extension().unwrap() == "srt" && files[1].extension().unwrap() == "vtt");
+        (std::fs::read_to_string(&files[0]).unwrap(), std::fs::read_to_string(&files[1]).unwrap())
+    }
+
+    #[test]
+    fn timestamps_use_either_separator() {
+        let time = TimeDelta::milliseconds(3_723_045);
+        assert_eq!(STTSubtitles::timestamp(time, ','), "01:02:03,045");
+        assert_eq!(STTSubtitles::timestamp(time, '.'), "01:02:03.045");
+        assert_eq!(STTSubtitles::timestamp(TimeDelta::milliseconds(-5), ','), "00:00:00,000");
+    }
+
+    #[test]
+    fn relative_cues_count_from_session_start() {
+        let mut subtitles = STTSubtitles::new(&config("subs-relative"));
+        subtitles.write(&[cue(0, 1500, "hello"), cue(2000, 4250, "there")]).unwrap();
+        subtitles.write(&[cue(61_000, 62_000, "again")]).unwrap();
+        let (srt, vtt) = read(&subtitles);
+        std::fs::remove_dir_all(subtitles.dir()).unwrap();
+        assert_eq!(srt, "\
+1\n00:00:00,000 --> 00:00:01,500\nhello\n\n\
+2\n00:00:02,000 --> 00:00:04,250\nthere\n\n\
+3\n00:01:01,000 --> 00:01:02,000\nagain\n\n");
+        assert_eq!(vtt.matches("WEBVTT").count(), 1);
+        assert!(vtt.starts_with("WEBVTT - MelonSTT session started 2024-05-06 12:00:00\n\n"), "{}", vtt);
+        assert!(vtt.contains("2024-05-06 12:00:02.000\n00:00:02.000 --> 00:00:04.250\nthere\n\n"), "{}", vtt);
+    }
+
+    #[test]
+    fn wall_clock_cues_use_time_of_day() {
+        let config = SubtitleConfig { timestamps: Timestamps::WallClock, vtt: false, ..config("subs-wall-clock") };
+        let mut subtitles = STTSubtitles::new(&config);
+        subtitles.write(&[cue(1500, 3000, "hello")]).unwrap();
+        let srt = std::fs::read_to_string(&subtitles.files()[0]).unwrap();
+        std::fs::remove_dir_all(subtitles.dir()).unwrap();
+        assert_eq!(srt, "1\n12:00:01,500 --> 12:00:03,000\nhello\n\n");
+    }
+
+    #[test]
+    fn long_gap_starts_new_files() {
+        let mut subtitles = STTSubtitles::new(&SubtitleConfig { gap_minutes: 30, ..config("subs-gap") });
+        subtitles.write(&[cue(0, 1000, "one")]).unwrap();
+        let first = subtitles.files();
+        // Just under the gap carries on
+        subtitles.write(&[cue(1000 + 30 * 60_000, 2000 + 30 * 60_000, "two")]).unwrap();
+        assert_eq!(subtitles.files(), first);
+        subtitles.write(&[cue(3000 + 61 * 60_000, 4000 + 61 * 60_000, "three")]).unwrap();
+        let second = subtitles.files();
+        assert_ne!(second, first);
+        let (srt, vtt) = read(&subtitles);
+        std::fs::remove_dir_all(subtitles.dir()).unwrap();
+        assert_eq!(srt, "1\n00:00:00,000 --> 00:00:01,000\nthree\n\n");
+        assert!(vtt.starts_with("WEBVTT - MelonSTT session started 2024-05-06 13:01:03\n\n"), "{}", vtt);
+    }
+
+    #[test]
+    fn new_session_and_new_settings_start_new_files() {
+        let config = config("subs-sessions");
+        let mut subtitles = STTSubtitles::new(&config);
+        subtitles.write(&[cue(0, 1000, "one")]).unwrap();
+        let first = subtitles.files();
+        subtitles.new_session();
+        assert!(subtitles.files().is_empty());
+        // Same second as the first session, so it needs a different name
+        subtitles.write(&[cue(500, 1000, "two")]).unwrap();
+        let second = subtitles.files();
+        assert!(second[0].to_string_lossy().ends_with("melonstt-20240506-120000-2.srt"), "{:?}", second);
+        let (srt, vtt) = read(&subtitles);
+        assert_eq!(srt, "1\n00:00:00,000 --> 00:00:00,500\ntwo\n\n");
+        assert_eq!(vtt.matches("WEBVTT").count(), 1);
+        assert_eq!(std::fs::read_to_string(&first[0]).unwrap(), "1\n00:00:00,000 --> 00:00:01,000\none\n\n");
+
+        subtitles.set_config(&config);
+        assert_eq!(subtitles.files(), second);
+        subtitles.set_config(&SubtitleConfig { srt: false, ..config.clone() });
+        assert!(subtitles.files().is_empty());
+        subtitles.write(&[cue(1000, 2000, "three")]).unwrap();
+        let third = subtitles.files();
+        std::fs::remove_dir_all(subtitles.dir()).unwrap();
+        assert_eq!(third.len(), 1);
+        assert!(third[0].to_string_lossy().ends_with("melonstt-20240506-120001.vtt"), "{:?}", third);
+    }
+
+    #[test]
+    fn cue_text_cant_break_the_file() {
+        let mut subtitles = STTSubtitles::new(&config("subs-escape"));
+        subtitles.write(&[
+            cue(0, 1000, "two\n\nlines"),
+            cue(1000, 2000, "  \n "),
+            cue(2000, 3000, "a --> b ---> c <i>&"),
+        ]).unwrap();
+        let (srt, vtt) = read(&subtitles);
+        std::fs::remove_dir_all(subtitles.dir()).unwrap();
+        assert_eq!(srt, "\
+1\n00:00:00,000 --> 00:00:01,000\ntwo lines\n\n\
+2\n00:00:02,000 --> 00:00:03,000\na -> b -> c <i>&\n\n");
+        assert!(vtt.contains("\na -&gt; b -&gt; c &lt;i&gt;&amp;\n\n"), "{}", vtt);
+        assert_eq!(vtt.matches("-->").count(), 2);
+    }
+}
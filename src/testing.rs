@@ -0,0 +1,12 @@
+//! This module holds helpers shared between the tests in the other
+//! modules, so they all make their files the same way.
+use std::path::PathBuf;
+
+/// Make an empty scratch directory for a test under the system temp
+/// dir.  Tests run in parallel, so each one needs its own name.
+pub fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("melonstt-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}